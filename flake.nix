@@ -178,6 +178,8 @@
 
                   Restart = "on-failure";
                   RestartSec = "5s";
+                  # Exit status for a rejected bot token, restarting won't help
+                  RestartPreventExitStatus = 78;
                 };
 
                 preStart = ''
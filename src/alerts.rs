@@ -0,0 +1,100 @@
+use crate::error::is_disk_full;
+use crate::network::CLIENT;
+use crate::settings::SETTINGS;
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::{mpsc, oneshot};
+use tracing::{info, warn};
+
+/// Discord rejects webhook messages longer than this.
+const MAX_CONTENT_CHARS: usize = 2000;
+
+enum AlertCommand {
+	Send(String),
+	Flush(oneshot::Sender<()>),
+}
+
+#[derive(Serialize)]
+struct WebhookMessage<'a> {
+	content: &'a str,
+}
+
+static ALERTS: OnceLock<mpsc::UnboundedSender<AlertCommand>> = OnceLock::new();
+static DISK_FULL_REPORTED: AtomicBool = AtomicBool::new(false);
+
+/// Starts the alert sender if `alerts.webhook_url` is set.
+/// Without a webhook, alerts are dropped. The errors behind them are logged either way.
+pub fn init() {
+	if SETTINGS.alerts.webhook_url.is_empty() {
+		return;
+	}
+
+	let (tx, mut rx) = mpsc::unbounded_channel();
+	if ALERTS.set(tx).is_err() {
+		return;
+	}
+
+	tokio::spawn(async move {
+		while let Some(cmd) = rx.recv().await {
+			match cmd {
+				AlertCommand::Send(message) => post_webhook(message).await,
+				AlertCommand::Flush(respond_to) => {
+					let _ = respond_to.send(());
+				}
+			}
+		}
+	});
+	info!("Alert webhook enabled.");
+}
+
+/// Queues `message` to be posted to the alert webhook.
+/// Can be called from any thread, including the log writers.
+pub fn send(message: String) {
+	if let Some(tx) = ALERTS.get() {
+		let _ = tx.send(AlertCommand::Send(message));
+	}
+}
+
+/// Sends a one-time alert if `err` means the disk is full.
+pub fn check_disk_full(err: &std::io::Error) {
+	if is_disk_full(err) && !DISK_FULL_REPORTED.swap(true, Ordering::Relaxed) {
+		send(format!("💾 Disk is full, new data can't be saved: {err}"));
+	}
+}
+
+/// Waits until every queued alert has been posted. Call before exiting.
+pub async fn flush() {
+	let Some(tx) = ALERTS.get() else { return };
+	let (respond_to, rx) = oneshot::channel();
+	if tx.send(AlertCommand::Flush(respond_to)).is_ok() {
+		let _ = rx.await;
+	}
+}
+
+async fn post_webhook(mut message: String) {
+	if let Some((cut, _)) = message.char_indices().nth(MAX_CONTENT_CHARS) {
+		message.truncate(cut);
+	}
+
+	let body = match sonic_rs::to_vec(&WebhookMessage { content: &message }) {
+		Ok(body) => body,
+		Err(e) => {
+			warn!(error = ?e, "Failed to serialize alert");
+			return;
+		}
+	};
+
+	let result = CLIENT
+		.post(&SETTINGS.alerts.webhook_url)
+		.header(CONTENT_TYPE, "application/json")
+		.body(body)
+		.send()
+		.await
+		.and_then(reqwest::Response::error_for_status);
+
+	if let Err(e) = result {
+		warn!(error = ?e, "Failed to post alert to webhook");
+	}
+}
@@ -1,30 +1,56 @@
 use crate::State;
-use crate::messages::ChannelArchiver;
+use crate::error::ArchiveError;
+use crate::filters;
+use crate::manifest;
+use crate::messages::{self, ChannelArchiver};
 use crate::metadata::MetadataArchiver;
-use crate::settings::SETTINGS;
+use crate::network::retry_http;
+use crate::retention;
+use crate::settings::{ChannelOrder, SETTINGS};
+use crate::storage::LogStore;
+use crate::utils::{get_current_time_millis, millis_to_snowflake, snowflake_to_millis};
 use anyhow::Context;
 use futures_util::{StreamExt, stream};
-use std::sync::atomic::Ordering;
-use tracing::{debug, info, instrument, warn};
-use twilight_model::channel::Message;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::task::JoinHandle;
+use tracing::{Instrument, Span, debug, info, instrument, warn};
+use twilight_http::request::channel::reaction::RequestReactionType;
+use twilight_model::channel::message::EmojiReactionType;
+use twilight_model::channel::{Channel as TwilightChannel, ChannelType, Message};
+use twilight_model::gateway::payload::incoming::ChannelCreate;
 use twilight_model::id::Id;
-use twilight_model::id::marker::{ChannelMarker, GuildMarker};
+use twilight_model::id::marker::{ChannelMarker, GuildMarker, MessageMarker, UserMarker};
 
 #[instrument(skip_all)]
 pub async fn run_full_guild_catchup(
 	guild_id: Id<GuildMarker>,
 	state: State,
 	metadata_archiver: &mut MetadataArchiver,
+	force: bool,
 ) -> anyhow::Result<()> {
-	info!("Starting full catchup for guild.");
-
-	metadata_archiver.do_full_catchup(&state, guild_id).await?;
+	let full = force || metadata_archiver.full_catchup_due().await;
+	if full {
+		info!("Starting full catchup for guild.");
+		metadata_archiver.do_full_catchup(&state, guild_id).await?;
+	} else {
+		info!("Last full catchup is recent enough, only catching up messages.");
+	}
 
-	if state.shutdown.load(Ordering::SeqCst) {
+	if state.halted() {
 		return Ok(());
 	}
 
 	run_message_catchup(guild_id, state.clone()).await?;
+	manifest::write_guild(guild_id).await;
+	// An interrupted catchup has to run in full again
+	if full && !state.halted() {
+		metadata_archiver.save_catchup_complete().await?;
+	}
 
 	let pending = state.pending_downloads.load(Ordering::SeqCst);
 	info!("✅ Full catchup complete for guild. (Background downloads pending: {})", pending);
@@ -36,28 +62,128 @@ pub async fn run_full_guild_catchup(
 async fn run_message_catchup(guild_id: Id<GuildMarker>, state: State) -> anyhow::Result<()> {
 	info!("Starting message catchup for guild.");
 
-	if let Some(channels) = state.cache.guild_channels(guild_id) {
-		stream::iter(channels.iter())
-			.map(|&channel_id| (channel_id, state.clone()))
-			.for_each_concurrent(SETTINGS.catchup.channel_concurrency, |(channel_id, state)| async move {
-				if state.shutdown.load(Ordering::Relaxed) {
-					return;
-				}
-				if let Err(e) = process_channel(channel_id, state).await {
-					tracing::error!(%channel_id, error = ?e, "Failed to process channel");
-				}
-			})
-			.await;
+	let cached: Vec<Id<ChannelMarker>> = state
+		.cache
+		.guild_channels(guild_id)
+		.map(|c| c.iter().copied().collect())
+		.unwrap_or_default();
+	let mut channels = if cached.is_empty() {
+		fetch_guild_channels(guild_id, &state).await?
 	} else {
-		warn!("No channels found in cache for guild.");
-		return Ok(());
+		cached
+	};
+	order_channels(&mut channels, &state);
+
+	let progress = Arc::new(CatchupProgress::default());
+	let reporter = spawn_progress_reporter(progress.clone(), channels.len());
+
+	stream::iter(channels)
+		.map(|channel_id| (channel_id, state.clone(), progress.clone()))
+		.for_each_concurrent(SETTINGS.catchup.channel_concurrency, |(channel_id, state, progress)| async move {
+			if state.halted() {
+				return;
+			}
+			if let Err(e) = process_channel(channel_id, state, &progress).await {
+				tracing::error!(%channel_id, error = ?e, "Failed to process channel");
+			}
+			progress.channels_done.fetch_add(1, Ordering::Relaxed);
+		})
+		.await;
+
+	if let Some(reporter) = reporter {
+		reporter.abort();
 	}
+	info!(
+		"Message catchup fetched {} messages.",
+		progress.messages_fetched.load(Ordering::Relaxed)
+	);
 
 	Ok(())
 }
 
+/// Fetches the guild's channels from the API, for when catchup starts before the cache has them, and adds them to the cache.
+/// The rest of catchup looks channels up there. Threads aren't included, they are picked up once the guild is cached.
+async fn fetch_guild_channels(guild_id: Id<GuildMarker>, state: &State) -> anyhow::Result<Vec<Id<ChannelMarker>>> {
+	info!("No channels found in cache for guild. Fetching them from the API.");
+	let channels = retry_http(|| async { Ok(state.http().guild_channels(guild_id).await?.models().await?) }).await?;
+	Ok(channels
+		.into_iter()
+		.map(|channel| {
+			let id = channel.id;
+			state.cache.update(&ChannelCreate(channel));
+			id
+		})
+		.collect())
+}
+
+/// Sorts channels by `catchup.channel_order`, with `catchup.priority_channels` first.
+fn order_channels(channels: &mut [Id<ChannelMarker>], state: &State) {
+	let priority = |id: Id<ChannelMarker>| {
+		let listed = SETTINGS.catchup.priority_channels.iter().position(|&p| p == id.get());
+		listed.unwrap_or(usize::MAX)
+	};
+	match SETTINGS.catchup.channel_order {
+		ChannelOrder::Unordered => channels.sort_by_cached_key(|&id| priority(id)),
+		ChannelOrder::Position => channels.sort_by_cached_key(|&id| {
+			// Threads have no position, and go after every channel
+			let position = state.cache.channel(id).and_then(|c| c.position).unwrap_or(i32::MAX);
+			(priority(id), position, id)
+		}),
+		ChannelOrder::Activity => channels.sort_by_cached_key(|&id| {
+			let last_message = state.cache.channel(id).and_then(|c| c.last_message_id).map_or(0, |m| m.get());
+			(priority(id), Reverse(last_message))
+		}),
+	}
+}
+
+/// Shared counters for reporting message catchup progress.
+#[derive(Debug, Default)]
+struct CatchupProgress {
+	channels_done: AtomicUsize,
+	messages_fetched: AtomicUsize,
+}
+
+/// Periodically logs catchup progress until aborted. Returns `None` if progress reporting is disabled.
+fn spawn_progress_reporter(progress: Arc<CatchupProgress>, total_channels: usize) -> Option<JoinHandle<()>> {
+	if SETTINGS.catchup.progress_interval_secs == 0 {
+		return None;
+	}
+
+	let task = async move {
+		let start = Instant::now();
+		let mut interval = tokio::time::interval(Duration::from_secs(SETTINGS.catchup.progress_interval_secs));
+		interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+		interval.tick().await;
+
+		loop {
+			interval.tick().await;
+			log_progress(&progress, total_channels, start.elapsed());
+		}
+	};
+	Some(tokio::spawn(task.instrument(Span::current())))
+}
+
+#[allow(clippy::cast_precision_loss)]
+fn log_progress(progress: &CatchupProgress, total_channels: usize, elapsed: Duration) {
+	let done = progress.channels_done.load(Ordering::Relaxed);
+	let messages = progress.messages_fetched.load(Ordering::Relaxed);
+	let rate = messages as f64 / elapsed.as_secs_f64();
+
+	// Rough estimate that assumes the remaining channels are as big as the finished ones on average
+	let eta = (done > 0).then(|| elapsed.mul_f64(total_channels.saturating_sub(done) as f64 / done as f64).as_secs());
+
+	match eta {
+		Some(eta) => info!(
+			"📊 Catchup progress: {done}/{total_channels} channels, {messages} messages ({rate:.0} msg/s), ETA {}m {}s",
+			eta / 60,
+			eta % 60
+		),
+		None => info!("📊 Catchup progress: {done}/{total_channels} channels, {messages} messages ({rate:.0} msg/s)"),
+	}
+}
+
 #[instrument(skip_all, fields(channel_id=channel_id.get()))]
-async fn process_channel(channel_id: Id<ChannelMarker>, state: State) -> anyhow::Result<()> {
+async fn process_channel(channel_id: Id<ChannelMarker>, state: State, progress: &CatchupProgress) -> anyhow::Result<()> {
 	let channel = state.cache.channel(channel_id).context("Channel missing from cache")?;
 
 	if !crate::utils::is_archivable_channel(channel.kind) {
@@ -66,55 +192,444 @@ async fn process_channel(channel_id: Id<ChannelMarker>, state: State) -> anyhow:
 
 	let guild_id = channel.guild_id.context("Channel missing guild_id")?;
 	drop(channel);
-	let archiver = ChannelArchiver::new(guild_id.get(), channel_id.get(), &state.shutdown.clone())?;
+	if !filters::is_channel_archived(&state.cache, channel_id) {
+		return Ok(());
+	}
+	let folder = messages::channel_folder(&state.cache, channel_id);
+	let archiver = ChannelArchiver::new(guild_id.get(), channel_id.get(), folder, &state.shutdown.clone())?;
 
-	let start_after = archiver.get_last_message_id().await?.map_or_else(|| Id::new(1), Id::new);
+	catchup_channel(&archiver, guild_id, channel_id, &state, progress).await
+}
+
+/// Fetches and stores all messages newer than the last one saved by `archiver`.
+/// A channel the bot isn't allowed to read is skipped, so it doesn't stop the rest of the catchup.
+async fn catchup_channel(
+	archiver: &ChannelArchiver,
+	guild_id: Id<GuildMarker>,
+	channel_id: Id<ChannelMarker>,
+	state: &State,
+	progress: &CatchupProgress,
+) -> anyhow::Result<()> {
+	let res = if SETTINGS.catchup.newest_first {
+		catchup_channel_newest_first(archiver, guild_id, channel_id, state, progress).await
+	} else {
+		catchup_channel_oldest_first(archiver, guild_id, channel_id, state, progress).await
+	};
+	match res {
+		Err(e) if e.downcast_ref::<ArchiveError>().is_some_and(ArchiveError::is_forbidden) => {
+			info!(%channel_id, "No permission to read the channel's history. Skipping it.");
+			Ok(())
+		}
+		res => res,
+	}
+}
+
+async fn catchup_channel_oldest_first(
+	archiver: &ChannelArchiver,
+	guild_id: Id<GuildMarker>,
+	channel_id: Id<ChannelMarker>,
+	state: &State,
+	progress: &CatchupProgress,
+) -> anyhow::Result<()> {
+	let last_stored = archiver.newest_stored().await?.max(1);
+	let since = catchup_since_snowflake(guild_id)?.unwrap_or(1);
+	let start_after = Id::new(last_stored.max(since));
+	let until = SETTINGS.catchup.until_ms;
 
 	info!(start_after = %start_after.get(), "Starting message catchup.");
 
 	let mut message_buffer = Vec::with_capacity(SETTINGS.catchup.write_batch_size);
 	let mut current_after = start_after;
+	// Only a stored message is a meaningful start for a gap, not the `since` bound
+	let mut gaps = GapTracker::new((last_stored > since).then_some(last_stored));
 
 	loop {
-		if state.shutdown.load(Ordering::Relaxed) {
+		if state.halted() {
 			break;
 		}
 
-		let messages = state
-			.http
-			.channel_messages(channel_id)
-			.limit(SETTINGS.catchup.messages_per_request)
-			.after(current_after)
-			.await?
-			.models()
-			.await?;
+		let mut messages = retry_http(|| async {
+			let _permit = state.catchup_request_permit().await;
+			Ok(state
+				.http()
+				.channel_messages(channel_id)
+				.limit(SETTINGS.catchup.messages_per_request)
+				.after(current_after)
+				.await?
+				.models()
+				.await?)
+		})
+		.await?;
 
 		if messages.is_empty() {
 			break;
 		}
 
 		let batch_size = messages.len();
-		// API returns newest first
-		current_after = messages.first().unwrap().id;
+		progress.messages_fetched.fetch_add(batch_size, Ordering::Relaxed);
+		// The API returns newest first, but the cursor must be the newest message no matter the order,
+		// or the next page would skip or repeat messages at the boundary
+		messages.sort_unstable_by_key(|m| m.id);
+		current_after = messages.last().unwrap().id;
 
-		message_buffer.extend(messages.into_iter().rev());
+		let mut reached_until = false;
+		if let Some(until) = until
+			&& snowflake_to_millis(current_after.get()) > until
+		{
+			messages.retain(|m| snowflake_to_millis(m.id.get()) <= until);
+			reached_until = true;
+		}
+
+		for message in &messages {
+			gaps.observe(message.id.get());
+		}
+		message_buffer.extend(messages);
 
 		if message_buffer.len() >= SETTINGS.catchup.write_batch_size {
-			flush_buffer(&mut message_buffer, &archiver, &state).await?;
+			flush_buffer(&mut message_buffer, archiver, state, false).await?;
+			gaps.save(archiver, state).await?;
 		}
 
-		if batch_size < SETTINGS.catchup.messages_per_request as usize {
+		// A full page can't tell whether more follow, so a channel with an exact multiple of the page size ends on one empty fetch.
+		// The channel's cached last message can't end it sooner, as the cache doesn't keep it up to date
+		if reached_until || batch_size < SETTINGS.catchup.messages_per_request as usize {
 			break;
 		}
 	}
 
-	flush_buffer(&mut message_buffer, &archiver, &state).await?;
+	flush_buffer(&mut message_buffer, archiver, state, false).await?;
+	gaps.save(archiver, state).await?;
+
+	info!("✅ Message catchup complete for channel");
+	Ok(())
+}
+
+/// Message IDs newest-first catchup still has to fetch: everything after `floor` and before `before` (or the newest message if unset).
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct BackfillRange {
+	#[serde(rename = "f")]
+	floor: u64,
+	#[serde(rename = "b", skip_serializing_if = "Option::is_none", default)]
+	before: Option<u64>,
+}
+
+/// Like `catchup_channel`, but pages down from the newest message, so recent history is stored first.
+/// Ranges not fully fetched yet are kept in `{channel_id}.backfill.json` next to the log, and continued on the next catchup.
+async fn catchup_channel_newest_first(
+	archiver: &ChannelArchiver,
+	guild_id: Id<GuildMarker>,
+	channel_id: Id<ChannelMarker>,
+	state: &State,
+	progress: &CatchupProgress,
+) -> anyhow::Result<()> {
+	let ranges_path = archiver.path().with_extension("backfill.json");
+	let mut ranges: Vec<BackfillRange> = match tokio::fs::read(&ranges_path).await {
+		Ok(bytes) => sonic_rs::from_slice(&bytes).context("Failed to parse backfill ranges")?,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+		Err(e) => return Err(e).context("Failed to read backfill ranges"),
+	};
+
+	// Messages newer than anything stored come first, then whatever earlier catchups left unfinished
+	let floor = archiver.newest_stored().await?.max(catchup_since_snowflake(guild_id)?.unwrap_or(0));
+	let before = SETTINGS.catchup.until_ms.map(|until| millis_to_snowflake(until.saturating_add(1)));
+	if before.is_none_or(|before| before > floor) {
+		ranges.insert(0, BackfillRange { floor, before });
+	}
+	if ranges.is_empty() {
+		return Ok(());
+	}
+
+	info!(floor, ranges = ranges.len(), "Starting newest-first message catchup.");
+	// Saved before anything is written, so a crash can't leave a hole behind the messages stored above it
+	save_backfill_ranges(&ranges_path, &ranges).await?;
+
+	let mut gaps = GapTracker::new(None);
+	while let Some(range) = ranges.first_mut() {
+		if state.halted() {
+			return Ok(());
+		}
+
+		let before = range.before;
+		let page = retry_http(|| async {
+			let _permit = state.catchup_request_permit().await;
+			let http = state.http();
+			let request = http.channel_messages(channel_id).limit(SETTINGS.catchup.messages_per_request);
+			let response = match before {
+				Some(before) => request.before(Id::new(before)).await?,
+				None => request.await?,
+			};
+			Ok(response.models().await?)
+		})
+		.await?;
+
+		let batch_size = page.len();
+		progress.messages_fetched.fetch_add(batch_size, Ordering::Relaxed);
+		// API returns newest first
+		let reached_floor = page.last().is_none_or(|m| m.id.get() <= range.floor);
+		if let Some(oldest) = page.last() {
+			range.before = Some(oldest.id.get());
+		}
+
+		let floor = range.floor;
+		let mut buffer: Vec<Message> = page.into_iter().rev().filter(|m| m.id.get() > floor).collect();
+		for message in buffer.iter().rev() {
+			gaps.observe(message.id.get());
+		}
+		flush_buffer(&mut buffer, archiver, state, true).await?;
+		gaps.save(archiver, state).await?;
+
+		if reached_floor || batch_size < SETTINGS.catchup.messages_per_request as usize {
+			ranges.remove(0);
+			// The next range isn't adjacent to this one
+			gaps.restart();
+		}
+		save_backfill_ranges(&ranges_path, &ranges).await?;
+	}
 
 	info!("✅ Message catchup complete for channel");
 	Ok(())
 }
 
-async fn flush_buffer(buffer: &mut Vec<Message>, archiver: &ChannelArchiver, state: &State) -> anyhow::Result<()> {
+async fn save_backfill_ranges(path: &Path, ranges: &[BackfillRange]) -> anyhow::Result<()> {
+	// Nothing is stored, so there is no progress to remember
+	if SETTINGS.dry_run {
+		return Ok(());
+	}
+	if ranges.is_empty() {
+		return match tokio::fs::remove_file(path).await {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e).context("Failed to remove backfill ranges"),
+			_ => Ok(()),
+		};
+	}
+	tokio::fs::write(path, sonic_rs::to_vec(ranges)?)
+		.await
+		.context("Failed to save backfill ranges")
+}
+
+/// Re-lists archived threads and catches up any whose last message is newer than what is stored.
+/// Archived threads don't emit gateway events, so this is the only way to notice activity in them.
+#[instrument(skip_all)]
+pub async fn rescan_archived_threads(guild_id: Id<GuildMarker>, state: State) -> anyhow::Result<()> {
+	let Some(channels) = state.cache.guild_channels(guild_id) else {
+		return Ok(());
+	};
+	let parents: Vec<(Id<ChannelMarker>, ChannelType)> = channels
+		.iter()
+		.filter_map(|&id| state.cache.channel(id).map(|c| (id, c.kind)))
+		.filter(|&(_, kind)| is_thread_parent(kind))
+		.filter(|&(id, _)| filters::is_channel_archived(&state.cache, id))
+		.collect();
+	drop(channels);
+
+	let mut threads = Vec::new();
+	for (parent_id, kind) in parents {
+		if state.halted() {
+			return Ok(());
+		}
+		match fetch_archived_threads(parent_id, kind, &state).await {
+			Ok(t) => threads.extend(t),
+			Err(e) => warn!(%parent_id, error = ?e, "Failed to list archived threads"),
+		}
+	}
+
+	debug!("Re-scanning {} archived threads.", threads.len());
+
+	stream::iter(threads)
+		.map(|thread| (thread, state.clone()))
+		.for_each_concurrent(SETTINGS.catchup.channel_concurrency, |(thread, state)| async move {
+			if state.halted() {
+				return;
+			}
+			let thread_id = thread.id;
+			if let Err(e) = rescan_thread(guild_id, thread, state).await {
+				tracing::error!(%thread_id, error = ?e, "Failed to re-scan archived thread");
+			}
+		})
+		.await;
+
+	Ok(())
+}
+
+/// Lists the archived threads of a channel. Private ones are included if the bot may list them, which takes Manage Threads.
+async fn fetch_archived_threads(parent_id: Id<ChannelMarker>, kind: ChannelType, state: &State) -> anyhow::Result<Vec<TwilightChannel>> {
+	let mut threads = fetch_archived_thread_pages(parent_id, false, state).await?;
+	// Only text channels have private threads
+	if kind == ChannelType::GuildText {
+		match fetch_archived_thread_pages(parent_id, true, state).await {
+			Ok(private) => threads.extend(private),
+			Err(e) if e.downcast_ref::<ArchiveError>().is_some_and(ArchiveError::is_forbidden) => {
+				debug!(%parent_id, "Not allowed to list private archived threads.");
+			}
+			Err(e) => return Err(e),
+		}
+	}
+	Ok(threads)
+}
+
+async fn fetch_archived_thread_pages(parent_id: Id<ChannelMarker>, private: bool, state: &State) -> anyhow::Result<Vec<TwilightChannel>> {
+	let mut threads = Vec::new();
+	let mut before: Option<String> = None;
+
+	loop {
+		let listing = {
+			let _permit = state.catchup_request_permit().await;
+			let http = state.http();
+			let response = if private {
+				let mut request = http.private_archived_threads(parent_id);
+				if let Some(before) = &before {
+					request = request.before(before);
+				}
+				request.await
+			} else {
+				let mut request = http.public_archived_threads(parent_id);
+				if let Some(before) = &before {
+					request = request.before(before);
+				}
+				request.await
+			};
+			response.map_err(ArchiveError::from)?.model().await?
+		};
+
+		// Pagination is by archive timestamp of the oldest thread in the page
+		before = listing
+			.threads
+			.last()
+			.and_then(|t| t.thread_metadata.as_ref())
+			.map(|m| m.archive_timestamp.iso_8601().to_string());
+		threads.extend(listing.threads);
+
+		if !listing.has_more.unwrap_or(false) || before.is_none() {
+			break;
+		}
+	}
+
+	Ok(threads)
+}
+
+/// Catches up a single channel on request, resuming from its newest stored message like the full catchup does.
+#[instrument(skip_all, fields(channel_id = channel_id.get()))]
+pub async fn recatchup_channel(guild_id: Id<GuildMarker>, channel_id: Id<ChannelMarker>, state: State) -> anyhow::Result<()> {
+	let in_guild = state.cache.channel(channel_id).is_some_and(|c| c.guild_id == Some(guild_id));
+	if !in_guild {
+		warn!("Channel isn't a known channel of the guild. Ignoring re-catchup.");
+		return Ok(());
+	}
+
+	info!("Catching up channel on request.");
+	let progress = CatchupProgress::default();
+	process_channel(channel_id, state, &progress).await?;
+	info!(
+		"Channel re-catchup fetched {} messages.",
+		progress.messages_fetched.load(Ordering::Relaxed)
+	);
+	Ok(())
+}
+
+#[instrument(skip_all, fields(channel_id = thread.id.get()))]
+async fn rescan_thread(guild_id: Id<GuildMarker>, thread: TwilightChannel, state: State) -> anyhow::Result<()> {
+	let Some(latest) = thread.last_message_id else {
+		return Ok(());
+	};
+
+	// Archived threads aren't in the cache, but their parent is
+	let folder = messages::folder_for(&state.cache, thread.kind, thread.parent_id);
+	let archiver = ChannelArchiver::new(guild_id.get(), thread.id.get(), folder, &state.shutdown.clone())?;
+	if archiver.newest_stored().await? >= latest.get() {
+		return Ok(());
+	}
+
+	catchup_channel(&archiver, guild_id, thread.id, &state, &CatchupProgress::default()).await
+}
+
+const fn is_thread_parent(kind: ChannelType) -> bool {
+	matches!(
+		kind,
+		ChannelType::GuildText | ChannelType::GuildAnnouncement | ChannelType::GuildForum | ChannelType::GuildMedia
+	)
+}
+
+/// Resolves the configured `since_ms`/`since_days` bounds into the snowflake catchup should start after.
+/// Messages past the guild's retention period are never fetched, as they would just be pruned again.
+fn catchup_since_snowflake(guild_id: Id<GuildMarker>) -> anyhow::Result<Option<u64>> {
+	let since_days = match SETTINGS.catchup.since_days {
+		Some(days) => Some(get_current_time_millis()?.saturating_sub(days.saturating_mul(24 * 60 * 60 * 1000))),
+		None => None,
+	};
+
+	Ok(SETTINGS
+		.catchup
+		.since_ms
+		.max(since_days)
+		.max(retention::cutoff_ms(guild_id)?)
+		.map(millis_to_snowflake))
+}
+
+/// Two consecutive messages further apart than `catchup.gap_threshold_secs`.
+/// Hints at messages deleted before they could be archived, though it can just as well be a quiet period.
+#[derive(Debug, Serialize)]
+struct GapEvent {
+	/// The older message.
+	#[serde(rename = "a")]
+	after: u64,
+	/// The newer message.
+	#[serde(rename = "b")]
+	before: u64,
+}
+
+/// Finds gaps in the sequence of fetched message IDs, and appends them to the channel's `{channel_id}.gaps.ndjson`.
+struct GapTracker {
+	previous: Option<u64>,
+	gaps: Vec<GapEvent>,
+	/// Opened on the first save, and kept for the rest of the catchup.
+	log: Option<LogStore>,
+}
+
+impl GapTracker {
+	/// `previous` is the stored message the fetched ones continue from, if any.
+	const fn new(previous: Option<u64>) -> Self {
+		Self {
+			previous,
+			gaps: Vec::new(),
+			log: None,
+		}
+	}
+
+	/// Starts over at messages that don't continue from the ones observed so far.
+	const fn restart(&mut self) {
+		self.previous = None;
+	}
+
+	/// Checks the gap between `id` and the previously observed message. IDs have to be observed in order, either direction.
+	fn observe(&mut self, id: u64) {
+		if SETTINGS.catchup.gap_threshold_secs == 0 {
+			return;
+		}
+		if let Some(previous) = self.previous.replace(id) {
+			let (after, before) = (previous.min(id), previous.max(id));
+			if snowflake_to_millis(before) - snowflake_to_millis(after) > SETTINGS.catchup.gap_threshold_secs.saturating_mul(1000) {
+				self.gaps.push(GapEvent { after, before });
+			}
+		}
+	}
+
+	/// Writes the gaps found so far. The log is only created once there is a gap to record.
+	async fn save(&mut self, archiver: &ChannelArchiver, state: &State) -> anyhow::Result<()> {
+		if self.gaps.is_empty() {
+			return Ok(());
+		}
+		let log = match &mut self.log {
+			Some(log) => log,
+			None => self
+				.log
+				.insert(LogStore::new(archiver.path().with_extension("gaps.ndjson"), &state.shutdown)?),
+		};
+		log.append_bulk(std::mem::take(&mut self.gaps))?;
+		log.flush().await
+	}
+}
+
+/// Stores the buffered messages. `backfill` is for messages that can be older than ones already stored (newest-first catchup).
+async fn flush_buffer(buffer: &mut Vec<Message>, archiver: &ChannelArchiver, state: &State, backfill: bool) -> anyhow::Result<()> {
 	if buffer.is_empty() {
 		return Ok(());
 	}
@@ -122,8 +637,219 @@ async fn flush_buffer(buffer: &mut Vec<Message>, archiver: &ChannelArchiver, sta
 	debug!("Flushing {} messages.", buffer.len());
 
 	let batch = std::mem::take(buffer);
-	archiver.push_messages_bulk(batch, state).await?;
+	let reactions: Vec<(Id<ChannelMarker>, Id<MessageMarker>, EmojiReactionType)> = if SETTINGS.catchup.fetch_reaction_users {
+		batch
+			.iter()
+			.flat_map(|m| m.reactions.iter().map(|r| (m.channel_id, m.id, r.emoji.clone())))
+			.collect()
+	} else {
+		Vec::new()
+	};
+
+	if backfill {
+		archiver.push_messages_backfill(batch, state).await?;
+	} else {
+		archiver.push_messages_bulk(batch, state).await?;
+	}
 	archiver.flush().await?;
 
+	if !reactions.is_empty() {
+		archive_reaction_users(reactions, archiver, state).await;
+		archiver.flush().await?;
+	}
+
 	Ok(())
 }
+
+/// Stores the full list of users behind each reaction.
+/// Catchup messages only carry reaction counts, so this is the only way to know who reacted historically.
+async fn archive_reaction_users(
+	reactions: Vec<(Id<ChannelMarker>, Id<MessageMarker>, EmojiReactionType)>,
+	archiver: &ChannelArchiver,
+	state: &State,
+) {
+	debug!("Fetching users for {} reactions.", reactions.len());
+
+	stream::iter(reactions)
+		.for_each_concurrent(
+			SETTINGS.catchup.reaction_fetch_concurrency,
+			|(channel_id, message_id, emoji)| async move {
+				if state.halted() {
+					return;
+				}
+				match fetch_reaction_users(channel_id, message_id, &emoji, state).await {
+					Ok(users) => {
+						if let Err(e) = archiver.push_reaction_users(message_id.get(), &emoji, users).await {
+							warn!(%message_id, error = ?e, "Failed to store reaction users");
+						}
+					}
+					Err(e) => warn!(%message_id, error = ?e, "Failed to fetch reaction users"),
+				}
+			},
+		)
+		.await;
+}
+
+async fn fetch_reaction_users(
+	channel_id: Id<ChannelMarker>,
+	message_id: Id<MessageMarker>,
+	emoji: &EmojiReactionType,
+	state: &State,
+) -> anyhow::Result<Vec<u64>> {
+	const PAGE_SIZE: u16 = 100;
+
+	let request_emoji = match emoji {
+		EmojiReactionType::Custom { id, name, .. } => RequestReactionType::Custom {
+			id: *id,
+			name: name.as_deref(),
+		},
+		EmojiReactionType::Unicode { name } => RequestReactionType::Unicode { name },
+	};
+
+	let mut users = Vec::new();
+	let mut after: Option<Id<UserMarker>> = None;
+
+	loop {
+		let page = retry_http(|| async {
+			let _permit = state.catchup_request_permit().await;
+			let http = state.http();
+			let mut request = http.reactions(channel_id, message_id, &request_emoji).limit(PAGE_SIZE);
+			if let Some(after) = after {
+				request = request.after(after);
+			}
+			Ok(request.await?.models().await?)
+		})
+		.await?;
+
+		let page_len = page.len();
+		after = page.last().map(|u| u.id);
+		users.extend(page.into_iter().map(|u| u.id.get()));
+
+		if page_len < PAGE_SIZE as usize {
+			break;
+		}
+	}
+
+	Ok(users)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::messages::MessageEvent;
+	use crate::mock_discord::{self, MockDiscord};
+	use crate::storage::{LogEvent, for_each_record};
+	use crate::utils::create_path;
+
+	/// Messages with a Create event in the channel's log, as their ID and content, in the order they were written.
+	fn stored_messages(guild_id: u64, channel_id: u64) -> Vec<(u64, String)> {
+		let path = create_path(&[&guild_id.to_string(), "messages", &format!("{channel_id}.ndjson")]);
+		let mut stored = Vec::new();
+		for_each_record(&path, |event: LogEvent<MessageEvent>| {
+			if let MessageEvent::Create { message } = event.payload {
+				stored.push((message.id, message.content));
+			}
+		})
+		.unwrap();
+		stored
+	}
+
+	fn canned(ids: &[u64]) -> Vec<(u64, String)> {
+		ids.iter().map(|&id| (id, mock_discord::message_content(id))).collect()
+	}
+
+	#[tokio::test]
+	async fn process_channel_stores_every_message() {
+		let (guild_id, channel_id) = (1001, 2001);
+		let ids = mock_discord::message_ids(150);
+		let discord = MockDiscord::start().await;
+		discord.add_channel(guild_id, channel_id, ids.last().copied());
+		discord.serve_messages(channel_id, &ids).await;
+
+		process_channel(Id::new(channel_id), discord.state.clone(), &CatchupProgress::default())
+			.await
+			.unwrap();
+
+		assert_eq!(stored_messages(guild_id, channel_id), canned(&ids));
+		// The second page continues after the newest message of the first, and is the last as it isn't full
+		let afters = discord.afters(&format!("channels/{channel_id}/messages")).await;
+		assert_eq!(afters, [Some(1), Some(ids[99])]);
+	}
+
+	#[tokio::test]
+	async fn process_channel_skips_unreadable_channel() {
+		let (guild_id, channel_id) = (1003, 2003);
+		let discord = MockDiscord::start().await;
+		discord.add_channel(guild_id, channel_id, None);
+		let forbidden =
+			wiremock::ResponseTemplate::new(403).set_body_json(serde_json::json!({ "message": "Missing Access", "code": 50001 }));
+		discord.serve(&format!("channels/{channel_id}/messages"), forbidden).await;
+
+		process_channel(Id::new(channel_id), discord.state.clone(), &CatchupProgress::default())
+			.await
+			.unwrap();
+
+		assert!(stored_messages(guild_id, channel_id).is_empty());
+	}
+
+	/// The `after` each page of `ids` should be fetched with, paging oldest first from the start of the channel.
+	/// Paging goes on after every full page, so an exact multiple of the page size ends on an empty one.
+	fn expected_afters(ids: &[u64]) -> Vec<Option<u64>> {
+		let page_size = usize::from(SETTINGS.catchup.messages_per_request);
+		let mut afters = vec![Some(1)];
+		afters.extend(ids.chunks_exact(page_size).map(|page| page.last().copied()));
+		afters
+	}
+
+	#[tokio::test]
+	async fn catchup_pages_until_short_page() {
+		let guild_id = 1100;
+		// None, one, and exactly one and two pages of new messages
+		for (count, channel_id) in [(0, 2100), (1, 2101), (100, 2102), (200, 2103)] {
+			let ids = mock_discord::message_ids(count);
+			let discord = MockDiscord::start().await;
+			discord.add_channel(guild_id, channel_id, ids.last().copied());
+			discord.serve_messages(channel_id, &ids).await;
+			let route = format!("channels/{channel_id}/messages");
+
+			process_channel(Id::new(channel_id), discord.state.clone(), &CatchupProgress::default())
+				.await
+				.unwrap();
+			assert_eq!(stored_messages(guild_id, channel_id), canned(&ids), "{count} messages");
+			let mut afters = expected_afters(&ids);
+			assert_eq!(discord.afters(&route).await, afters, "{count} messages");
+
+			// Caught up already, so one empty page after the last stored message is all that's fetched
+			process_channel(Id::new(channel_id), discord.state.clone(), &CatchupProgress::default())
+				.await
+				.unwrap();
+			assert_eq!(stored_messages(guild_id, channel_id), canned(&ids), "{count} messages");
+			afters.push(Some(ids.last().copied().unwrap_or(1)));
+			assert_eq!(discord.afters(&route).await, afters, "{count} messages");
+		}
+	}
+
+	#[tokio::test]
+	async fn catchup_resumes_after_last_stored_message() {
+		let (guild_id, channel_id) = (1101, 2110);
+		let ids = mock_discord::message_ids(200);
+		let discord = MockDiscord::start().await;
+		let route = format!("channels/{channel_id}/messages");
+		discord.add_channel(guild_id, channel_id, Some(ids[99]));
+		discord.serve_messages(channel_id, &ids[..100]).await;
+		process_channel(Id::new(channel_id), discord.state.clone(), &CatchupProgress::default())
+			.await
+			.unwrap();
+
+		// Another page of messages is sent while the bot is away
+		discord.server.reset().await;
+		discord.add_channel(guild_id, channel_id, ids.last().copied());
+		discord.serve_messages(channel_id, &ids).await;
+		process_channel(Id::new(channel_id), discord.state.clone(), &CatchupProgress::default())
+			.await
+			.unwrap();
+
+		assert_eq!(stored_messages(guild_id, channel_id), canned(&ids));
+		assert_eq!(discord.afters(&route).await, [Some(ids[99]), Some(ids[199])]);
+	}
+}
@@ -0,0 +1,108 @@
+use crate::storage;
+use crate::utils::create_path;
+use anyhow::{Context, Result, bail};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+use twilight_model::id::Id;
+use twilight_model::id::marker::{ChannelMarker, GuildMarker};
+
+/// Name of the file in the data folder that commands are read from.
+const CONTROL_FILE: &str = "control";
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A command given to the running bot through the control file.
+#[derive(Debug)]
+pub enum ControlCommand {
+	/// Catch up a single channel again, resuming from its newest stored message.
+	Recatchup {
+		guild_id: Id<GuildMarker>,
+		channel_id: Id<ChannelMarker>,
+	},
+	/// Run a full catchup of a guild, metadata and member list included, even if the last one is recent.
+	FullCatchup {
+		guild_id: Id<GuildMarker>,
+	},
+	/// Stop archiving a guild until `Resume`, see `catchup.while_paused`. A running catchup stops at its next request.
+	Pause {
+		guild_id: Id<GuildMarker>,
+	},
+	Resume {
+		guild_id: Id<GuildMarker>,
+	},
+	/// Flush every open log to disk, so it can be copied or read from outside.
+	Flush,
+}
+
+/// A long-running task that watches for a `control` file in the data folder, one command per line.
+/// Write it in one go, e.g. `echo "recatchup <guild_id> <channel_id>" > data/control`.
+/// The file is deleted once `flush` commands have run and the others are queued, so its removal tells that logs are flushed.
+pub async fn control_file_watcher(tx: mpsc::UnboundedSender<ControlCommand>, shutdown: Arc<AtomicBool>) {
+	let path = create_path(&[CONTROL_FILE]);
+	let mut interval = tokio::time::interval(POLL_INTERVAL);
+	interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+	loop {
+		interval.tick().await;
+		if shutdown.load(Ordering::Relaxed) {
+			break;
+		}
+
+		let Ok(contents) = tokio::fs::read_to_string(&path).await else {
+			continue;
+		};
+		let mut queued = Vec::new();
+		for line in contents.lines().map(str::trim).filter(|line| !line.is_empty()) {
+			match parse_command(line) {
+				Ok(ControlCommand::Flush) => {
+					storage::flush_all().await;
+					info!("Flushed all logs on request.");
+				}
+				Ok(command) => {
+					info!(?command, "Received control command.");
+					queued.push(command);
+				}
+				Err(e) => warn!(line, error = %e, "Invalid control command"),
+			}
+		}
+
+		// Flushing again is harmless, running other commands twice isn't
+		if let Err(e) = tokio::fs::remove_file(&path).await {
+			warn!(error = ?e, "Failed to remove control file. Ignoring its commands, so they don't run twice.");
+			continue;
+		}
+		for command in queued {
+			if tx.send(command).is_err() {
+				return;
+			}
+		}
+	}
+}
+
+fn parse_command(line: &str) -> Result<ControlCommand> {
+	let mut words = line.split_whitespace();
+	match words.next() {
+		Some("recatchup") => {
+			let guild_id = words.next().context("Missing guild ID")?.parse().context("Invalid guild ID")?;
+			let channel_id = words.next().context("Missing channel ID")?.parse().context("Invalid channel ID")?;
+			Ok(ControlCommand::Recatchup { guild_id, channel_id })
+		}
+		Some("catchup") => {
+			let guild_id = words.next().context("Missing guild ID")?.parse().context("Invalid guild ID")?;
+			Ok(ControlCommand::FullCatchup { guild_id })
+		}
+		Some("pause") => {
+			let guild_id = words.next().context("Missing guild ID")?.parse().context("Invalid guild ID")?;
+			Ok(ControlCommand::Pause { guild_id })
+		}
+		Some("resume") => {
+			let guild_id = words.next().context("Missing guild ID")?.parse().context("Invalid guild ID")?;
+			Ok(ControlCommand::Resume { guild_id })
+		}
+		Some("flush") => Ok(ControlCommand::Flush),
+		Some(other) => bail!("Unknown command: {other}"),
+		None => bail!("Empty command"),
+	}
+}
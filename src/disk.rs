@@ -0,0 +1,48 @@
+use crate::alerts;
+use crate::settings::SETTINGS;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// How often to check again while downloads are paused. Short, so shutdown isn't held up.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Free inodes of the filesystem holding the data folder.
+/// `None` where it can't be queried, or on filesystems without a fixed inode count (like btrfs).
+#[cfg(unix)]
+fn free_inodes() -> Option<u64> {
+	let stats = rustix::fs::statvfs(SETTINGS.data_path.as_str()).ok()?;
+	(stats.f_files > 0).then_some(stats.f_favail)
+}
+
+#[cfg(not(unix))]
+const fn free_inodes() -> Option<u64> {
+	None
+}
+
+/// Waits while the data folder is below `storage.min_free_inodes`, or until shutdown.
+/// Each download creates a file, so running out of inodes makes every one of them fail with confusing errors.
+pub async fn wait_for_free_inodes(shutdown: &AtomicBool) {
+	let min = SETTINGS.storage.min_free_inodes;
+	if min == 0 {
+		return;
+	}
+
+	let mut paused = false;
+	while let Some(free) = free_inodes()
+		&& free < min
+		&& !shutdown.load(Ordering::Relaxed)
+	{
+		if !paused {
+			warn!(free, min, "Running out of inodes. Pausing downloads.");
+			alerts::send(format!(
+				"💾 Only {free} inodes left on the data disk, downloads are paused until some are freed."
+			));
+			paused = true;
+		}
+		tokio::time::sleep(RECHECK_INTERVAL).await;
+	}
+	if paused {
+		info!("Inodes freed up. Resuming downloads.");
+	}
+}
@@ -1,27 +1,205 @@
 use crate::State;
-use crate::messages::ChannelArchiver;
+use crate::filters;
+use crate::messages::{self, ChannelArchiver};
 use crate::metadata::{GuildUpdate, MetadataArchiver};
+use crate::network::retry_http;
+use crate::settings::{EventCategory, SETTINGS};
+use crate::utils::{get_current_time_millis, snowflake_to_millis};
 use std::collections::HashMap;
 use std::sync::Arc;
-use tracing::instrument;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, instrument, trace, warn};
 use twilight_gateway::Event;
 use twilight_model::guild::{Member, MemberFlags};
 use twilight_model::id::Id;
 use twilight_model::id::marker::{ChannelMarker, GuildMarker};
 
-fn get_archiver(
-	guild_id: Id<GuildMarker>,
-	channel_id: Id<ChannelMarker>,
-	state: &State,
-	cache: &mut HashMap<Id<ChannelMarker>, Arc<ChannelArchiver>>,
-) -> anyhow::Result<Arc<ChannelArchiver>> {
-	if let Some(archiver) = cache.get(&channel_id) {
-		return Ok(archiver.clone());
+/// Open channel archivers of a guild.
+/// Past `max_open_channel_archivers`, the least recently used archiver is flushed and dropped, and recreated on demand.
+#[derive(Default)]
+pub struct ArchiverCache {
+	archivers: HashMap<Id<ChannelMarker>, (Arc<ChannelArchiver>, u64)>,
+	clock: u64,
+}
+
+impl ArchiverCache {
+	async fn get(
+		&mut self,
+		guild_id: Id<GuildMarker>,
+		channel_id: Id<ChannelMarker>,
+		state: &State,
+	) -> anyhow::Result<Arc<ChannelArchiver>> {
+		self.clock += 1;
+
+		if let Some((archiver, last_used)) = self.archivers.get_mut(&channel_id) {
+			*last_used = self.clock;
+			return Ok(archiver.clone());
+		}
+
+		let cap = SETTINGS.storage.max_open_channel_archivers;
+		if cap > 0 && self.archivers.len() >= cap {
+			self.evict_lru().await;
+		}
+
+		let folder = messages::channel_folder(&state.cache, channel_id);
+		let archiver = Arc::new(ChannelArchiver::new(
+			guild_id.get(),
+			channel_id.get(),
+			folder,
+			&state.shutdown.clone(),
+		)?);
+		self.archivers.insert(channel_id, (archiver.clone(), self.clock));
+		Ok(archiver)
+	}
+
+	/// Makes every open archiver re-read its newest stored message.
+	/// Needed after catchup, which writes to the same logs through its own archivers.
+	pub async fn forget_newest(&self) {
+		for (archiver, _) in self.archivers.values() {
+			archiver.forget_newest().await;
+		}
+	}
+
+	/// Flushes and drops every open archiver. They are reopened on demand.
+	pub async fn close_all(&mut self) {
+		for (channel_id, (archiver, _)) in self.archivers.drain() {
+			if let Err(e) = archiver.flush().await {
+				warn!(%channel_id, error = ?e, "Failed to flush closed channel archiver");
+			}
+		}
+	}
+
+	/// Writes the held back reactions of every archiver whose batch window is up.
+	/// Returns when the next of the remaining ones is due, if any.
+	pub fn flush_due_reactions(&self) -> Option<Instant> {
+		let now = Instant::now();
+		let mut next: Option<Instant> = None;
+		for (channel_id, (archiver, _)) in &self.archivers {
+			let Some(due) = archiver.reactions_due() else { continue };
+			if due > now {
+				next = Some(next.map_or(due, |next| next.min(due)));
+			} else if let Err(e) = archiver.flush_reactions() {
+				warn!(%channel_id, error = ?e, "Failed to write held back reactions");
+			}
+		}
+		next
+	}
+
+	/// Writes the held back reactions of every archiver, due or not.
+	pub fn flush_reactions(&self) {
+		for (channel_id, (archiver, _)) in &self.archivers {
+			if let Err(e) = archiver.flush_reactions() {
+				warn!(%channel_id, error = ?e, "Failed to write held back reactions");
+			}
+		}
+	}
+
+	async fn evict_lru(&mut self) {
+		let Some(oldest) = self
+			.archivers
+			.iter()
+			.min_by_key(|(_, (_, last_used))| *last_used)
+			.map(|(&id, _)| id)
+		else {
+			return;
+		};
+		if let Some((archiver, _)) = self.archivers.remove(&oldest) {
+			trace!(channel_id = %oldest, "Evicting idle channel archiver.");
+			if let Err(e) = archiver.flush().await {
+				warn!(channel_id = %oldest, error = ?e, "Failed to flush evicted channel archiver");
+			}
+		}
+	}
+}
+
+/// Upper bounds of the event latency buckets, in milliseconds. The last bucket holds everything slower.
+const LATENCY_BUCKETS_MS: [u64; 3] = [1_000, 10_000, 60_000];
+
+/// How long events took from Discord creating them to being handled, since the last report.
+struct EventLatency {
+	events: AtomicU64,
+	max_ms: AtomicU64,
+	buckets: [AtomicU64; LATENCY_BUCKETS_MS.len() + 1],
+}
+
+static LATENCY: EventLatency = EventLatency {
+	events: AtomicU64::new(0),
+	max_ms: AtomicU64::new(0),
+	buckets: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+};
+
+/// When Discord created the event, in unix milliseconds. Only some events carry a timestamp.
+/// Message updates don't count, as their edit timestamp is stale for embed and pin updates.
+fn event_created_millis(event: &Event) -> Option<u64> {
+	match event {
+		Event::MessageCreate(m) => Some(snowflake_to_millis(m.id.get())),
+		// Only has second precision
+		Event::TypingStart(t) => Some(t.timestamp * 1000),
+		_ => None,
 	}
+}
 
-	let archiver = Arc::new(ChannelArchiver::new(guild_id.get(), channel_id.get(), &state.shutdown.clone())?);
-	cache.insert(channel_id, archiver.clone());
-	Ok(archiver)
+fn record_latency(event: &Event) {
+	let (Some(created), Ok(now)) = (event_created_millis(event), get_current_time_millis()) else {
+		return;
+	};
+	// Clock skew can put the event slightly in the future
+	let latency = now.saturating_sub(created);
+	let bucket = LATENCY_BUCKETS_MS
+		.iter()
+		.position(|&bound| latency < bound)
+		.unwrap_or(LATENCY_BUCKETS_MS.len());
+	LATENCY.events.fetch_add(1, Ordering::Relaxed);
+	LATENCY.max_ms.fetch_max(latency, Ordering::Relaxed);
+	LATENCY.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+}
+
+/// A long-running task that periodically logs how far behind realtime event handling is.
+/// Events queue up while a guild processor is busy, and ones that wait long enough can be missed on reconnects, so a growing latency
+/// explains gaps in the archive.
+pub async fn event_latency_metrics(shutdown: Arc<AtomicBool>) {
+	let mut interval = tokio::time::interval(Duration::from_secs(SETTINGS.storage.metrics_interval_secs));
+	interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+	interval.tick().await;
+
+	loop {
+		interval.tick().await;
+		if shutdown.load(Ordering::Relaxed) {
+			break;
+		}
+
+		let events = LATENCY.events.swap(0, Ordering::Relaxed);
+		let max_ms = LATENCY.max_ms.swap(0, Ordering::Relaxed);
+		let [under_1s, under_10s, under_1m, over_1m] = LATENCY.buckets.each_ref().map(|b| b.swap(0, Ordering::Relaxed));
+		if events == 0 {
+			continue;
+		}
+
+		if over_1m > 0 {
+			warn!(
+				events,
+				max_ms, under_1s, under_10s, under_1m, over_1m, "Event handling is falling behind."
+			);
+		} else {
+			info!(events, max_ms, under_1s, under_10s, under_1m, over_1m, "Event latency stats.");
+		}
+	}
+}
+
+/// Category of `event` for `disabled_events`, if it falls in one.
+pub const fn event_category(event: &Event) -> Option<EventCategory> {
+	match event {
+		Event::ReactionAdd(_) | Event::ReactionRemove(_) | Event::ReactionRemoveAll(_) | Event::ReactionRemoveEmoji(_) => {
+			Some(EventCategory::Reactions)
+		}
+		Event::MessageUpdate(_) => Some(EventCategory::Edits),
+		Event::TypingStart(_) => Some(EventCategory::Typing),
+		Event::PresenceUpdate(_) => Some(EventCategory::Presences),
+		Event::VoiceStateUpdate(_) | Event::VoiceServerUpdate(_) => Some(EventCategory::Voice),
+		_ => None,
+	}
 }
 
 #[instrument(skip_all, fields(event = ?event.kind()))]
@@ -30,15 +208,25 @@ pub async fn handle_event(
 	guild_id: Id<GuildMarker>,
 	state: &State,
 	meta: &mut MetadataArchiver,
-	chan_archivers: &mut HashMap<Id<ChannelMarker>, Arc<ChannelArchiver>>,
+	chan_archivers: &mut ArchiverCache,
 ) -> anyhow::Result<()> {
 	macro_rules! forward {
         ($channel_id:expr, $method:ident $(, $args:expr)*) => {{
-            get_archiver(guild_id, $channel_id, state, chan_archivers)?
+            if !filters::is_channel_archived(&state.cache, $channel_id) {
+                return Ok(());
+            }
+            chan_archivers.get(guild_id, $channel_id, state).await?
                 .$method($($args),*).await
         }};
     }
 
+	if event_category(&event).is_some_and(EventCategory::is_disabled) {
+		return Ok(());
+	}
+	if SETTINGS.storage.metrics_interval_secs > 0 {
+		record_latency(&event);
+	}
+
 	match event {
 		Event::MessageCreate(m) => forward!(m.channel_id, push_message, m.0, state)?,
 		Event::MessageUpdate(m) => forward!(m.channel_id, update_message, m.0)?,
@@ -53,13 +241,18 @@ pub async fn handle_event(
 		Event::ReactionRemoveAll(r) => forward!(r.channel_id, remove_all_reactions, r.message_id.get())?,
 		Event::ReactionRemoveEmoji(r) => forward!(r.channel_id, remove_emoji_reactions, r.message_id.get(), &r.emoji)?,
 
+		Event::TypingStart(t) => forward!(t.channel_id, typing_start, t.user_id.get())?,
+
+		Event::MessagePollVoteAdd(v) => forward!(v.channel_id, poll_vote, v.message_id.get(), v.user_id.get(), v.answer_id, true)?,
+		Event::MessagePollVoteRemove(v) => forward!(v.channel_id, poll_vote, v.message_id.get(), v.user_id.get(), v.answer_id, false)?,
+
 		Event::GuildUpdate(e) => meta.process_guild_update(state, GuildUpdate::Partial(&e.0))?,
 		Event::GuildEmojisUpdate(e) => {
-			let g = state.http.guild(e.guild_id).await?.model().await?;
+			let g = state.http().guild(e.guild_id).await?.model().await?;
 			meta.process_guild_update(state, GuildUpdate::Full(&g))?;
 		}
 		Event::GuildStickersUpdate(e) => {
-			let g = state.http.guild(e.guild_id).await?.model().await?;
+			let g = state.http().guild(e.guild_id).await?.model().await?;
 			meta.process_guild_update(state, GuildUpdate::Full(&g))?;
 		}
 
@@ -96,7 +289,29 @@ pub async fn handle_event(
 		Event::ThreadUpdate(e) => meta.process_channel_update(&e.0)?,
 		Event::ThreadDelete(e) => meta.process_channel_delete(e.id.get())?,
 
-		_ => {}
+		Event::InviteCreate(e) => meta.process_invite_create(&e)?,
+		Event::InviteDelete(e) => meta.process_invite_delete(&e.code)?,
+
+		Event::IntegrationCreate(e) => meta.process_integration_update(&e.0)?,
+		Event::IntegrationUpdate(e) => meta.process_integration_update(&e.0)?,
+		Event::IntegrationDelete(e) => meta.process_integration_delete(e.id.get())?,
+
+		Event::BanAdd(e) => {
+			// The event doesn't carry the reason, and fetching it needs the Ban Members permission
+			let reason = match retry_http(|| async { Ok(state.http().ban(guild_id, e.user.id).await?.model().await?) }).await {
+				Ok(ban) => ban.reason,
+				Err(err) => {
+					warn!(error = ?err, "Failed to fetch ban reason");
+					None
+				}
+			};
+			meta.process_ban_add(e.user.id.get(), reason)?;
+		}
+		Event::BanRemove(e) => meta.process_ban_remove(e.user.id.get())?,
+
+		Event::PresenceUpdate(e) => meta.process_presence_update(&e.0)?,
+
+		other => meta.process_unhandled(other)?,
 	}
 	Ok(())
 }
@@ -0,0 +1,78 @@
+use crate::State;
+use crate::dispatch;
+use crate::messages::ChannelArchiver;
+use crate::settings::{EventCategory, SETTINGS};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::sync::atomic::Ordering;
+use tokio::sync::mpsc;
+use tracing::{info, instrument, warn};
+use twilight_gateway::Event;
+use twilight_model::id::Id;
+use twilight_model::id::marker::ChannelMarker;
+
+/// Direct message channel an event belongs to, if it should go to the DM processor.
+pub fn get_event_dm_channel(event: &Event) -> Option<Id<ChannelMarker>> {
+	match event {
+		Event::MessageCreate(e) if e.guild_id.is_none() => Some(e.channel_id),
+		Event::MessageUpdate(e) if e.guild_id.is_none() => Some(e.channel_id),
+		Event::MessageDelete(e) if e.guild_id.is_none() => Some(e.channel_id),
+		Event::ReactionAdd(e) if e.guild_id.is_none() => Some(e.channel_id),
+		Event::ReactionRemove(e) if e.guild_id.is_none() => Some(e.channel_id),
+		Event::ReactionRemoveAll(e) if e.guild_id.is_none() => Some(e.channel_id),
+		Event::TypingStart(e) if e.guild_id.is_none() && SETTINGS.metadata.archive_typing => Some(e.channel_id),
+		_ => None,
+	}
+}
+
+/// Archives direct messages sent to the bot, the counterpart of a guild processor for events outside of guilds.
+/// Bots can't list their DM channels, so there is no catchup. Only messages received while running are stored.
+#[instrument(skip_all)]
+pub async fn dm_processor_task(mut rx: mpsc::UnboundedReceiver<Event>, state: State) {
+	info!("Started DM processor task.");
+	let mut archivers: HashMap<Id<ChannelMarker>, ChannelArchiver> = HashMap::new();
+
+	while let Some(event) = rx.recv().await {
+		if state.shutdown.load(Ordering::SeqCst) {
+			break;
+		}
+		let Some(channel_id) = get_event_dm_channel(&event) else {
+			continue;
+		};
+		if dispatch::event_category(&event).is_some_and(EventCategory::is_disabled) {
+			continue;
+		}
+
+		let archiver = match archivers.entry(channel_id) {
+			Entry::Occupied(entry) => entry.into_mut(),
+			Entry::Vacant(entry) => match ChannelArchiver::new_dm(channel_id.get(), &state.shutdown) {
+				Ok(archiver) => entry.insert(archiver),
+				Err(e) => {
+					warn!(%channel_id, error = ?e, "Failed to open DM log");
+					continue;
+				}
+			},
+		};
+
+		let res = match event {
+			Event::MessageCreate(m) => archiver.push_message(m.0, &state).await,
+			Event::MessageUpdate(m) => archiver.update_message(m.0).await,
+			Event::MessageDelete(m) => archiver.delete_message(m.id.get()).await,
+			Event::ReactionAdd(r) => archiver.add_reaction(r.message_id.get(), r.user_id.get(), &r.emoji).await,
+			Event::ReactionRemove(r) => archiver.remove_reaction(r.message_id.get(), r.user_id.get(), &r.emoji).await,
+			Event::ReactionRemoveAll(r) => archiver.remove_all_reactions(r.message_id.get()).await,
+			Event::TypingStart(t) => archiver.typing_start(t.user_id.get()).await,
+			_ => Ok(()),
+		};
+		if let Err(e) = res {
+			warn!(%channel_id, error = ?e, "Failed to archive DM event");
+		}
+	}
+
+	for (channel_id, archiver) in archivers {
+		if let Err(e) = archiver.flush().await {
+			warn!(%channel_id, error = ?e, "Failed to flush DM log");
+		}
+	}
+	info!("DM processor task terminated");
+}
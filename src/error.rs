@@ -1,5 +1,9 @@
 use thiserror::Error;
 
+/// Process exit status used when Discord rejects the bot token.
+/// Matches `EX_CONFIG` from sysexits, so service managers can be told not to restart on it.
+pub const EXIT_INVALID_TOKEN: u8 = 78;
+
 #[derive(Debug, Error)]
 pub enum ProcessorError {
 	#[error("Recoverable error: {0}")]
@@ -10,46 +14,181 @@ pub enum ProcessorError {
 
 impl From<anyhow::Error> for ProcessorError {
 	fn from(err: anyhow::Error) -> Self {
-		// Serialization Errors
-		// If we can't serialize the data to JSON, retrying won't fix it.
-		// This is a logic bug or data corruption.
-		if err.is::<sonic_rs::Error>() {
-			return Self::Fatal(err);
+		let fatal = match err.downcast_ref::<ArchiveError>() {
+			Some(archive_err) => archive_err.is_fatal(),
+			// Errors of paths that don't return an `ArchiveError` are classified the same way
+			None => {
+				err.downcast_ref::<std::io::Error>().is_some_and(is_fatal_io)
+					|| err.downcast_ref::<twilight_http::Error>().is_some_and(is_fatal_http)
+					|| err.is::<sonic_rs::Error>()
+			}
+		};
+		if fatal { Self::Fatal(err) } else { Self::Recoverable(err) }
+	}
+}
+
+/// Errors of the log write and catchup paths, by what went wrong.
+#[derive(Debug, Error)]
+pub enum ArchiveError {
+	#[error("I/O error: {0}")]
+	Io(#[from] std::io::Error),
+	#[error("Failed to serialize log record: {0}")]
+	Serialize(Box<dyn std::error::Error + Send + Sync>),
+	#[error("HTTP error: {0}")]
+	Http(twilight_http::Error),
+	#[error("Rate limited: {0}")]
+	RateLimited(twilight_http::Error),
+	#[error("Failed to read Discord response: {0}")]
+	Decode(#[from] twilight_http::response::DeserializeBodyError),
+	#[error("System clock is before the unix epoch: {0}")]
+	Clock(#[from] std::time::SystemTimeError),
+	#[error("Log writer is closed")]
+	WriterClosed,
+}
+
+impl ArchiveError {
+	/// Whether retrying can't help, and the processor has to stop.
+	pub fn is_fatal(&self) -> bool {
+		match self {
+			Self::Io(e) => is_fatal_io(e),
+			// If we can't serialize the data, retrying won't fix it.
+			// This is a logic bug or data corruption.
+			Self::Serialize(_) => true,
+			Self::Http(e) => is_fatal_http(e),
+			Self::RateLimited(_) | Self::Decode(_) | Self::Clock(_) | Self::WriterClosed => false,
 		}
+	}
 
-		// I/O Errors
-		if let Some(io_err) = err.downcast_ref::<std::io::Error>() {
-			match io_err.kind() {
-				std::io::ErrorKind::PermissionDenied |
-				std::io::ErrorKind::WriteZero | // Usually indicates disk full
-				std::io::ErrorKind::NotFound => return Self::Fatal(err),
-				_ => {
-					if let Some(code) = io_err.raw_os_error()
-                // 28: ENOSPC (No space left on device)
-                // 30: EROFS (Read-only file system)
-                // 5:  EIO (Input/output error - hardware failure)
-                && code == 28 | 30 | 5
-					{
-						return Self::Fatal(err);
-					}
-				}
-			}
+	/// Whether Discord refused the request for missing permissions, like reading a channel the bot can't see.
+	pub fn is_forbidden(&self) -> bool {
+		matches!(self, Self::Http(e) if matches!(e.kind(), twilight_http::error::ErrorType::Response { status, .. } if status.get() == 403))
+	}
+
+	/// Whether the failed request is likely to succeed if retried.
+	pub fn is_transient(&self) -> bool {
+		match self {
+			Self::Http(e) => is_transient_http(e),
+			Self::RateLimited(_) => true,
+			_ => false,
 		}
+	}
+}
 
-		// HTTP Errors
-		if let Some(http_err) = err.downcast_ref::<twilight_http::Error>()
-			&& let twilight_http::error::ErrorType::Response { status, .. } = http_err.kind()
-		{
-			match status.get() {
-				// 401: Unauthorized (Token invalid)
-				// 403: Forbidden (Missing permissions/Intents)
-				// 405: Method Not Allowed (API usage error)
-				401 | 403 | 405 => return Self::Fatal(err),
-				_ => {}
-			}
+impl From<twilight_http::Error> for ArchiveError {
+	fn from(err: twilight_http::Error) -> Self {
+		match err.kind() {
+			twilight_http::error::ErrorType::Response { status, .. } if status.get() == 429 => Self::RateLimited(err),
+			_ => Self::Http(err),
 		}
+	}
+}
+
+impl From<sonic_rs::Error> for ArchiveError {
+	fn from(err: sonic_rs::Error) -> Self {
+		Self::Serialize(err.into())
+	}
+}
+
+impl From<rmp_serde::encode::Error> for ArchiveError {
+	fn from(err: rmp_serde::encode::Error) -> Self {
+		Self::Serialize(err.into())
+	}
+}
+
+/// Whether an I/O error won't go away by retrying, like a full or read-only disk.
+fn is_fatal_io(err: &std::io::Error) -> bool {
+	use std::io::ErrorKind;
 
-		// Consider everything else transient
-		Self::Recoverable(err)
+	matches!(
+		err.kind(),
+		ErrorKind::PermissionDenied | ErrorKind::WriteZero | ErrorKind::NotFound | ErrorKind::StorageFull | ErrorKind::ReadOnlyFilesystem
+	) || err.raw_os_error().is_some_and(is_fatal_errno)
+}
+
+/// Whether an OS error code won't go away by retrying. Checked on top of the `ErrorKind`, which not every platform maps them to.
+const fn is_fatal_errno(code: i32) -> bool {
+	// 28: ENOSPC (No space left on device)
+	// 30: EROFS (Read-only file system)
+	// 5:  EIO (Input/output error - hardware failure)
+	matches!(code, 28 | 30 | 5)
+}
+
+/// Whether an HTTP error means the bot can't do what it is trying to, no matter how often it retries.
+fn is_fatal_http(err: &twilight_http::Error) -> bool {
+	match err.kind() {
+		// 401: Unauthorized (Token invalid)
+		// 403: Forbidden (Missing permissions/Intents)
+		// 405: Method Not Allowed (API usage error)
+		twilight_http::error::ErrorType::Response { status, .. } => matches!(status.get(), 401 | 403 | 405),
+		_ => false,
+	}
+}
+
+/// Whether an I/O error means there is no space left on the storage device.
+pub fn is_disk_full(err: &std::io::Error) -> bool {
+	err.kind() == std::io::ErrorKind::StorageFull || err.raw_os_error() == Some(28)
+}
+
+/// Whether an HTTP error means Discord rejected the bot token.
+pub fn is_unauthorized(err: &twilight_http::Error) -> bool {
+	match err.kind() {
+		twilight_http::error::ErrorType::Unauthorized => true,
+		twilight_http::error::ErrorType::Response { status, .. } => status.get() == 401,
+		_ => false,
+	}
+}
+
+/// Whether an HTTP error is likely to go away if the request is retried.
+fn is_transient_http(err: &twilight_http::Error) -> bool {
+	use twilight_http::error::ErrorType;
+
+	match err.kind() {
+		ErrorType::ChunkingResponse
+		| ErrorType::RequestCanceled
+		| ErrorType::RequestError
+		| ErrorType::RequestTimedOut
+		| ErrorType::ServiceUnavailable { .. } => true,
+		// 429: Too Many Requests, 5xx: Discord-side failures
+		ErrorType::Response { status, .. } => status.get() == 429 || status.is_server_error(),
+		_ => false,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io;
+
+	fn classify(code: i32) -> ProcessorError {
+		ProcessorError::from(anyhow::Error::new(io::Error::from_raw_os_error(code)))
+	}
+
+	#[test]
+	fn disk_errors_are_fatal() {
+		// ENOSPC, EROFS, EIO
+		for code in [28, 30, 5] {
+			assert!(matches!(classify(code), ProcessorError::Fatal(_)), "errno {code} should be fatal");
+		}
+	}
+
+	#[test]
+	fn disk_errors_are_fatal_with_context() {
+		let err = anyhow::Error::new(io::Error::from_raw_os_error(28)).context("Failed to write log");
+		assert!(matches!(ProcessorError::from(err), ProcessorError::Fatal(_)));
+	}
+
+	#[test]
+	fn other_errors_are_recoverable() {
+		// EINTR, EAGAIN
+		for code in [4, 11] {
+			assert!(
+				matches!(classify(code), ProcessorError::Recoverable(_)),
+				"errno {code} should be recoverable"
+			);
+		}
+		assert!(matches!(
+			ProcessorError::from(anyhow::anyhow!("Something went wrong")),
+			ProcessorError::Recoverable(_)
+		));
 	}
 }
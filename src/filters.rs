@@ -0,0 +1,61 @@
+use crate::settings::SETTINGS;
+use anyhow::{Context, Result};
+use regex::Regex;
+use std::sync::OnceLock;
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_model::id::Id;
+use twilight_model::id::marker::ChannelMarker;
+
+/// Compiled `channels.name_include` and `channels.name_exclude` patterns.
+struct NameFilters {
+	include: Vec<Regex>,
+	exclude: Vec<Regex>,
+}
+
+static NAME_FILTERS: OnceLock<NameFilters> = OnceLock::new();
+
+/// Compiles the channel name patterns, so an invalid one stops the bot on startup instead of being ignored.
+pub fn init() -> Result<()> {
+	let compile = |patterns: &[String], setting: &str| {
+		patterns
+			.iter()
+			.map(|pattern| Regex::new(pattern).with_context(|| format!("Invalid pattern in `channels.{setting}`: {pattern}")))
+			.collect::<Result<Vec<_>>>()
+	};
+	let filters = NameFilters {
+		include: compile(&SETTINGS.channels.name_include, "name_include")?,
+		exclude: compile(&SETTINGS.channels.name_exclude, "name_exclude")?,
+	};
+	let _ = NAME_FILTERS.set(filters);
+	Ok(())
+}
+
+/// Whether the channel name filters let `name` through.
+fn is_name_archived(name: &str) -> bool {
+	let Some(filters) = NAME_FILTERS.get() else { return true };
+	(filters.include.is_empty() || filters.include.iter().any(|re| re.is_match(name)))
+		&& !filters.exclude.iter().any(|re| re.is_match(name))
+}
+
+/// Whether messages of a channel are archived, going by its name. Threads go by the name of their parent channel.
+/// Channels missing from the cache, or without a name, are archived.
+pub fn is_channel_archived(cache: &InMemoryCache, channel_id: Id<ChannelMarker>) -> bool {
+	if NAME_FILTERS
+		.get()
+		.is_none_or(|filters| filters.include.is_empty() && filters.exclude.is_empty())
+	{
+		return true;
+	}
+	let Some(channel) = cache.channel(channel_id) else { return true };
+	// Released before looking up the parent, as holding two cache entries at once can deadlock
+	let (name, parent_id) = match channel.parent_id {
+		Some(parent_id) if channel.kind.is_thread() => (None, Some(parent_id)),
+		_ => (channel.name.clone(), None),
+	};
+	drop(channel);
+	let name = match parent_id {
+		Some(parent_id) => cache.channel(parent_id).and_then(|parent| parent.name.clone()),
+		None => name,
+	};
+	name.as_deref().is_none_or(is_name_archived)
+}
@@ -0,0 +1,167 @@
+use crate::settings::SETTINGS;
+use crate::storage::LogStore;
+use crate::utils::get_current_time_millis;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use tracing::{info, warn};
+use twilight_gateway::error::ReceiveMessageError;
+use twilight_gateway::{Event, Session};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "t")]
+enum GatewayLogEvent {
+	#[serde(rename = "r")]
+	Ready {
+		#[serde(rename = "s")]
+		session_id: String,
+		#[serde(rename = "rc")]
+		reconnects: u32,
+		#[serde(rename = "g", skip_serializing_if = "Option::is_none")]
+		gap_ms: Option<u64>,
+	},
+	#[serde(rename = "rs")]
+	Resumed {
+		#[serde(rename = "s", skip_serializing_if = "Option::is_none")]
+		session_id: Option<String>,
+		#[serde(rename = "sq", skip_serializing_if = "Option::is_none")]
+		sequence: Option<u64>,
+		#[serde(rename = "rc")]
+		reconnects: u32,
+		#[serde(rename = "g", skip_serializing_if = "Option::is_none")]
+		gap_ms: Option<u64>,
+	},
+	#[serde(rename = "c")]
+	Close {
+		#[serde(rename = "c", skip_serializing_if = "Option::is_none")]
+		code: Option<u16>,
+		#[serde(rename = "sq", skip_serializing_if = "Option::is_none")]
+		sequence: Option<u64>,
+	},
+	#[serde(rename = "rq")]
+	ReconnectRequested {
+		#[serde(rename = "sq", skip_serializing_if = "Option::is_none")]
+		sequence: Option<u64>,
+	},
+	#[serde(rename = "is")]
+	InvalidSession {
+		#[serde(rename = "r")]
+		resumable: bool,
+		#[serde(rename = "sq", skip_serializing_if = "Option::is_none")]
+		sequence: Option<u64>,
+	},
+	#[serde(rename = "e")]
+	Error {
+		#[serde(rename = "e")]
+		error: String,
+		#[serde(rename = "sq", skip_serializing_if = "Option::is_none")]
+		sequence: Option<u64>,
+	},
+}
+
+/// Records gateway connection lifecycle events, so archive gaps can be correlated with connectivity.
+#[derive(Debug)]
+pub struct GatewayLog {
+	log_store: LogStore,
+	connected_once: bool,
+	reconnects: u32,
+	disconnected_at: Option<u64>,
+}
+
+impl GatewayLog {
+	pub fn new(shutdown: &Arc<AtomicBool>) -> Result<Self> {
+		let path = Path::new(&SETTINGS.data_path).join("gateway.ndjson");
+		let log_store = LogStore::new(path, shutdown)?;
+		Ok(Self {
+			log_store,
+			connected_once: false,
+			reconnects: 0,
+			disconnected_at: None,
+		})
+	}
+
+	/// How often the gateway connected again after the first connection, by resuming or with a new session.
+	pub const fn reconnects(&self) -> u32 {
+		self.reconnects
+	}
+
+	/// Records `event` if it is a connection lifecycle event. Other events are ignored.
+	pub fn record(&mut self, event: &Event, session: Option<&Session>) {
+		let sequence = session.map(Session::sequence);
+
+		let log_event = match event {
+			Event::Ready(r) => {
+				let gap_ms = self.mark_connected();
+				GatewayLogEvent::Ready {
+					session_id: r.session_id.clone(),
+					reconnects: self.reconnects,
+					gap_ms,
+				}
+			}
+			Event::Resumed => {
+				let gap_ms = self.mark_connected();
+				info!(gap_ms, "Resumed gateway session.");
+				GatewayLogEvent::Resumed {
+					session_id: session.map(|s| s.id().to_owned()),
+					sequence,
+					reconnects: self.reconnects,
+					gap_ms,
+				}
+			}
+			Event::GatewayClose(frame) => {
+				self.mark_disconnected();
+				GatewayLogEvent::Close {
+					code: frame.as_ref().map(|f| f.code),
+					sequence,
+				}
+			}
+			Event::GatewayReconnect => {
+				self.mark_disconnected();
+				GatewayLogEvent::ReconnectRequested { sequence }
+			}
+			Event::GatewayInvalidateSession(resumable) => {
+				self.mark_disconnected();
+				GatewayLogEvent::InvalidSession {
+					resumable: *resumable,
+					sequence,
+				}
+			}
+			_ => return,
+		};
+
+		self.append(&log_event);
+	}
+
+	pub fn record_error(&mut self, error: &ReceiveMessageError, session: Option<&Session>) {
+		self.mark_disconnected();
+		self.append(&GatewayLogEvent::Error {
+			error: error.to_string(),
+			sequence: session.map(Session::sequence),
+		});
+	}
+
+	fn mark_disconnected(&mut self) {
+		if self.disconnected_at.is_none() {
+			self.disconnected_at = get_current_time_millis().ok();
+		}
+	}
+
+	/// Returns how long the gateway was disconnected, if it was.
+	fn mark_connected(&mut self) -> Option<u64> {
+		if self.connected_once {
+			self.reconnects += 1;
+		}
+		self.connected_once = true;
+
+		let disconnected_at = self.disconnected_at.take()?;
+		Some(get_current_time_millis().ok()?.saturating_sub(disconnected_at))
+	}
+
+	fn append(&self, event: &GatewayLogEvent) {
+		if let Err(e) = self.log_store.append(event) {
+			warn!(error = ?e, "Failed to record gateway event");
+		}
+	}
+}
@@ -0,0 +1,255 @@
+use crate::messages::{self, MessageEvent, StoredMessage};
+use crate::settings::SETTINGS;
+use crate::storage::{self, LogEvent};
+use crate::utils::{create_path, int_to_str};
+use anyhow::{Context, Result, bail};
+use rusqlite::{Connection, Transaction, params};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs;
+use std::path::Path;
+use std::sync::OnceLock;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, error, info};
+use twilight_model::id::Id;
+use twilight_model::id::marker::GuildMarker;
+
+const INDEX_FILE: &str = "index.db";
+
+const SCHEMA: &str = "
+	PRAGMA journal_mode = WAL;
+	PRAGMA synchronous = NORMAL;
+	CREATE TABLE IF NOT EXISTS messages (
+		message_id INTEGER PRIMARY KEY,
+		channel_id INTEGER NOT NULL,
+		author_id INTEGER NOT NULL,
+		created_at INTEGER NOT NULL,
+		has_attachments INTEGER NOT NULL,
+		deleted INTEGER NOT NULL DEFAULT 0
+	);
+	CREATE INDEX IF NOT EXISTS messages_by_author ON messages (author_id, created_at);
+	CREATE INDEX IF NOT EXISTS messages_by_channel ON messages (channel_id, created_at);
+";
+
+/// The indexed fields of a message.
+#[derive(Debug)]
+pub struct IndexedMessage {
+	message_id: u64,
+	channel_id: u64,
+	author_id: u64,
+	created_at: u64,
+	has_attachments: bool,
+}
+
+impl IndexedMessage {
+	pub const fn new(channel_id: u64, message: &StoredMessage) -> Self {
+		Self {
+			message_id: message.id,
+			channel_id,
+			author_id: message.author_id,
+			created_at: message.created_at,
+			has_attachments: !message.attachments.is_empty(),
+		}
+	}
+}
+
+enum IndexUpdate {
+	Insert(Vec<IndexedMessage>),
+	Delete(Vec<u64>),
+	/// Drops messages created before the given unix millis, after retention pruned them from the logs.
+	Prune(u64),
+}
+
+enum IndexCommand {
+	Update(u64, IndexUpdate),
+	Shutdown(oneshot::Sender<()>),
+}
+
+static INDEX: OnceLock<mpsc::UnboundedSender<IndexCommand>> = OnceLock::new();
+
+/// Starts the index writer if `storage.sqlite_index` is on. Without it, index updates are dropped.
+pub fn init() {
+	if !SETTINGS.storage.sqlite_index || SETTINGS.dry_run {
+		return;
+	}
+	let (tx, rx) = mpsc::unbounded_channel();
+	if INDEX.set(tx).is_err() {
+		return;
+	}
+	std::thread::Builder::new()
+		.name("sqlite-index".to_owned())
+		.spawn(move || index_writer_loop(rx))
+		.expect("Failed to spawn index writer thread");
+	info!("SQLite message index enabled.");
+}
+
+fn send(cmd: IndexCommand) {
+	if let Some(tx) = INDEX.get() {
+		let _ = tx.send(cmd);
+	}
+}
+
+pub fn insert(guild_id: u64, messages: Vec<IndexedMessage>) {
+	if !messages.is_empty() {
+		send(IndexCommand::Update(guild_id, IndexUpdate::Insert(messages)));
+	}
+}
+
+pub fn mark_deleted(guild_id: u64, message_ids: Vec<u64>) {
+	send(IndexCommand::Update(guild_id, IndexUpdate::Delete(message_ids)));
+}
+
+pub fn prune(guild_id: u64, before_ms: u64) {
+	send(IndexCommand::Update(guild_id, IndexUpdate::Prune(before_ms)));
+}
+
+/// Commits every pending update and stops the index writer. Does nothing if it was never started.
+pub async fn close() {
+	let Some(tx) = INDEX.get() else { return };
+	let (respond_to, rx) = oneshot::channel();
+	if tx.send(IndexCommand::Shutdown(respond_to)).is_ok() {
+		let _ = rx.await;
+	}
+}
+
+fn open(guild_id: u64) -> Result<Connection> {
+	let path = create_path(&[&int_to_str!(guild_id, u64), INDEX_FILE]);
+	let conn = Connection::open(&path).with_context(|| format!("Failed to open {}", path.display()))?;
+	conn.execute_batch(SCHEMA)?;
+	Ok(conn)
+}
+
+fn index_writer_loop(mut rx: mpsc::UnboundedReceiver<IndexCommand>) {
+	let mut connections: HashMap<u64, Connection> = HashMap::new();
+
+	while let Some(cmd) = rx.blocking_recv() {
+		// Everything queued meanwhile goes into one transaction per guild, as SQLite commits are slow
+		let mut batch: HashMap<u64, Vec<IndexUpdate>> = HashMap::new();
+		let mut shutdown = None;
+		for cmd in std::iter::once(cmd).chain(std::iter::from_fn(|| rx.try_recv().ok())) {
+			match cmd {
+				IndexCommand::Update(guild_id, update) => batch.entry(guild_id).or_default().push(update),
+				IndexCommand::Shutdown(respond_to) => {
+					shutdown = Some(respond_to);
+					break;
+				}
+			}
+		}
+
+		for (guild_id, updates) in batch {
+			let conn = match connections.entry(guild_id) {
+				Entry::Occupied(e) => e.into_mut(),
+				Entry::Vacant(e) => match open(guild_id) {
+					Ok(conn) => e.insert(conn),
+					Err(err) => {
+						error!(guild_id, error = ?err, "Failed to open message index");
+						continue;
+					}
+				},
+			};
+			if let Err(e) = conn.transaction().map_err(anyhow::Error::from).and_then(|tx| apply(tx, updates)) {
+				error!(guild_id, error = ?e, "Failed to update message index");
+			}
+		}
+
+		if let Some(respond_to) = shutdown {
+			let _ = respond_to.send(());
+			break;
+		}
+	}
+	debug!("Index writer shutting down.");
+}
+
+fn apply(tx: Transaction<'_>, updates: Vec<IndexUpdate>) -> Result<()> {
+	{
+		let mut insert = tx.prepare_cached(
+			"INSERT OR IGNORE INTO messages (message_id, channel_id, author_id, created_at, has_attachments) VALUES (?1, ?2, ?3, ?4, ?5)",
+		)?;
+		let mut delete = tx.prepare_cached("UPDATE messages SET deleted = 1 WHERE message_id = ?1")?;
+		let mut prune = tx.prepare_cached("DELETE FROM messages WHERE created_at < ?1")?;
+
+		for update in updates {
+			match update {
+				IndexUpdate::Insert(messages) => {
+					for m in messages {
+						insert.execute(params![
+							m.message_id.cast_signed(),
+							m.channel_id.cast_signed(),
+							m.author_id.cast_signed(),
+							m.created_at.cast_signed(),
+							m.has_attachments
+						])?;
+					}
+				}
+				IndexUpdate::Delete(ids) => {
+					for id in ids {
+						delete.execute(params![id.cast_signed()])?;
+					}
+				}
+				IndexUpdate::Prune(before_ms) => {
+					prune.execute(params![before_ms.cast_signed()])?;
+				}
+			}
+		}
+	}
+	tx.commit()?;
+	Ok(())
+}
+
+/// Rebuilds a guild's message index from its logs. Run with `BigBrother reindex <guild_id>` while the bot is stopped,
+/// as a running bot keeps writing to the index it had open.
+pub fn rebuild(guild_id: &str) -> Result<()> {
+	let guild_id: Id<GuildMarker> = guild_id.parse().with_context(|| format!("Invalid guild ID: {guild_id}"))?;
+	let guild = int_to_str!(guild_id.get(), u64);
+
+	// Built next to it and renamed over, so a failed rebuild leaves the old index in place
+	let path = create_path(&[&guild, INDEX_FILE]);
+	let tmp = path.with_extension("db.tmp");
+	let _ = fs::remove_file(&tmp);
+	let mut conn = Connection::open(&tmp).with_context(|| format!("Failed to create {}", tmp.display()))?;
+	conn.execute_batch(SCHEMA)?;
+
+	let dirs = messages::message_log_dirs(&guild);
+	if !dirs[0].is_dir() {
+		bail!("No messages archived at {}", dirs[0].display());
+	}
+	let mut total = 0;
+	for dir in dirs {
+		for entry in fs::read_dir(&dir)? {
+			let path = entry?.path();
+			// Only channel logs, named after the channel
+			let Some(channel_id) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u64>().ok()) else {
+				continue;
+			};
+			if path.is_file() && storage::is_log_file(&path) {
+				total += index_log(&mut conn, channel_id, &path)?;
+			}
+		}
+	}
+	drop(conn);
+
+	// Left over from the old index, and would be applied to the new one
+	for suffix in ["db-wal", "db-shm"] {
+		let _ = fs::remove_file(path.with_extension(suffix));
+	}
+	fs::rename(&tmp, &path).with_context(|| format!("Failed to replace {}", path.display()))?;
+	info!(messages = total, "Rebuilt message index.");
+	Ok(())
+}
+
+/// Indexes every message of a channel log. Returns how many messages were created in it.
+fn index_log(conn: &mut Connection, channel_id: u64, path: &Path) -> Result<usize> {
+	let mut updates = Vec::new();
+	let mut created = 0;
+	storage::for_each_record(path, |event: LogEvent<MessageEvent>| match event.payload {
+		MessageEvent::Create { message } => {
+			created += 1;
+			updates.push(IndexUpdate::Insert(vec![IndexedMessage::new(channel_id, &message)]));
+		}
+		MessageEvent::Delete { id } => updates.push(IndexUpdate::Delete(vec![id])),
+		MessageEvent::BulkDelete { ids } => updates.push(IndexUpdate::Delete(ids)),
+		_ => {}
+	})?;
+	apply(conn.transaction()?, updates)?;
+	Ok(created)
+}
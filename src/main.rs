@@ -1,126 +1,332 @@
+mod alerts;
 mod catchup;
+mod control;
+mod disk;
 mod dispatch;
+mod dms;
 mod error;
+mod filters;
+mod gateway;
+mod index;
+mod manifest;
 mod messages;
 mod metadata;
+#[cfg(test)]
+mod mock_discord;
 mod network;
+mod object_store;
+mod packer;
+mod presence;
+mod replay;
+mod retention;
 mod settings;
+mod snapshot;
 mod storage;
+mod usage;
 mod utils;
 
-use crate::catchup::run_full_guild_catchup;
-use crate::error::ProcessorError;
-use crate::messages::ChannelArchiver;
+use crate::catchup::{recatchup_channel, rescan_archived_threads, run_full_guild_catchup};
+use crate::control::ControlCommand;
+use crate::dispatch::ArchiverCache;
+use crate::error::{EXIT_INVALID_TOKEN, ProcessorError, is_unauthorized};
+use crate::gateway::GatewayLog;
 use crate::metadata::MetadataArchiver;
-use crate::network::{DownloadRequest, DownloadTracker, asset_downloader_worker};
-use crate::settings::SETTINGS;
+use crate::network::{AssetKind, DownloadRequest, DownloadTracker, QueuedDownload, asset_downloader_worker};
+use crate::settings::{CacheResource, MetadataKind, PausePolicy, QueueFullPolicy, SETTINGS};
 use crate::utils::HumanUptime;
 use anyhow::Context;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::process::ExitCode;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError, RwLock};
 use std::time::Duration;
-use tokio::sync::mpsc;
 use tokio::sync::mpsc::Sender;
-use tracing::{error, info, instrument, warn};
+use tokio::sync::mpsc::error::{SendTimeoutError, TrySendError};
+use tokio::sync::{OwnedRwLockWriteGuard, OwnedSemaphorePermit, Semaphore, mpsc};
+use tokio::task::JoinSet;
+use tokio::time::Instant;
+use tracing::{debug, error, info, instrument, warn};
 use tracing_appender::non_blocking;
-use tracing_subscriber::{EnvFilter, FmtSubscriber};
+use tracing_subscriber::layer::SubscriberExt as _;
+use tracing_subscriber::{EnvFilter, Layer as _};
 use twilight_cache_inmemory::{DefaultInMemoryCache, InMemoryCache, ResourceType};
 use twilight_gateway::{Event, EventTypeFlags, Intents, Shard, ShardId, StreamExt as _};
 use twilight_http::Client as HttpClient;
-use twilight_model::gateway::CloseFrame;
+use twilight_model::gateway::{CloseCode, CloseFrame};
 use twilight_model::id::Id;
 use twilight_model::id::marker::{ChannelMarker, GuildMarker};
 
+// mimalloc wins if both are enabled, so `--features mimalloc` works without turning off the defaults
+#[cfg(all(feature = "snmalloc", not(feature = "mimalloc")))]
 #[global_allocator]
 static ALLOC: snmalloc_rs::SnMalloc = snmalloc_rs::SnMalloc;
 
+#[cfg(feature = "mimalloc")]
+#[global_allocator]
+static ALLOC: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
 #[derive(Debug)]
 pub enum GuildQueueEvent {
 	InitialCatchup,
+	/// A full catchup regardless of `catchup.full_catchup_interval_secs`, requested through the control file.
+	FullCatchup,
+	RescanArchivedThreads,
+	PruneExpired,
+	CompactMetadata,
+	RecatchupChannel(Id<ChannelMarker>),
+	SampleStats,
+	/// Wakes the processor after the guild was unpaused, to handle the events it held meanwhile.
+	Resume,
 	GatewayEvent(Box<Event>),
 }
 
+impl GuildQueueEvent {
+	/// Periodic upkeep that can wait for its next tick, and so is skipped while paused.
+	const fn is_upkeep(&self) -> bool {
+		matches!(self, Self::PruneExpired | Self::CompactMetadata | Self::SampleStats)
+	}
+
+	/// A copy of a catchup event, to run it again after a pause cut it short.
+	const fn catchup_again(&self) -> Option<Self> {
+		match self {
+			Self::InitialCatchup => Some(Self::InitialCatchup),
+			Self::FullCatchup => Some(Self::FullCatchup),
+			Self::RescanArchivedThreads => Some(Self::RescanArchivedThreads),
+			Self::RecatchupChannel(channel_id) => Some(Self::RecatchupChannel(*channel_id)),
+			_ => None,
+		}
+	}
+}
+
+/// Per-guild caps on shared resources, so one guild can't starve the others.
+#[derive(Clone)]
+pub struct GuildLimits {
+	downloads: Option<Arc<Semaphore>>,
+	requests: Option<Arc<Semaphore>>,
+}
+
+impl GuildLimits {
+	fn new() -> Self {
+		let semaphore = |limit: usize| (limit > 0).then(|| Arc::new(Semaphore::new(limit)));
+		Self {
+			downloads: semaphore(SETTINGS.network.guild_download_limit),
+			requests: semaphore(SETTINGS.catchup.guild_request_limit),
+		}
+	}
+}
+
 #[derive(Clone)]
 pub struct State {
-	pub http: Arc<HttpClient>,
+	/// Shared by every copy of the state, so switching to a fallback token reaches every task at once. See `http`.
+	http: Arc<RwLock<Arc<HttpClient>>>,
 	pub cache: Arc<InMemoryCache>,
-	pub file_downloader: Sender<DownloadRequest>,
+	pub file_downloader: Sender<QueuedDownload>,
 	pub pending_downloads: Arc<AtomicUsize>,
 	pub download_tracker: Arc<DownloadTracker>,
 	pub shutdown: Arc<AtomicBool>,
+	pub guild_limits: GuildLimits,
+	/// Whether `submit_download` downloads anything, off for text-only guilds.
+	pub download_assets: bool,
+	/// Slots for guilds running a full catchup, shared by all guilds. `None` if unlimited.
+	pub catchup_slots: Option<Arc<Semaphore>>,
+	/// Set by the `pause` control command. Only meaningful in a guild processor's copy of the state.
+	pub paused: Arc<AtomicBool>,
+	/// Held while the guild processors of a rejected token wind down. New processors wait for it, see `drain_processors`.
+	handover: Arc<tokio::sync::RwLock<()>>,
 }
 
 impl State {
-	pub const fn new(
+	pub fn new(
 		http: Arc<HttpClient>,
 		cache: Arc<InMemoryCache>,
-		file_downloader: Sender<DownloadRequest>,
+		file_downloader: Sender<QueuedDownload>,
 		pending_downloads: Arc<AtomicUsize>,
 		download_tracker: Arc<DownloadTracker>,
 		shutdown: Arc<AtomicBool>,
 	) -> Self {
 		Self {
-			http,
+			http: Arc::new(RwLock::new(http)),
 			cache,
 			file_downloader,
 			pending_downloads,
 			download_tracker,
 			shutdown,
+			guild_limits: GuildLimits {
+				downloads: None,
+				requests: None,
+			},
+			download_assets: SETTINGS.network.download_assets,
+			catchup_slots: (SETTINGS.catchup.max_concurrent_guild_catchups > 0)
+				.then(|| Arc::new(Semaphore::new(SETTINGS.catchup.max_concurrent_guild_catchups))),
+			paused: Arc::new(AtomicBool::new(false)),
+			handover: Arc::default(),
 		}
 	}
 
-	pub fn submit_download(&self, url: String, folder: PathBuf, filename: String) {
-		if self.shutdown.load(Ordering::SeqCst) {
-			return;
+	/// The Discord API client of the token in use.
+	pub fn http(&self) -> Arc<HttpClient> {
+		self.http.read().unwrap_or_else(PoisonError::into_inner).clone()
+	}
+
+	fn set_http(&self, http: Arc<HttpClient>) {
+		*self.http.write().unwrap_or_else(PoisonError::into_inner) = http;
+	}
+
+	/// A copy of the state for a guild processor, with its own `GuildLimits` and pause flag.
+	fn for_guild(&self, guild_id: Id<GuildMarker>, paused: Arc<AtomicBool>) -> Self {
+		Self {
+			guild_limits: GuildLimits::new(),
+			download_assets: network::download_assets(guild_id),
+			paused,
+			..self.clone()
 		}
+	}
+
+	/// Whether catchups should stop where they are, because the bot is shutting down or the guild was paused.
+	pub fn halted(&self) -> bool {
+		self.shutdown.load(Ordering::Relaxed) || self.paused.load(Ordering::Relaxed)
+	}
 
-		let req = DownloadRequest { url, folder, filename };
+	/// Waits for a free slot in this guild's catchup request limit.
+	/// Hold the returned permit until the request is done.
+	pub async fn catchup_request_permit(&self) -> Option<OwnedSemaphorePermit> {
+		let semaphore = self.guild_limits.requests.clone()?;
+		semaphore.acquire_owned().await.ok()
+	}
+
+	pub fn submit_download(&self, kind: AssetKind, url: String, folder: PathBuf, filename: String) {
+		self.submit_downloads(vec![DownloadRequest {
+			url,
+			folder,
+			filename,
+			kind,
+		}]);
+	}
+
+	/// Like `submit_download` for many files, like the attachments of a catchup batch.
+	/// They share a single task and log write, instead of one of each per file.
+	pub fn submit_downloads(&self, reqs: Vec<DownloadRequest>) {
+		if reqs.is_empty() || !self.download_assets || self.shutdown.load(Ordering::SeqCst) {
+			return;
+		}
 
 		let tx = self.file_downloader.clone();
 		let counter = self.pending_downloads.clone();
 		let tracker = self.download_tracker.clone();
+		let guild_downloads = self.guild_limits.downloads.clone();
+		counter.fetch_add(reqs.len(), Ordering::SeqCst);
 
 		tokio::spawn(async move {
-			counter.fetch_add(1, Ordering::SeqCst);
-
-			if let Err(e) = tracker.log_start(&req) {
-				error!(?req, error = %e, "Failed to log download start. Aborting submission.");
-				counter.fetch_sub(1, Ordering::SeqCst);
+			let total = reqs.len();
+			if let Err(e) = tracker.log_starts(&reqs).await {
+				error!(count = total, error = %e, "Failed to log download start. Aborting submission.");
+				counter.fetch_sub(total, Ordering::SeqCst);
 				return;
 			}
 
-			if tx.send(req).await.is_err() {
-				warn!("Asset queue closed, failed to schedule download.");
-				counter.fetch_sub(1, Ordering::SeqCst);
+			for (sent, request) in reqs.into_iter().enumerate() {
+				// Waits here rather than in the shared queue, so other guilds' downloads can get ahead
+				let guild_permit = match &guild_downloads {
+					Some(semaphore) => semaphore.clone().acquire_owned().await.ok(),
+					None => None,
+				};
+
+				if tx.send(QueuedDownload { request, guild_permit }).await.is_err() {
+					warn!("Asset queue closed, failed to schedule download.");
+					counter.fetch_sub(total - sent, Ordering::SeqCst);
+					return;
+				}
 			}
 		});
 	}
 }
 
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
+async fn main() -> anyhow::Result<ExitCode> {
 	let (non_blocking_writer, _guard) = non_blocking(std::io::stdout());
-	tracing::subscriber::set_global_default(
-		FmtSubscriber::builder()
-			.with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
-			.compact()
-			.with_target(false)
-			.with_timer(HumanUptime::new())
-			.with_writer(non_blocking_writer)
-			.finish(),
-	)
-	.expect("setting default subscriber failed");
-
-	if SETTINGS.discord_token.is_empty() {
+	let fmt_layer = tracing_subscriber::fmt::layer()
+		.compact()
+		.with_target(false)
+		.with_timer(HumanUptime::new())
+		.with_writer(non_blocking_writer)
+		.with_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")));
+	// Filtered on its own, as the console needs the runtime's task events the filter above drops
+	tracing::subscriber::set_global_default(tracing_subscriber::registry().with(console_layer()).with(fmt_layer))
+		.expect("setting default subscriber failed");
+	if SETTINGS.tokio_console && !cfg!(feature = "console") {
+		warn!("`tokio_console` needs a build with the `console` feature. Ignoring it.");
+	}
+
+	storage::init_encryption()?;
+	filters::init()?;
+	messages::check_folders()?;
+	network::check_asset_size()?;
+
+	// Offline tools that only read the archive
+	let args: Vec<String> = std::env::args().skip(1).collect();
+	match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+		[] => {}
+		["usage", guild_id] => {
+			usage::run(guild_id)?;
+			return Ok(ExitCode::SUCCESS);
+		}
+		["reindex", guild_id] => {
+			index::rebuild(guild_id)?;
+			return Ok(ExitCode::SUCCESS);
+		}
+		["replay", guild_id] => {
+			replay::run(guild_id)?;
+			return Ok(ExitCode::SUCCESS);
+		}
+		["tree", guild_id] => {
+			replay::print_tree(guild_id, None)?;
+			return Ok(ExitCode::SUCCESS);
+		}
+		["tree", guild_id, at] => {
+			replay::print_tree(guild_id, Some(at))?;
+			return Ok(ExitCode::SUCCESS);
+		}
+		["export", guild_id, out] => {
+			snapshot::export(guild_id, out)?;
+			return Ok(ExitCode::SUCCESS);
+		}
+		["import", path] => {
+			snapshot::import(path)?;
+			return Ok(ExitCode::SUCCESS);
+		}
+		_ => anyhow::bail!(
+			"Unknown arguments: {args:?}. Usage: BigBrother [usage <guild_id> | reindex <guild_id> | replay <guild_id> | \
+			 tree <guild_id> [unix_millis] | export <guild_id> <file> | import <file>]"
+		),
+	}
+
+	let tokens = SETTINGS.discord_tokens();
+	if tokens.is_empty() {
 		anyhow::bail!("DISCORD_TOKEN is not set.");
 	}
 
-	let mut shard = Shard::new(ShardId::ONE, SETTINGS.discord_token.clone(), Intents::all());
+	alerts::init();
+	index::init();
+
+	if SETTINGS.dry_run {
+		warn!("Dry run: events are processed as usual, but nothing is written to disk or downloaded.");
+	}
+	if !SETTINGS.network.proxy_url.is_empty() {
+		warn!("`network.proxy_url` only applies to downloads, object storage and webhooks. Discord connections are made directly.");
+	}
 
-	let http = Arc::new(HttpClient::new(SETTINGS.discord_token.clone()));
-	let cache = Arc::new(DefaultInMemoryCache::builder().resource_types(ResourceType::all()).build());
+	// Fail fast on a bad token instead of letting every guild task hit 401s
+	let Some((mut token_index, http)) = first_accepted_token(&tokens, 0).await else {
+		error!("Discord rejected every bot token (401 Unauthorized). Check `discord_token` and restart manually.");
+		alerts::send("🔑 Discord rejected the bot token. The archiver did not start.".to_owned());
+		alerts::flush().await;
+		return Ok(ExitCode::from(EXIT_INVALID_TOKEN));
+	};
+	let mut shard = Shard::new(ShardId::ONE, tokens[token_index].clone(), Intents::all());
+
+	let log_uploader = object_store::init()?;
+	let cache = new_cache();
 	let shutdown = Arc::new(AtomicBool::new(false));
 
 	let (asset_tx, asset_rx) = mpsc::channel(50_000);
@@ -131,7 +337,13 @@ async fn main() -> anyhow::Result<()> {
 	if !unfinished_downloads.is_empty() {
 		info!("Re-queuing {} unfinished downloads.", unfinished_downloads.len());
 		for req in unfinished_downloads {
-			asset_tx.send(req).await.context("Failed to re-queue download task")?;
+			asset_tx
+				.send(QueuedDownload {
+					request: req,
+					guild_permit: None,
+				})
+				.await
+				.context("Failed to re-queue download task")?;
 		}
 	}
 
@@ -142,37 +354,149 @@ async fn main() -> anyhow::Result<()> {
 		shutdown.clone(),
 	));
 
-	let state = State::new(http, cache, asset_tx, pending_downloads.clone(), download_tracker, shutdown.clone());
+	if SETTINGS.storage.pack_assets_after_days > 0 && !SETTINGS.dry_run {
+		tokio::spawn(packer::asset_packer(shutdown.clone()));
+	}
+	if SETTINGS.storage.metrics_interval_secs > 0 {
+		tokio::spawn(storage::log_writer_metrics(shutdown.clone()));
+		tokio::spawn(dispatch::event_latency_metrics(shutdown.clone()));
+	}
+	if SETTINGS.storage.manifest_interval_secs > 0 && !SETTINGS.dry_run {
+		tokio::spawn(manifest::manifest_writer(shutdown.clone()));
+	}
+	if SETTINGS.storage.disk_usage_interval_secs > 0 {
+		tokio::spawn(manifest::disk_usage_reporter(shutdown.clone()));
+	}
+	if SETTINGS.network.download_log_compact_interval_secs > 0 && !SETTINGS.dry_run {
+		tokio::spawn(network::download_log_compactor(download_tracker.clone(), shutdown.clone()));
+	}
 
-	let mut guild_processors: HashMap<u64, mpsc::UnboundedSender<GuildQueueEvent>> = HashMap::new();
+	let mut state = State::new(http, cache, asset_tx, pending_downloads.clone(), download_tracker, shutdown.clone());
+
+	let mut guild_processors: HashMap<u64, GuildQueue> = HashMap::new();
+	let mut processor_tasks = JoinSet::new();
+	let dm_processor = SETTINGS.metadata.archive_direct_messages.then(|| {
+		let (tx, rx) = mpsc::unbounded_channel();
+		tokio::spawn(dms::dm_processor_task(rx, state.clone()));
+		tx
+	});
 
 	info!("Bot starting...");
 
+	let (control_tx, mut control_rx) = mpsc::unbounded_channel();
+	tokio::spawn(control::control_file_watcher(control_tx, shutdown.clone()));
+
+	let mut token_rejected = false;
+	let mut gateway_log = GatewayLog::new(&shutdown)?;
+	// Close code of the last connection Discord closed, to tell why the shard stopped
+	let mut last_close_code: Option<u16> = None;
+	let mut restarts: u32 = 0;
+
 	loop {
 		let item = tokio::select! {
 			e = shard.next_event(EventTypeFlags::all()) => e,
+			Some(command) = control_rx.recv() => {
+				handle_control_command(command, &mut guild_processors, &mut processor_tasks, &state).await;
+				continue;
+			}
 			_ = tokio::signal::ctrl_c() => break,
 		};
 
-		// Handle Shard close
+		// The shard reconnects and resumes by itself, and only ends for close codes it can't reconnect after
 		let Some(result) = item else {
-			break;
+			let code = last_close_code.and_then(|code| CloseCode::try_from(code).ok());
+			if code.is_some_and(|code| !code.can_reconnect()) {
+				error!(
+					?code,
+					"Gateway closed the connection and doesn't allow reconnecting. Shutting down."
+				);
+				alerts::send(format!(
+					"🚨 The gateway closed the connection ({code:?}) and doesn't allow reconnecting. The archiver is shutting down."
+				));
+				break;
+			}
+			// Anything else is unexpected, so start over with a new shard rather than stop archiving
+			let delay = Duration::from_secs(1 << restarts.min(6));
+			restarts += 1;
+			warn!(?delay, "Gateway connection ended. Reconnecting with a new session.");
+			tokio::select! {
+				() = tokio::time::sleep(delay) => {}
+				_ = tokio::signal::ctrl_c() => break,
+			}
+			shard = Shard::new(ShardId::ONE, tokens[token_index].clone(), Intents::all());
+			continue;
 		};
 
 		// Handle Network Error
-		let Ok(event) = result else {
-			warn!(source = ?result.unwrap_err(), "Gateway error");
-			continue;
+		let event = match result {
+			Ok(event) => event,
+			Err(source) => {
+				warn!(?source, "Gateway error");
+				gateway_log.record_error(&source, shard.session());
+				continue;
+			}
 		};
 
 		state.cache.update(&event);
+		gateway_log.record(&event, shard.session());
+
+		if let Event::GatewayClose(frame) = &event {
+			last_close_code = frame.as_ref().map(|frame| frame.code);
+		}
 
 		match event {
+			Event::GatewayClose(Some(frame)) if frame.code == CloseCode::AuthenticationFailed as u16 => {
+				let Some((next, http)) = first_accepted_token(&tokens, token_index + 1).await else {
+					error!("Gateway rejected the bot token (Authentication Failed), and there is no other token to use. Shutting down.");
+					alerts::send("🔑 The gateway rejected the bot token. The archiver is shutting down.".to_owned());
+					token_rejected = true;
+					break;
+				};
+				warn!(
+					token = next + 1,
+					"Gateway rejected the bot token (Authentication Failed). Switching to the next one."
+				);
+				alerts::send(format!(
+					"🔑 The gateway rejected bot token #{}. Switched to token #{}.",
+					token_index + 1,
+					next + 1
+				));
+
+				// Guilds are caught up again by the new session. Their old processors have to stop first, so two don't write the same logs.
+				// They wind down off the gateway loop, while the processors of the new session wait for them
+				guild_processors.clear();
+				let handover = Arc::new(tokio::sync::RwLock::new(()));
+				let held = handover.clone().try_write_owned().expect("Nothing else holds a new lock");
+				tokio::spawn(drain_processors(std::mem::take(&mut processor_tasks), held));
+				state.handover = handover;
+
+				token_index = next;
+				state.set_http(http);
+				state.cache.clear();
+				shard = Shard::new(ShardId::ONE, tokens[token_index].clone(), Intents::all());
+			}
 			Event::Ready(r) => {
-				info!("🏃 Connected to {} guilds.", r.guilds.len());
+				restarts = 0;
+				// A resumed session replays what was missed, a new one doesn't. Each guild's GuildCreate then catches it up again
+				if gateway_log.reconnects() > 0 {
+					info!(
+						"🔄 Started a new gateway session with {} guilds. Events sent meanwhile are caught up.",
+						r.guilds.len()
+					);
+				} else {
+					info!("🏃 Connected to {} guilds.", r.guilds.len());
+				}
 			}
+			Event::Resumed => restarts = 0,
 			Event::GuildCreate(e) => {
-				dispatch_guild_event(&mut guild_processors, e.id(), GuildQueueEvent::InitialCatchup, &state);
+				dispatch_guild_event(
+					&mut guild_processors,
+					&mut processor_tasks,
+					e.id(),
+					GuildQueueEvent::InitialCatchup,
+					&state,
+				)
+				.await;
 			}
 			Event::GuildDelete(e) => {
 				info!(guild_id = %e.id, "Left guild. Stopping processor.");
@@ -180,7 +504,12 @@ async fn main() -> anyhow::Result<()> {
 			}
 			_ => {
 				if let Some(gid) = utils::get_event_guild_id(&event) {
-					dispatch_guild_event(&mut guild_processors, gid, GuildQueueEvent::GatewayEvent(Box::new(event)), &state);
+					let event = GuildQueueEvent::GatewayEvent(Box::new(event));
+					dispatch_guild_event(&mut guild_processors, &mut processor_tasks, gid, event, &state).await;
+				} else if let Some(tx) = &dm_processor
+					&& dms::get_event_dm_channel(&event).is_some()
+				{
+					let _ = tx.send(event);
 				}
 			}
 		}
@@ -196,63 +525,438 @@ async fn main() -> anyhow::Result<()> {
 
 	shard.close(CloseFrame::NORMAL);
 	guild_processors.clear();
+	drop(dm_processor);
+	// Left to wind down on their own, as before
+	processor_tasks.detach_all();
 
 	drop(state);
 	let _ = asset_worker.await;
+	storage::close_writer_pool().await;
+	index::close().await;
+	if let Some(uploader) = log_uploader {
+		uploader.finish().await;
+	}
+	alerts::flush().await;
+	if SETTINGS.dry_run {
+		info!(
+			log_bytes = storage::dry_run_log_bytes(),
+			downloads = network::dry_run_downloads(),
+			"Dry run finished. This is what would have been written."
+		);
+	}
 	info!("👋 Goodbye!");
-	Ok(())
+
+	if token_rejected {
+		return Ok(ExitCode::from(EXIT_INVALID_TOKEN));
+	}
+	Ok(ExitCode::SUCCESS)
+}
+
+/// How long the guild processors of a rejected token get to stop, before they are aborted.
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Waits for the guild processors of a rejected token to stop, then lets the new session's processors start by releasing `handover`.
+async fn drain_processors(mut tasks: JoinSet<()>, handover: OwnedRwLockWriteGuard<()>) {
+	let drained = tokio::time::timeout(HANDOVER_TIMEOUT, async { while tasks.join_next().await.is_some() {} }).await;
+	if drained.is_err() {
+		warn!(
+			remaining = tasks.len(),
+			"Guild processors of the rejected token didn't stop in time. Aborting them."
+		);
+		tasks.shutdown().await;
+	}
+	drop(handover);
+}
+
+/// How long the `block` policy of `catchup.guild_queue_full` waits for room before dropping events.
+/// The gateway connection isn't served meanwhile, so this stays well below the heartbeat interval.
+const QUEUE_BLOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Sending side of a guild processor's queue.
+struct GuildQueue {
+	tx: mpsc::Sender<GuildQueueEvent>,
+	/// Shared with the processor, so the oldest events can be dropped to make room.
+	rx: Arc<tokio::sync::Mutex<GuildReceiver>>,
+	/// Gateway events dropped since the queue last had room, see `catchup.guild_queue_full`.
+	dropped: u64,
+	/// Shared with the processor's `State`, set through the control file.
+	paused: Arc<AtomicBool>,
+}
+
+impl GuildQueue {
+	/// Queues `event`, making room when full as `catchup.guild_queue_full` says.
+	/// Gives the event back if the processor has stopped.
+	async fn send(&mut self, guild_id: Id<GuildMarker>, event: GuildQueueEvent) -> Result<(), GuildQueueEvent> {
+		let event = match self.tx.try_send(event) {
+			Ok(()) => {
+				if self.dropped > 0 {
+					info!(%guild_id, dropped = self.dropped, "Guild queue has room again.");
+					self.dropped = 0;
+				}
+				return Ok(());
+			}
+			Err(TrySendError::Closed(event)) => return Err(event),
+			Err(TrySendError::Full(event)) => event,
+		};
+
+		// Catchups and other requests are never dropped, but mustn't hold up the gateway either
+		if !matches!(event, GuildQueueEvent::GatewayEvent(_)) {
+			let tx = self.tx.clone();
+			tokio::spawn(async move { tx.send(event).await });
+			return Ok(());
+		}
+
+		// Once waiting didn't help, events are dropped right away until the queue has room again
+		let event = if SETTINGS.catchup.guild_queue_full == QueueFullPolicy::Block && self.dropped == 0 {
+			debug!(%guild_id, "Guild queue is full. Waiting for room.");
+			match self.tx.send_timeout(event, QUEUE_BLOCK_TIMEOUT).await {
+				Ok(()) => return Ok(()),
+				Err(SendTimeoutError::Closed(event)) => return Err(event),
+				Err(SendTimeoutError::Timeout(event)) => event,
+			}
+		} else {
+			event
+		};
+
+		if self.dropped == 0 {
+			warn!(%guild_id, "Guild queue is full. Dropping its oldest gateway events until the processor catches up.");
+		}
+		self.dropped += 1;
+		// Fails only while the processor is taking an event, which makes room anyway
+		if let Ok(mut rx) = self.rx.try_lock() {
+			rx.drop_oldest();
+		}
+		match self.tx.try_send(event) {
+			Err(TrySendError::Closed(event)) => Err(event),
+			// If another sender got the room first, the newest event is the one dropped
+			Ok(()) | Err(TrySendError::Full(_)) => Ok(()),
+		}
+	}
+}
+
+/// Receiving side of a guild processor's queue.
+struct GuildReceiver {
+	rx: mpsc::Receiver<GuildQueueEvent>,
+	/// Events taken out along with a dropped one that mustn't be dropped themselves. Received before the rest.
+	kept: VecDeque<GuildQueueEvent>,
+}
+
+impl GuildReceiver {
+	async fn recv(&mut self) -> Option<GuildQueueEvent> {
+		match self.kept.pop_front() {
+			Some(event) => Some(event),
+			None => self.rx.recv().await,
+		}
+	}
+
+	/// Drops the oldest queued gateway event, keeping the requests queued before it in order.
+	fn drop_oldest(&mut self) {
+		while let Ok(event) = self.rx.try_recv() {
+			if matches!(event, GuildQueueEvent::GatewayEvent(_)) {
+				return;
+			}
+			self.kept.push_back(event);
+		}
+	}
 }
 
-fn dispatch_guild_event(
-	processors: &mut HashMap<u64, mpsc::UnboundedSender<GuildQueueEvent>>,
+async fn dispatch_guild_event(
+	processors: &mut HashMap<u64, GuildQueue>,
+	tasks: &mut JoinSet<()>,
 	guild_id: Id<GuildMarker>,
 	mut event: GuildQueueEvent,
 	state: &State,
 ) {
 	let gid = guild_id.get();
+	let mut paused = None;
 
-	if let Some(tx) = processors.get(&gid) {
-		match tx.send(event) {
+	if let Some(queue) = processors.get_mut(&gid) {
+		match queue.send(guild_id, event).await {
 			Ok(()) => return,
 			Err(e) => {
-				event = e.0;
-				processors.remove(&gid);
+				event = e;
+				// A paused guild stays paused when its processor is started again
+				paused = processors.remove(&gid).map(|queue| queue.paused);
 			}
 		}
 	}
+	let paused = paused.unwrap_or_default();
+
+	// A bounded channel only allocates as events come in, so an unlimited one costs nothing up front
+	let capacity = match SETTINGS.catchup.guild_queue_capacity {
+		0 => Semaphore::MAX_PERMITS,
+		capacity => capacity,
+	};
+	let (tx, rx) = mpsc::channel(capacity);
+	let rx = Arc::new(tokio::sync::Mutex::new(GuildReceiver { rx, kept: VecDeque::new() }));
+	let state_clone = state.for_guild(guild_id, paused.clone());
+	let processor_rx = rx.clone();
+	tasks.spawn(async move {
+		guild_processor_task(guild_id, processor_rx, state_clone).await;
+	});
+
+	if SETTINGS.catchup.archived_thread_rescan_interval_secs > 0 {
+		let period = Duration::from_secs(SETTINGS.catchup.archived_thread_rescan_interval_secs);
+		tokio::spawn(guild_ticker(tx.downgrade(), period, || GuildQueueEvent::RescanArchivedThreads));
+	}
+	if retention::max_age_days(guild_id) > 0 {
+		let period = Duration::from_secs(SETTINGS.retention.interval_secs.max(1));
+		tokio::spawn(guild_ticker(tx.downgrade(), period, || GuildQueueEvent::PruneExpired));
+	}
+	if MetadataKind::ALL.into_iter().any(MetadataKind::is_compacted) {
+		let period = Duration::from_secs(SETTINGS.storage.compaction_interval_secs);
+		tokio::spawn(guild_ticker(tx.downgrade(), period, || GuildQueueEvent::CompactMetadata));
+	}
+	if SETTINGS.metadata.stats_interval_secs > 0 {
+		let period = Duration::from_secs(SETTINGS.metadata.stats_interval_secs);
+		tokio::spawn(guild_ticker(tx.downgrade(), period, || GuildQueueEvent::SampleStats));
+	}
 
-	let (tx, rx) = mpsc::unbounded_channel();
-	let state_clone = state.clone();
-	tokio::spawn(async move {
-		guild_processor_task(guild_id, rx, state_clone).await;
+	let _ = tx.try_send(event);
+	processors.insert(
+		gid,
+		GuildQueue {
+			tx,
+			rx,
+			dropped: 0,
+			paused,
+		},
+	);
+}
+
+async fn handle_control_command(
+	command: ControlCommand,
+	processors: &mut HashMap<u64, GuildQueue>,
+	tasks: &mut JoinSet<()>,
+	state: &State,
+) {
+	match command {
+		ControlCommand::Recatchup { guild_id, channel_id } => {
+			// Starting a processor here would skip the guild's initial catchup
+			if !processors.contains_key(&guild_id.get()) {
+				warn!(%guild_id, "Not archiving this guild. Ignoring re-catchup.");
+				return;
+			}
+			dispatch_guild_event(processors, tasks, guild_id, GuildQueueEvent::RecatchupChannel(channel_id), state).await;
+		}
+		ControlCommand::FullCatchup { guild_id } => {
+			if !processors.contains_key(&guild_id.get()) {
+				warn!(%guild_id, "Not archiving this guild. Ignoring full catchup.");
+				return;
+			}
+			dispatch_guild_event(processors, tasks, guild_id, GuildQueueEvent::FullCatchup, state).await;
+		}
+		ControlCommand::Pause { guild_id } => {
+			let Some(queue) = processors.get(&guild_id.get()) else {
+				warn!(%guild_id, "Not archiving this guild. Ignoring pause.");
+				return;
+			};
+			if queue.paused.swap(true, Ordering::Relaxed) {
+				info!(%guild_id, "Guild is already paused.");
+			} else {
+				info!(%guild_id, policy = ?SETTINGS.catchup.while_paused, "⏸️ Paused archiving of guild.");
+			}
+		}
+		ControlCommand::Resume { guild_id } => {
+			let Some(queue) = processors.get(&guild_id.get()) else {
+				warn!(%guild_id, "Not archiving this guild. Ignoring resume.");
+				return;
+			};
+			if !queue.paused.swap(false, Ordering::Relaxed) {
+				info!(%guild_id, "Guild isn't paused. Ignoring resume.");
+				return;
+			}
+			dispatch_guild_event(processors, tasks, guild_id, GuildQueueEvent::Resume, state).await;
+		}
+		// Run by the control file watcher itself
+		ControlCommand::Flush => {}
+	}
+}
+
+/// Returns the first token from `start` on that Discord doesn't reject, with a client for it.
+/// A token that can't be verified for another reason (e.g. a network error) is used anyway.
+async fn first_accepted_token(tokens: &[String], start: usize) -> Option<(usize, Arc<HttpClient>)> {
+	for (index, token) in tokens.iter().enumerate().skip(start) {
+		let http = Arc::new(network::discord_client(token.clone()));
+		match http.current_user().await {
+			Ok(_) => return Some((index, http)),
+			Err(e) if is_unauthorized(&e) => warn!(token = index + 1, "Discord rejected bot token (401 Unauthorized)."),
+			Err(e) => {
+				warn!(error = ?e, "Failed to verify the bot token. Continuing anyway.");
+				return Some((index, http));
+			}
+		}
+	}
+	None
+}
+
+/// The `tokio-console` layer, when `tokio_console` is on.
+#[cfg(feature = "console")]
+fn console_layer() -> Option<console_subscriber::ConsoleLayer> {
+	SETTINGS
+		.tokio_console
+		.then(|| console_subscriber::ConsoleLayer::builder().with_default_env().spawn())
+}
+
+#[cfg(not(feature = "console"))]
+const fn console_layer() -> Option<tracing_subscriber::layer::Identity> {
+	None
+}
+
+/// A cache of channels, the only objects the archiver looks up, and of the `cache_resources` kinds.
+fn new_cache() -> Arc<InMemoryCache> {
+	let resource_types = SETTINGS.cache_resources.iter().fold(ResourceType::CHANNEL, |types, resource| {
+		types
+			| match resource {
+				CacheResource::Guilds => ResourceType::GUILD,
+				CacheResource::Members => ResourceType::MEMBER,
+				CacheResource::Roles => ResourceType::ROLE,
+				CacheResource::Emojis => ResourceType::EMOJI,
+				CacheResource::Stickers => ResourceType::STICKER,
+				CacheResource::Users => ResourceType::USER,
+				CacheResource::Presences => ResourceType::PRESENCE,
+				CacheResource::VoiceStates => ResourceType::VOICE_STATE,
+				CacheResource::Messages => ResourceType::MESSAGE,
+			}
 	});
+	Arc::new(DefaultInMemoryCache::builder().resource_types(resource_types).build())
+}
+
+/// Periodically sends `event()` to a guild processor, starting one `period` from now.
+/// Holds only a weak sender so it doesn't keep a stopped processor alive.
+async fn guild_ticker(tx: mpsc::WeakSender<GuildQueueEvent>, period: Duration, event: fn() -> GuildQueueEvent) {
+	let mut interval = tokio::time::interval(period);
+	interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+	// The first tick completes immediately, and the initial catchup already covers it
+	interval.tick().await;
 
-	let _ = tx.send(event);
-	processors.insert(gid, tx);
+	loop {
+		interval.tick().await;
+		let Some(tx) = tx.upgrade() else { break };
+		// A tick missed because the queue is full is made up for by the next one
+		if let Err(TrySendError::Closed(_)) = tx.try_send(event()) {
+			break;
+		}
+	}
 }
 
 #[instrument(skip_all, fields(guild_id = %guild_id))]
-async fn guild_processor_task(guild_id: Id<GuildMarker>, mut rx: mpsc::UnboundedReceiver<GuildQueueEvent>, state: State) {
+async fn guild_processor_task(guild_id: Id<GuildMarker>, rx: Arc<tokio::sync::Mutex<GuildReceiver>>, state: State) {
+	// Processors of a rejected token may still be writing this guild's logs
+	drop(state.handover.read().await);
 	info!("Started guild processor task.");
-	let mut meta_archiver = MetadataArchiver::new(guild_id, state.shutdown.clone()).await;
-	let mut chan_archivers: HashMap<Id<ChannelMarker>, Arc<ChannelArchiver>> = HashMap::new();
+	let mut meta_archiver = match MetadataArchiver::new(guild_id, state.shutdown.clone()).await {
+		Ok(archiver) => archiver,
+		Err(e) => {
+			error!(error = ?e, "FATAL: Failed to open metadata logs. Terminating task.");
+			alerts::send(format!(
+				"🚨 Archiving stopped for guild {guild_id}, metadata logs can't be opened: {e:#}"
+			));
+			return;
+		}
+	};
+	let mut chan_archivers = ArchiverCache::default();
+	// Events that came in while paused, see `catchup.while_paused`
+	let mut held = VecDeque::new();
+	let mut dropped: u64 = 0;
 
-	while let Some(event) = rx.recv().await {
+	loop {
+		// Held back reactions are written once their window is up, even while other events keep coming
+		let reactions_due = chan_archivers.flush_due_reactions();
+		// Role moves are written a moment after the first one, so a whole reorder lands in one batch
+		let role_moves_due = match meta_archiver.role_moves_due() {
+			Some(due) if due <= Instant::now() => {
+				if let Err(e) = meta_archiver.flush_role_moves() {
+					warn!(error = ?e, "Failed to write role moves");
+				}
+				None
+			}
+			due => due,
+		};
+		let event = if !state.paused.load(Ordering::Relaxed)
+			&& let Some(event) = held.pop_front()
+		{
+			Some(event)
+		} else if let Some(deadline) = reactions_due.into_iter().chain(role_moves_due).min() {
+			match tokio::time::timeout_at(deadline, async { rx.lock().await.recv().await }).await {
+				Ok(event) => event,
+				// Written at the top of the loop
+				Err(_) => continue,
+			}
+		} else {
+			rx.lock().await.recv().await
+		};
+		let Some(event) = event else { break };
 		if state.shutdown.load(Ordering::SeqCst) {
 			break;
 		}
+		if state.paused.load(Ordering::Relaxed) {
+			match (SETTINGS.catchup.while_paused, event) {
+				// A resume is stale if the guild was paused again since
+				(_, event) if event.is_upkeep() || matches!(event, GuildQueueEvent::Resume) => {}
+				(PausePolicy::Buffer, event) => held.push_back(event),
+				(PausePolicy::Drop, _) => dropped += 1,
+			}
+			continue;
+		}
+		// Handled again on resume if a pause cuts it short, unless its events are dropped
+		let rerun = if SETTINGS.catchup.while_paused == PausePolicy::Buffer {
+			event.catchup_again()
+		} else {
+			None
+		};
 		let is_catchup = matches!(event, GuildQueueEvent::InitialCatchup);
+		let force = matches!(event, GuildQueueEvent::FullCatchup);
 
 		let res = match event {
-			GuildQueueEvent::InitialCatchup => run_full_guild_catchup(guild_id, state.clone(), &mut meta_archiver).await,
+			GuildQueueEvent::InitialCatchup | GuildQueueEvent::FullCatchup => {
+				// Gateway events keep queueing up meanwhile, and are handled once the catchup is done
+				let _slot = match state.catchup_slots.clone() {
+					Some(slots) => {
+						if slots.available_permits() == 0 {
+							info!("Waiting for other guilds to finish their catchup.");
+						}
+						slots.acquire_owned().await.ok()
+					}
+					None => None,
+				};
+				let res = run_full_guild_catchup(guild_id, state.clone(), &mut meta_archiver, force).await;
+				chan_archivers.forget_newest().await;
+				res
+			}
+			GuildQueueEvent::RescanArchivedThreads => {
+				let res = rescan_archived_threads(guild_id, state.clone()).await;
+				chan_archivers.forget_newest().await;
+				res
+			}
+			GuildQueueEvent::PruneExpired => retention::prune_guild(guild_id, &mut chan_archivers).await,
+			GuildQueueEvent::CompactMetadata => meta_archiver.compact().await,
+			GuildQueueEvent::SampleStats => meta_archiver.sample_stats(&state, guild_id).await,
+			GuildQueueEvent::Resume => {
+				info!(held = held.len(), dropped, "▶️ Resumed archiving of guild.");
+				dropped = 0;
+				Ok(())
+			}
+			GuildQueueEvent::RecatchupChannel(channel_id) => {
+				let res = recatchup_channel(guild_id, channel_id, state.clone()).await;
+				chan_archivers.forget_newest().await;
+				res
+			}
 			GuildQueueEvent::GatewayEvent(evt) => {
 				dispatch::handle_event(*evt, guild_id, &state, &mut meta_archiver, &mut chan_archivers).await
 			}
 		};
 
+		if state.paused.load(Ordering::Relaxed)
+			&& let Some(event) = rerun
+		{
+			held.push_back(event);
+		}
+
 		if let Err(e) = res {
 			if is_catchup {
 				error!(error = ?e, "FATAL error during initial catchup. Terminating task.");
+				alerts::send(format!("🚨 Archiving stopped for guild {guild_id}, initial catchup failed: {e:#}"));
 				break;
 			}
 
@@ -263,10 +967,15 @@ async fn guild_processor_task(guild_id: Id<GuildMarker>, mut rx: mpsc::Unbounded
 				}
 				ProcessorError::Fatal(err) => {
 					error!(error = ?err, "FATAL error. Terminating task.");
+					alerts::send(format!("🚨 Archiving stopped for guild {guild_id}: {err:#}"));
 					break;
 				}
 			}
 		}
 	}
+	if let Err(e) = meta_archiver.flush_role_moves() {
+		warn!(error = ?e, "Failed to write role moves");
+	}
+	chan_archivers.flush_reactions();
 	info!("Guild processor task terminated");
 }
@@ -0,0 +1,398 @@
+use crate::messages;
+use crate::metadata::{ChannelEvent, GuildEvent, MemberEvent};
+use crate::settings::{LogFormat, SETTINGS};
+use crate::storage::{self, LogEvent};
+use crate::utils::{create_path, get_current_time_millis, int_to_str, snowflake_to_millis};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+use tokio::time::Instant;
+use tracing::{debug, info, instrument, warn};
+use twilight_model::id::Id;
+use twilight_model::id::marker::GuildMarker;
+
+const MANIFEST: &str = "manifest.json";
+const USAGE: &str = "usage.json";
+
+/// An at-a-glance summary of what is archived for a guild, written to `{guild_id}/manifest.json`.
+#[derive(Debug, Serialize)]
+struct Manifest {
+	guild_id: u64,
+	guild_name: Option<String>,
+	/// Unix timestamp in milliseconds.
+	generated_at: u64,
+	/// When a log of the guild was last written to, as a unix timestamp in milliseconds.
+	last_updated: Option<u64>,
+	/// Channels and threads the guild currently has.
+	channels: usize,
+	/// Members currently in the guild.
+	members: usize,
+	messages: usize,
+	message_logs: Vec<ChannelSummary>,
+	attachments: FileUsage,
+	assets: FileUsage,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct ChannelSummary {
+	channel_id: u64,
+	name: Option<String>,
+	/// Messages ever archived, including deleted ones.
+	messages: usize,
+	/// Creation time of the oldest archived message, as a unix timestamp in milliseconds.
+	first_message: Option<u64>,
+	/// Creation time of the newest archived message, as a unix timestamp in milliseconds.
+	last_message: Option<u64>,
+	log_bytes: u64,
+}
+
+#[derive(Debug, Default, Serialize)]
+struct FileUsage {
+	files: u64,
+	bytes: u64,
+}
+
+/// The parts of a message log record needed to count messages.
+#[derive(Deserialize)]
+struct MessageRecord {
+	#[serde(rename = "t")]
+	kind: String,
+	#[serde(rename = "i", default)]
+	id: Option<u64>,
+}
+
+/// Regenerates the manifest of a guild. Failures are only logged, as the manifest can always be rebuilt later.
+pub async fn write_guild(guild_id: Id<GuildMarker>) {
+	if SETTINGS.storage.manifest_interval_secs == 0 || SETTINGS.dry_run {
+		return;
+	}
+	match tokio::task::spawn_blocking(move || write_manifest(guild_id.get())).await {
+		Ok(Ok(())) => debug!(%guild_id, "Wrote guild manifest."),
+		Ok(Err(e)) => warn!(%guild_id, error = ?e, "Failed to write guild manifest"),
+		Err(e) => warn!(%guild_id, error = ?e, "Guild manifest task failed"),
+	}
+}
+
+/// A long-running task that periodically regenerates the manifest of every archived guild.
+#[instrument(skip_all)]
+pub async fn manifest_writer(shutdown: Arc<AtomicBool>) {
+	let period = Duration::from_secs(SETTINGS.storage.manifest_interval_secs.max(1));
+	// Catchup writes them on startup already
+	let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+	interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+	loop {
+		interval.tick().await;
+		if shutdown.load(Ordering::Relaxed) {
+			break;
+		}
+
+		for guild_id in archived_guilds() {
+			if shutdown.load(Ordering::Relaxed) {
+				return;
+			}
+			if let Some(guild_id) = Id::new_checked(guild_id) {
+				write_guild(guild_id).await;
+			}
+		}
+	}
+}
+
+/// Guilds with a folder in the data path.
+fn archived_guilds() -> Vec<u64> {
+	let Ok(entries) = fs::read_dir(&SETTINGS.data_path) else {
+		return Vec::new();
+	};
+	entries
+		.filter_map(Result::ok)
+		.filter(|entry| entry.path().is_dir())
+		.filter_map(|entry| entry.file_name().to_str()?.parse().ok())
+		.collect()
+}
+
+/// On-disk bytes of a guild's archive, by what they hold. Written to `{guild_id}/usage.json`.
+#[derive(Debug, Default, Serialize)]
+pub struct GuildUsage {
+	guild_id: u64,
+	/// Unix timestamp in milliseconds.
+	measured_at: u64,
+	/// Message logs, gap logs and catchup state.
+	messages: u64,
+	attachments: u64,
+	metadata: u64,
+	assets: u64,
+	/// Everything else in the guild folder, like the message index and the manifest.
+	other: u64,
+}
+
+impl GuildUsage {
+	pub const fn total(&self) -> u64 {
+		self.messages + self.attachments + self.metadata + self.assets + self.other
+	}
+}
+
+/// Walks the guild's folder and adds up its files. Heavy on I/O for big archives.
+pub fn guild_disk_usage(guild_id: u64) -> Result<GuildUsage> {
+	let guild_dir = create_path(&[&int_to_str!(guild_id, u64)]);
+	let mut usage = GuildUsage {
+		guild_id,
+		measured_at: get_current_time_millis()?,
+		..GuildUsage::default()
+	};
+	for entry in fs::read_dir(&guild_dir).with_context(|| format!("Failed to read {}", guild_dir.display()))? {
+		let entry = entry?;
+		if !entry.file_type()?.is_dir() {
+			usage.other += entry.metadata()?.len();
+			continue;
+		}
+		let mut files = FileUsage::default();
+		match entry.file_name().to_str() {
+			Some("messages") => {
+				// Attachments are in a folder per channel, next to the logs
+				for dir in messages::message_log_dirs(&int_to_str!(guild_id, u64)) {
+					for entry in fs::read_dir(dir)? {
+						let entry = entry?;
+						if !entry.file_type()?.is_dir() {
+							usage.messages += entry.metadata()?.len();
+						} else if !messages::is_log_folder(&entry.path()) {
+							files.add_dir(&entry.path())?;
+						}
+					}
+				}
+				usage.attachments += files.bytes;
+			}
+			Some("metadata") => {
+				files.add_dir(&entry.path())?;
+				usage.metadata += files.bytes;
+			}
+			Some("assets") => {
+				files.add_dir(&entry.path())?;
+				usage.assets += files.bytes;
+			}
+			_ => {
+				files.add_dir(&entry.path())?;
+				usage.other += files.bytes;
+			}
+		}
+	}
+	Ok(usage)
+}
+
+/// A long-running task that periodically measures and logs the disk usage of every archived guild.
+#[instrument(skip_all)]
+pub async fn disk_usage_reporter(shutdown: Arc<AtomicBool>) {
+	let period = Duration::from_secs(SETTINGS.storage.disk_usage_interval_secs.max(1));
+	// The last measurement is still in usage.json, so restarts don't walk everything again right away
+	let mut interval = tokio::time::interval_at(Instant::now() + period, period);
+	interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+	loop {
+		interval.tick().await;
+		if shutdown.load(Ordering::Relaxed) {
+			break;
+		}
+
+		let mut total = 0;
+		for guild_id in archived_guilds() {
+			if shutdown.load(Ordering::Relaxed) {
+				return;
+			}
+			match tokio::task::spawn_blocking(move || write_usage(guild_id)).await {
+				Ok(Ok(usage)) => {
+					total += usage.total();
+					info!(
+						guild_id,
+						total = usage.total(),
+						messages = usage.messages,
+						attachments = usage.attachments,
+						metadata = usage.metadata,
+						assets = usage.assets,
+						other = usage.other,
+						"Guild disk usage in bytes."
+					);
+				}
+				Ok(Err(e)) => warn!(guild_id, error = ?e, "Failed to measure guild disk usage"),
+				Err(e) => warn!(guild_id, error = ?e, "Guild disk usage task failed"),
+			}
+		}
+		info!(total, "Disk usage of all guilds in bytes.");
+	}
+}
+
+/// Measures a guild's disk usage and keeps it in its `usage.json`.
+fn write_usage(guild_id: u64) -> Result<GuildUsage> {
+	let usage = guild_disk_usage(guild_id)?;
+	if !SETTINGS.dry_run {
+		let path = create_path(&[&int_to_str!(guild_id, u64), USAGE]);
+		let tmp = path.with_extension("json.tmp");
+		fs::write(&tmp, sonic_rs::to_vec_pretty(&usage)?).with_context(|| format!("Failed to write {}", tmp.display()))?;
+		fs::rename(&tmp, &path)?;
+	}
+	Ok(usage)
+}
+
+fn write_manifest(guild_id: u64) -> Result<()> {
+	let guild = int_to_str!(guild_id, u64);
+	// Logs written before a switch of `storage.log_format` hold the older records, so the current format's log is read last
+	let current = SETTINGS.storage.log_format;
+	let metadata_logs = |name: &str| {
+		[LogFormat::Ndjson, LogFormat::Msgpack]
+			.into_iter()
+			.filter(|&format| format != current)
+			.chain([current])
+			.map(|format| create_path(&[&guild, "metadata", &format!("{name}.{}", format.extension())]))
+			.collect::<Vec<_>>()
+	};
+
+	let mut guild_name = None;
+	for path in metadata_logs("guild") {
+		storage::for_each_record(&path, |event: LogEvent<GuildEvent>| guild_name = Some(event.payload.name))?;
+	}
+
+	let mut channel_names = HashMap::new();
+	for path in metadata_logs("channels") {
+		storage::for_each_record(&path, |event: LogEvent<ChannelEvent>| {
+			let channel = event.payload;
+			if channel.deleted {
+				channel_names.remove(&channel.channel_id);
+			} else {
+				channel_names.insert(channel.channel_id, channel.name);
+			}
+		})?;
+	}
+
+	let mut members = HashSet::new();
+	for path in metadata_logs("members") {
+		storage::for_each_record(&path, |event: LogEvent<MemberEvent>| {
+			if event.payload.left_at.is_some() {
+				members.remove(&event.payload.user_id);
+			} else {
+				members.insert(event.payload.user_id);
+			}
+		})?;
+	}
+
+	let mut last_updated = newest_modification(&create_path(&[&guild, "metadata"]))?;
+	let mut message_logs: BTreeMap<u64, ChannelSummary> = BTreeMap::new();
+	let mut attachments = FileUsage::default();
+	let entries = messages::message_log_dirs(&guild)
+		.into_iter()
+		.filter_map(|dir| fs::read_dir(dir).ok())
+		.flatten();
+	for entry in entries {
+		let entry = entry?;
+		let path = entry.path();
+		if entry.file_type()?.is_dir() {
+			if !messages::is_log_folder(&path) {
+				attachments.add_dir(&path)?;
+			}
+			continue;
+		}
+		// Only channel logs, named after the channel. Gap logs and the like are skipped
+		let Some(channel_id) = path.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse::<u64>().ok()) else {
+			continue;
+		};
+		if !storage::is_log_file(&path) {
+			continue;
+		}
+
+		let metadata = entry.metadata()?;
+		last_updated = last_updated.max(modified_millis(&metadata));
+		let summary = message_logs.entry(channel_id).or_default();
+		summary.log_bytes += metadata.len();
+		summary.add_log(&path)?;
+	}
+
+	let message_logs: Vec<ChannelSummary> = message_logs
+		.into_iter()
+		.map(|(channel_id, summary)| ChannelSummary {
+			channel_id,
+			name: channel_names.get(&channel_id).cloned(),
+			..summary
+		})
+		.collect();
+
+	let mut assets = FileUsage::default();
+	assets.add_dir(&create_path(&[&guild, "assets"]))?;
+
+	let manifest = Manifest {
+		guild_id,
+		guild_name,
+		generated_at: get_current_time_millis()?,
+		last_updated,
+		channels: channel_names.len(),
+		members: members.len(),
+		messages: message_logs.iter().map(|c| c.messages).sum(),
+		message_logs,
+		attachments,
+		assets,
+	};
+
+	// Written next to it and renamed over, so readers never see a half written manifest
+	let path = create_path(&[&guild, MANIFEST]);
+	let tmp = path.with_extension("json.tmp");
+	fs::write(&tmp, sonic_rs::to_vec_pretty(&manifest)?).with_context(|| format!("Failed to write {}", tmp.display()))?;
+	fs::rename(&tmp, &path)?;
+	Ok(())
+}
+
+impl ChannelSummary {
+	fn add_log(&mut self, path: &Path) -> Result<()> {
+		// Newest-first catchup can store a message twice
+		let mut ids = HashSet::new();
+		storage::for_each_record(path, |record: MessageRecord| {
+			if record.kind == "c"
+				&& let Some(id) = record.id
+			{
+				ids.insert(id);
+			}
+		})?;
+
+		self.messages += ids.len();
+		let (min, max) = (ids.iter().min().copied(), ids.iter().max().copied());
+		self.first_message = self.first_message.into_iter().chain(min.map(snowflake_to_millis)).min();
+		self.last_message = self.last_message.into_iter().chain(max.map(snowflake_to_millis)).max();
+		Ok(())
+	}
+}
+
+impl FileUsage {
+	/// Adds every file under `dir`. Packed files count as the packs holding them.
+	fn add_dir(&mut self, dir: &Path) -> Result<()> {
+		let entries = match fs::read_dir(dir) {
+			Ok(entries) => entries,
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+			Err(e) => return Err(e.into()),
+		};
+		for entry in entries {
+			let entry = entry?;
+			let file_type = entry.file_type()?;
+			if file_type.is_dir() {
+				self.add_dir(&entry.path())?;
+			} else if file_type.is_file() && !entry.file_name().to_string_lossy().ends_with(".part") {
+				self.files += 1;
+				self.bytes += entry.metadata()?.len();
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Most recent modification time of the files directly in `dir`, in unix milliseconds.
+fn newest_modification(dir: &Path) -> Result<Option<u64>> {
+	let mut newest = None;
+	for entry in fs::read_dir(dir).into_iter().flatten() {
+		newest = newest.max(modified_millis(&entry?.metadata()?));
+	}
+	Ok(newest)
+}
+
+fn modified_millis(metadata: &fs::Metadata) -> Option<u64> {
+	let since_epoch = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+	u64::try_from(since_epoch.as_millis()).ok()
+}
@@ -1,32 +1,129 @@
 use crate::State;
-use crate::storage::LogStore;
-use crate::utils::{concat_str, create_path, int_to_str, remove_extension};
-use anyhow::Result;
+use crate::index::{self, IndexedMessage};
+use crate::network::{AssetKind, DownloadRequest};
+use crate::settings::{ChannelKind, SETTINGS};
+use crate::storage::{LogMark, LogStore};
+use crate::utils::{concat_str, create_path, int_to_str, remove_extension, sanitize_filename, snowflake_to_millis};
+use anyhow::{Result, bail};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
-use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
-use tracing::instrument;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, PoisonError};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use tracing::{debug, instrument};
+use twilight_cache_inmemory::InMemoryCache;
+use twilight_model::channel::message::sticker::StickerFormatType;
 use twilight_model::channel::message::{Embed, EmojiReactionType};
-use twilight_model::channel::{Attachment, Message};
+use twilight_model::channel::{Attachment, ChannelType, Message};
+use twilight_model::id::Id;
+use twilight_model::id::marker::ChannelMarker;
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub enum ReactionData {
 	#[serde(rename = "c")]
-	Custom(u64),
+	Custom(CustomReaction),
 	#[serde(rename = "u")]
 	Unicode(String),
 }
 
+/// A custom emoji reaction. Keeps the emoji's name, so it stays readable after the emoji is deleted.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(from = "CustomReactionRecord")]
+pub struct CustomReaction {
+	#[serde(rename = "i")]
+	pub id: u64,
+	/// `None` when Discord didn't send it, which it doesn't always do for emojis that were deleted.
+	#[serde(rename = "n", skip_serializing_if = "Option::is_none")]
+	pub name: Option<String>,
+	#[serde(rename = "a", skip_serializing_if = "std::ops::Not::not")]
+	pub animated: bool,
+}
+
+/// Logs from before names were kept only have the emoji ID.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum CustomReactionRecord {
+	Id(u64),
+	Full {
+		#[serde(rename = "i")]
+		id: u64,
+		#[serde(rename = "n", default)]
+		name: Option<String>,
+		#[serde(rename = "a", default)]
+		animated: bool,
+	},
+}
+
+impl From<CustomReactionRecord> for CustomReaction {
+	fn from(record: CustomReactionRecord) -> Self {
+		match record {
+			CustomReactionRecord::Id(id) => Self {
+				id,
+				name: None,
+				animated: false,
+			},
+			CustomReactionRecord::Full { id, name, animated } => Self { id, name, animated },
+		}
+	}
+}
+
 impl From<&EmojiReactionType> for ReactionData {
 	fn from(emoji: &EmojiReactionType) -> Self {
 		match emoji {
-			EmojiReactionType::Custom { id, .. } => Self::Custom(id.get()),
+			EmojiReactionType::Custom { animated, id, name } => Self::Custom(CustomReaction {
+				id: id.get(),
+				name: name.clone(),
+				animated: *animated,
+			}),
 			EmojiReactionType::Unicode { name } => Self::Unicode(name.clone()),
 		}
 	}
 }
 
+/// A sticker sent with a message. Keeps what's needed to render it, as it may be from another guild with no `StickerEvent` for it.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(from = "StickerItemRecord")]
+pub struct StickerItem {
+	#[serde(rename = "i")]
+	pub id: u64,
+	#[serde(rename = "n", skip_serializing_if = "String::is_empty")]
+	pub name: String,
+	/// `None` in records from before it was kept.
+	#[serde(rename = "f", skip_serializing_if = "Option::is_none")]
+	pub format: Option<StickerFormatType>,
+}
+
+/// Logs from before names and formats were kept only have the sticker ID.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StickerItemRecord {
+	Id(u64),
+	Full {
+		#[serde(rename = "i")]
+		id: u64,
+		#[serde(rename = "n", default)]
+		name: String,
+		#[serde(rename = "f", default)]
+		format: Option<StickerFormatType>,
+	},
+}
+
+impl From<StickerItemRecord> for StickerItem {
+	fn from(record: StickerItemRecord) -> Self {
+		match record {
+			StickerItemRecord::Id(id) => Self {
+				id,
+				name: String::new(),
+				format: None,
+			},
+			StickerItemRecord::Full { id, name, format } => Self { id, name, format },
+		}
+	}
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(tag = "t")]
 pub enum MessageEvent {
@@ -40,6 +137,14 @@ pub enum MessageEvent {
 		#[serde(flatten)]
 		message: StoredMessage,
 	},
+	/// Discord resolved link embeds of a message. Only carries the embeds, as these updates can come without the message content.
+	#[serde(rename = "ue")]
+	EmbedUpdate {
+		#[serde(rename = "i")]
+		id: u64,
+		#[serde(rename = "e")]
+		embeds: Vec<Embed>,
+	},
 	#[serde(rename = "d")]
 	Delete {
 		#[serde(rename = "i")]
@@ -80,6 +185,51 @@ pub enum MessageEvent {
 		#[serde(rename = "e")]
 		emoji: ReactionData,
 	},
+	#[serde(rename = "ty")]
+	TypingStart {
+		#[serde(rename = "u")]
+		user_id: u64,
+	},
+	/// A vote on a poll answer, or its removal, telling who voted for what.
+	#[serde(rename = "pv")]
+	PollVote {
+		#[serde(rename = "i")]
+		message_id: u64,
+		#[serde(rename = "u")]
+		user_id: u64,
+		#[serde(rename = "a")]
+		answer_id: u8,
+		#[serde(rename = "ad")]
+		added: bool,
+	},
+	/// Everyone who had reacted with `emoji` at the time of catchup.
+	#[serde(rename = "ru")]
+	ReactionUsers {
+		#[serde(rename = "i")]
+		message_id: u64,
+		#[serde(rename = "e")]
+		emoji: ReactionData,
+		#[serde(rename = "us")]
+		user_ids: Vec<u64>,
+	},
+	/// Written after messages that newest-first catchup stored out of order, or after a catchup batch whose newest messages were skipped.
+	/// Holds the newest message catchup got to, so it can still be found by reading the log backwards.
+	#[serde(rename = "cm")]
+	CatchupMark {
+		#[serde(rename = "i")]
+		newest_id: u64,
+	},
+}
+
+impl MessageEvent {
+	/// When the event happened on Discord, if it says: a message's creation time for creates, and its edit time for edits.
+	const fn event_time(&self) -> Option<u64> {
+		match self {
+			Self::Create { message } => Some(snowflake_to_millis(message.id)),
+			Self::Update { message } => message.edited_at,
+			_ => None,
+		}
+	}
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -96,14 +246,36 @@ pub struct StoredMessage {
 	pub author_id: u64,
 	#[serde(skip_serializing_if = "Vec::is_empty", default, rename = "e")]
 	pub embeds: Vec<Embed>,
+	/// Attachment IDs, in the order Discord shows them.
 	#[serde(skip_serializing_if = "Vec::is_empty", default, rename = "at")]
 	pub attachments: Vec<u64>,
+	/// Names the attachments are downloaded under, in the same order as `attachments`. Empty in records written before it existed.
+	#[serde(skip_serializing_if = "Vec::is_empty", default, rename = "af")]
+	pub attachment_files: Vec<String>,
 	#[serde(skip_serializing_if = "Vec::is_empty", default, rename = "s")]
-	pub stickers: Vec<u64>,
+	pub stickers: Vec<StickerItem>,
 	#[serde(skip_serializing_if = "Vec::is_empty", default, rename = "r")]
 	pub reactions: Vec<(ReactionData, u64)>,
 	#[serde(skip_serializing_if = "Option::is_none", rename = "ri")]
 	pub reference_id: Option<u64>,
+	/// Channel of the referenced message. Only set when it is in another channel (cross-channel replies, forwards).
+	#[serde(skip_serializing_if = "Option::is_none", default, rename = "rc")]
+	pub reference_channel_id: Option<u64>,
+	/// Guild of the referenced message. Only set along with `reference_channel_id`.
+	#[serde(skip_serializing_if = "Option::is_none", default, rename = "rg")]
+	pub reference_guild_id: Option<u64>,
+	/// Raw [message flags](https://discord.com/developers/docs/resources/message#message-object-message-flags) bitfield. Not set if empty.
+	#[serde(skip_serializing_if = "Option::is_none", default, rename = "f")]
+	pub flags: Option<u64>,
+	/// Author's username when the message was seen. Only set with `metadata.snapshot_message_authors`.
+	#[serde(skip_serializing_if = "Option::is_none", default, rename = "an")]
+	pub author_name: Option<String>,
+	/// Author's server nickname, or global display name if they had none. Only set along with `author_name`.
+	#[serde(skip_serializing_if = "Option::is_none", default, rename = "ad")]
+	pub author_display_name: Option<String>,
+	/// Author's legacy discriminator. Only set along with `author_name`, and only for users (mostly bots) that still have one.
+	#[serde(skip_serializing_if = "Option::is_none", default, rename = "adi")]
+	pub author_discriminator: Option<u16>,
 }
 
 impl From<Message> for StoredMessage {
@@ -113,20 +285,42 @@ impl From<Message> for StoredMessage {
 		let edited_at = msg.edited_timestamp.map(|t| (t.as_micros() / 1000).cast_unsigned());
 		let author_id = msg.author.id.get();
 		let reference_id = msg.reference.as_ref().and_then(|r| r.message_id.map(twilight_model::id::Id::get));
+		// Most replies are in the same channel, the bare message ID is enough for those
+		let (reference_channel_id, reference_guild_id) = match &msg.reference {
+			Some(r) if r.channel_id.is_some_and(|c| c != msg.channel_id) => (
+				r.channel_id.map(twilight_model::id::Id::get),
+				r.guild_id.map(twilight_model::id::Id::get),
+			),
+			_ => (None, None),
+		};
+		let flags = msg.flags.map(|f| f.bits()).filter(|&bits| bits != 0);
+		let (author_name, author_display_name, author_discriminator) = if SETTINGS.metadata.snapshot_message_authors {
+			let nick = msg.member.as_mut().and_then(|m| m.nick.take());
+			(
+				Some(std::mem::take(&mut msg.author.name)),
+				nick.or_else(|| msg.author.global_name.take()),
+				Some(msg.author.discriminator).filter(|&d| d != 0),
+			)
+		} else {
+			(None, None, None)
+		};
 		let content = std::mem::take(&mut msg.content);
 		let embeds = std::mem::take(&mut msg.embeds);
-		let attachments = msg.attachments.into_iter().map(|a| a.id.get()).collect();
-		let stickers = msg.sticker_items.into_iter().map(|s| s.id.get()).collect();
-		let reactions = std::mem::take(&mut msg.reactions)
+		let attachments = msg.attachments.iter().map(|a| a.id.get()).collect();
+		let attachment_files = msg.attachments.iter().map(attachment_filename).collect();
+		let stickers = msg
+			.sticker_items
 			.into_iter()
-			.map(|r| {
-				let reaction_data = match &r.emoji {
-					EmojiReactionType::Custom { id, .. } => ReactionData::Custom(id.get()),
-					EmojiReactionType::Unicode { name } => ReactionData::Unicode(name.clone()),
-				};
-				(reaction_data, r.count)
+			.map(|s| StickerItem {
+				id: s.id.get(),
+				name: s.name,
+				format: Some(s.format_type),
 			})
 			.collect();
+		let reactions = std::mem::take(&mut msg.reactions)
+			.into_iter()
+			.map(|r| (ReactionData::from(&r.emoji), r.count))
+			.collect();
 
 		Self {
 			id,
@@ -136,37 +330,189 @@ impl From<Message> for StoredMessage {
 			author_id,
 			embeds,
 			attachments,
+			attachment_files,
 			stickers,
 			reactions,
 			reference_id,
+			reference_channel_id,
+			reference_guild_id,
+			flags,
+			author_name,
+			author_display_name,
+			author_discriminator,
+		}
+	}
+}
+
+/// Longest attachment name kept in file names, in bytes.
+/// With the ID prefix and the `.part` suffix of unfinished downloads, it stays within the 255 byte limit of most filesystems.
+const MAX_ATTACHMENT_NAME_BYTES: usize = 200;
+
+/// Name an attachment is downloaded under. Prefixed with its ID, so attachments sharing a name don't overwrite each other.
+fn attachment_filename(att: &Attachment) -> String {
+	format!(
+		"{}_{}",
+		int_to_str!(att.id.get(), u64),
+		sanitize_filename(&att.filename, MAX_ATTACHMENT_NAME_BYTES)
+	)
+}
+
+/// Whether `msg` is left out of the archive by `metadata.skip_bot_messages` or `metadata.skip_author_ids`.
+fn is_skipped_author(msg: &Message) -> bool {
+	(SETTINGS.metadata.skip_bot_messages && msg.author.bot) || SETTINGS.metadata.skip_author_ids.contains(&msg.author.id.get())
+}
+
+/// Checks the folder names of `channels.folders`, so a bad one stops the bot on startup instead of scattering logs.
+pub fn check_folders() -> Result<()> {
+	for (kind, folder) in &SETTINGS.channels.folders {
+		let mut components = Path::new(folder).components();
+		let single = matches!((components.next(), components.next()), (Some(Component::Normal(_)), None));
+		if !single || folder.starts_with('.') || folder.parse::<u64>().is_ok() {
+			bail!("Invalid folder for {kind:?} in `channels.folders`: {folder:?}. It must be a single folder name that isn't a number");
 		}
 	}
+	Ok(())
+}
+
+/// Which of the `channels.folders` kinds a channel is. Threads are told apart by the kind of their parent.
+fn channel_kind(kind: ChannelType, parent_kind: Option<ChannelType>) -> Option<ChannelKind> {
+	match kind {
+		ChannelType::GuildText => Some(ChannelKind::Text),
+		ChannelType::GuildVoice => Some(ChannelKind::Voice),
+		ChannelType::GuildAnnouncement => Some(ChannelKind::Announcement),
+		ChannelType::AnnouncementThread => Some(ChannelKind::AnnouncementThread),
+		ChannelType::PublicThread | ChannelType::PrivateThread => match parent_kind {
+			Some(ChannelType::GuildForum) => Some(ChannelKind::ForumPost),
+			Some(ChannelType::GuildMedia) => Some(ChannelKind::MediaPost),
+			_ => Some(ChannelKind::Thread),
+		},
+		_ => None,
+	}
+}
+
+/// Subfolder of `messages` a channel's log goes in, going by `channels.folders`. `None` for `messages` itself.
+/// The parent of a thread is looked up in the cache.
+pub fn folder_for(cache: &InMemoryCache, kind: ChannelType, parent_id: Option<Id<ChannelMarker>>) -> Option<&'static str> {
+	if SETTINGS.channels.folders.is_empty() {
+		return None;
+	}
+	let parent_kind = parent_id
+		.filter(|_| kind.is_thread())
+		.and_then(|id| cache.channel(id).map(|parent| parent.kind));
+	let kind = channel_kind(kind, parent_kind)?;
+	SETTINGS.channels.folders.get(&kind).map(String::as_str)
+}
+
+/// Like `folder_for`, for a channel in the cache. Channels missing from it go in `messages` itself.
+pub fn channel_folder(cache: &InMemoryCache, channel_id: Id<ChannelMarker>) -> Option<&'static str> {
+	if SETTINGS.channels.folders.is_empty() {
+		return None;
+	}
+	let channel = cache.channel(channel_id)?;
+	let (kind, parent_id) = (channel.kind, channel.parent_id);
+	// Released before looking up the parent, as holding two cache entries at once can deadlock
+	drop(channel);
+	folder_for(cache, kind, parent_id)
+}
+
+/// Whether a folder under `messages` holds routed channel logs, see `channels.folders`.
+/// Attachment folders are named after their channel, and routed folders can't be numbers.
+pub fn is_log_folder(path: &Path) -> bool {
+	path.file_name()
+		.and_then(|name| name.to_str())
+		.is_some_and(|name| !name.starts_with('.') && name.parse::<u64>().is_err())
+}
+
+/// Folders holding a guild's channel logs: `messages`, and the folders in it that channels were routed to.
+/// Found on disk rather than from the settings, so logs routed by an earlier configuration are included.
+pub fn message_log_dirs(guild: &str) -> Vec<PathBuf> {
+	let messages_dir = create_path(&[guild, "messages"]);
+	let mut dirs: Vec<PathBuf> = fs::read_dir(&messages_dir)
+		.into_iter()
+		.flatten()
+		.filter_map(|entry| entry.ok().map(|e| e.path()))
+		.filter(|path| path.is_dir() && is_log_folder(path))
+		.collect();
+	dirs.sort_unstable();
+	dirs.insert(0, messages_dir);
+	dirs
+}
+
+/// The newest message ID of a log up to a point, see `ChannelArchiver::get_last_message_id`.
+#[derive(Serialize, Deserialize)]
+struct NewestCheckpoint {
+	#[serde(rename = "i")]
+	newest: u64,
+	#[serde(rename = "m")]
+	mark: LogMark,
 }
 
 /// Manages the archiving logic and state for a single channel.
 pub struct ChannelArchiver {
 	log_store: LogStore,
+	/// Guild of the channel, or `None` for direct messages.
+	guild_id: Option<u64>,
 	channel_id: u64,
+	/// ID of the newest message with a Create event in the log. Loaded from the log on first use.
+	newest_created: Mutex<Option<u64>>,
+	reactions: std::sync::Mutex<ReactionBatch>,
+}
+
+/// Reaction events held back to be written together, see `storage.reaction_batch_window_ms`.
+#[derive(Debug, Default)]
+struct ReactionBatch {
+	events: Vec<MessageEvent>,
+	/// When the oldest held back event is due to be written.
+	due: Option<Instant>,
 }
 
 impl ChannelArchiver {
-	pub fn new(guild_id: u64, channel_id: u64, shutdown: &Arc<AtomicBool>) -> Result<Self> {
+	/// Archiver of a guild channel, whose log is in `folder` under `messages` if it has one. See `channel_folder`.
+	pub fn new(guild_id: u64, channel_id: u64, folder: Option<&str>, shutdown: &Arc<AtomicBool>) -> Result<Self> {
 		let guild_id_str = int_to_str!(guild_id, u64);
 		let channel_id_str = int_to_str!(channel_id, u64);
 
-		let path = create_path(&[&guild_id_str, "messages", &concat_str!(27, &channel_id_str, ".ndjson")]);
-		let log_store = LogStore::new(path, shutdown)?;
+		let file_name = concat_str!(27, &channel_id_str, ".ndjson");
+		let path = match folder {
+			Some(folder) => create_path(&[&guild_id_str, "messages", folder, &file_name]),
+			None => create_path(&[&guild_id_str, "messages", &file_name]),
+		};
+		Self::open(path, Some(guild_id), channel_id, shutdown)
+	}
 
-		Ok(Self { log_store, channel_id })
+	/// Archiver of a direct message channel, stored under `dms` instead of a guild.
+	pub fn new_dm(channel_id: u64, shutdown: &Arc<AtomicBool>) -> Result<Self> {
+		let channel_id_str = int_to_str!(channel_id, u64);
+		let path = create_path(&["dms", &concat_str!(27, &channel_id_str, ".ndjson")]);
+		Self::open(path, None, channel_id, shutdown)
+	}
+
+	fn open(path: PathBuf, guild_id: Option<u64>, channel_id: u64, shutdown: &Arc<AtomicBool>) -> Result<Self> {
+		Ok(Self {
+			log_store: LogStore::new(path, shutdown)?,
+			guild_id,
+			channel_id,
+			newest_created: Mutex::new(None),
+			reactions: std::sync::Mutex::default(),
+		})
 	}
 
 	#[instrument(skip(self, msg, state), fields(channel_id = %self.channel_id))]
 	pub async fn push_message(&self, msg: Message, state: &State) -> Result<()> {
+		if is_skipped_author(&msg) {
+			return Ok(());
+		}
+		// Messages arriving live may already have been fetched by catchup, and vice versa
+		if !self.claim_newest(msg.id.get()).await? {
+			debug!(message_id = %msg.id, "Skipping already stored message.");
+			return Ok(());
+		}
+
 		let attachments = msg.attachments.clone();
-		let event = MessageEvent::Create {
-			message: StoredMessage::from(msg),
-		};
-		self.log_store.append(&event)?;
+		let message = StoredMessage::from(msg);
+		self.index(std::slice::from_ref(&message));
+		let event = MessageEvent::Create { message };
+		self.append(&event)?;
 
 		if !attachments.is_empty() {
 			let folder_path = remove_extension(self.log_store.path());
@@ -176,20 +522,65 @@ impl ChannelArchiver {
 	}
 
 	#[instrument(skip(self, messages, state), fields(channel_id = %self.channel_id, count = messages.len()))]
-	pub async fn push_messages_bulk(&self, messages: Vec<Message>, state: &State) -> Result<()> {
+	pub async fn push_messages_bulk(&self, mut messages: Vec<Message>, state: &State) -> Result<()> {
+		let newest_id = {
+			let mut newest = self.newest_created.lock().await;
+			let stored = self.load_newest(&mut newest).await?;
+			messages.retain(|m| m.id.get() > stored);
+			let last = messages.iter().map(|m| m.id.get()).max();
+			if last.is_some() {
+				*newest = last;
+			}
+			last
+		};
+		let Some(newest_id) = newest_id else {
+			return Ok(());
+		};
+
+		messages.retain(|m| !is_skipped_author(m));
+		// Otherwise the next catchup would start before the skipped messages, and fetch them all again
+		let trailer = messages
+			.iter()
+			.all(|m| m.id.get() != newest_id)
+			.then_some(MessageEvent::CatchupMark { newest_id });
+		self.append_creates(messages, trailer, state)
+	}
+
+	/// Stores messages fetched by newest-first catchup, which can be older than ones already stored.
+	/// They are followed by a `CatchupMark`, so the newest stored message is still the first one found reading backwards.
+	#[instrument(skip(self, messages, state), fields(channel_id = %self.channel_id, count = messages.len()))]
+	pub async fn push_messages_backfill(&self, mut messages: Vec<Message>, state: &State) -> Result<()> {
 		if messages.is_empty() {
 			return Ok(());
 		}
 
+		let newest_id = {
+			let mut newest = self.newest_created.lock().await;
+			let stored = self.load_newest(&mut newest).await?;
+			let newest_id = messages.iter().map(|m| m.id.get()).fold(stored, u64::max);
+			*newest = Some(newest_id);
+			newest_id
+		};
+
+		messages.retain(|m| !is_skipped_author(m));
+		self.append_creates(messages, Some(MessageEvent::CatchupMark { newest_id }), state)
+	}
+
+	fn append_creates(&self, messages: Vec<Message>, trailer: Option<MessageEvent>, state: &State) -> Result<()> {
 		let all_attachments: Vec<Attachment> = messages.iter().flat_map(|m| m.attachments.clone()).collect();
 
+		let messages: Vec<StoredMessage> = messages.into_iter().map(StoredMessage::from).collect();
+		self.index(&messages);
 		let events: Vec<MessageEvent> = messages
 			.into_iter()
-			.map(|msg| MessageEvent::Create {
-				message: StoredMessage::from(msg),
-			})
+			.map(|message| MessageEvent::Create { message })
+			.chain(trailer)
 			.collect();
-		self.log_store.append_bulk(events)?;
+		self.flush_reactions()?;
+		self.log_store.append_bulk_at(events.into_iter().map(|e| {
+			let time = e.event_time();
+			(e, time)
+		}))?;
 
 		if !all_attachments.is_empty() {
 			let folder_path = remove_extension(self.log_store.path());
@@ -200,16 +591,31 @@ impl ChannelArchiver {
 
 	#[instrument(skip(self, msg), fields(channel_id = %self.channel_id, message_id = %msg.id.get()))]
 	pub async fn update_message(&self, msg: Message) -> Result<()> {
-		let event = MessageEvent::Update {
-			message: StoredMessage::from(msg),
+		if is_skipped_author(&msg) {
+			return Ok(());
+		}
+		// Not an edit, and nothing to keep besides the embeds. Storing it whole would make the message look blanked
+		let event = if msg.content.is_empty() && msg.edited_timestamp.is_none() {
+			MessageEvent::EmbedUpdate {
+				id: msg.id.get(),
+				embeds: msg.embeds,
+			}
+		} else {
+			MessageEvent::Update {
+				message: StoredMessage::from(msg),
+			}
 		};
-		self.log_store.append(&event)
+		self.append(&event)
 	}
 
 	#[instrument(skip(self), fields(channel_id = %self.channel_id, message_id))]
 	pub async fn delete_message(&self, message_id: u64) -> Result<()> {
 		let event = MessageEvent::Delete { id: message_id };
-		self.log_store.append(&event)
+		self.append(&event)?;
+		if let Some(guild_id) = self.guild_id {
+			index::mark_deleted(guild_id, vec![message_id]);
+		}
+		Ok(())
 	}
 
 	#[instrument(skip(self), fields(channel_id = %self.channel_id, count = ids_to_delete.len()))]
@@ -220,7 +626,10 @@ impl ChannelArchiver {
 		let event = MessageEvent::BulkDelete {
 			ids: ids_to_delete.to_vec(),
 		};
-		self.log_store.append(&event)?;
+		self.append(&event)?;
+		if let Some(guild_id) = self.guild_id {
+			index::mark_deleted(guild_id, ids_to_delete.to_vec());
+		}
 		Ok(ids_to_delete.len())
 	}
 
@@ -231,7 +640,7 @@ impl ChannelArchiver {
 			user_id,
 			emoji: ReactionData::from(emoji),
 		};
-		self.log_store.append(&event)
+		self.append_reaction(event)
 	}
 
 	#[instrument(skip(self, emoji), fields(channel_id = %self.channel_id, message_id, user_id))]
@@ -241,13 +650,13 @@ impl ChannelArchiver {
 			user_id,
 			emoji: ReactionData::from(emoji),
 		};
-		self.log_store.append(&event)
+		self.append_reaction(event)
 	}
 
 	#[instrument(skip(self), fields(channel_id = %self.channel_id, message_id))]
 	pub async fn remove_all_reactions(&self, message_id: u64) -> Result<()> {
 		let event = MessageEvent::ReactionRemoveAll { message_id };
-		self.log_store.append(&event)
+		self.append(&event)
 	}
 
 	#[instrument(skip(self, emoji), fields(channel_id = %self.channel_id, message_id))]
@@ -256,9 +665,38 @@ impl ChannelArchiver {
 			message_id,
 			emoji: ReactionData::from(emoji),
 		};
-		self.log_store.append(&event)
+		self.append(&event)
 	}
 
+	#[instrument(skip(self), fields(channel_id = %self.channel_id, user_id))]
+	pub async fn typing_start(&self, user_id: u64) -> Result<()> {
+		let event = MessageEvent::TypingStart { user_id };
+		self.append(&event)
+	}
+
+	#[instrument(skip(self), fields(channel_id = %self.channel_id, message_id, user_id))]
+	pub async fn poll_vote(&self, message_id: u64, user_id: u64, answer_id: u8, added: bool) -> Result<()> {
+		let event = MessageEvent::PollVote {
+			message_id,
+			user_id,
+			answer_id,
+			added,
+		};
+		self.append(&event)
+	}
+
+	#[instrument(skip(self, emoji, user_ids), fields(channel_id = %self.channel_id, message_id, count = user_ids.len()))]
+	pub async fn push_reaction_users(&self, message_id: u64, emoji: &EmojiReactionType, user_ids: Vec<u64>) -> Result<()> {
+		let event = MessageEvent::ReactionUsers {
+			message_id,
+			emoji: ReactionData::from(emoji),
+			user_ids,
+		};
+		self.append(&event)
+	}
+
+	/// Highest message ID among the last `resume_scan_window` records of the log, or in the last message record if there are none in it.
+	/// What the previous call found is kept in `{channel_id}.newest.json` next to the log, so only records appended since are scanned.
 	#[instrument(skip(self), fields(channel_id = %self.channel_id))]
 	pub async fn get_last_message_id(&self) -> Result<Option<u64>> {
 		// Minimal struct for efficient deserialization
@@ -266,26 +704,209 @@ impl ChannelArchiver {
 		struct ScanFrame {
 			#[serde(rename = "t")]
 			tag: String,
-			#[serde(rename = "i")]
-			id: u64,
+			#[serde(rename = "i", default)]
+			id: Option<u64>,
 		}
 
-		let last_id = self
+		let checkpoint_path = self.log_store.path().with_extension("newest.json");
+		// Only a shortcut, so a missing or unreadable one just means scanning the whole log
+		let checkpoint: Option<NewestCheckpoint> = tokio::fs::read(&checkpoint_path)
+			.await
+			.ok()
+			.and_then(|bytes| sonic_rs::from_slice(&bytes).ok());
+		let (known, since) = checkpoint.map_or((0, None), |c| (c.newest, Some(c.mark)));
+
+		let window = SETTINGS.catchup.resume_scan_window;
+		let newest = Arc::new(AtomicU64::new(0));
+		let newest_in_scan = newest.clone();
+		let scanned = AtomicUsize::new(0);
+		let scan = self
 			.log_store
-			.scan_last(|frame: ScanFrame| if frame.tag == "c" { Some(frame.id) } else { None })
+			.scan_last_since(since, move |frame: ScanFrame| {
+				let scanned = scanned.fetch_add(1, Ordering::Relaxed) + 1;
+				if let Some(id) = frame.id
+					&& (frame.tag == "c" || frame.tag == "cm")
+				{
+					newest_in_scan.fetch_max(id, Ordering::Relaxed);
+				}
+				(scanned >= window && newest_in_scan.load(Ordering::Relaxed) > 0).then_some(())
+			})
 			.await?;
 
-		Ok(last_id)
+		let mut newest = newest.load(Ordering::Relaxed);
+		if scan.resumed {
+			newest = newest.max(known);
+		}
+		if let Some(mark) = scan.end
+			&& newest > 0
+			&& !SETTINGS.dry_run
+		{
+			let checkpoint = NewestCheckpoint { newest, mark };
+			if let Err(e) = tokio::fs::write(&checkpoint_path, sonic_rs::to_vec(&checkpoint)?).await {
+				debug!(error = %e, "Failed to save the newest message checkpoint");
+			}
+		}
+		Ok((newest > 0).then_some(newest))
+	}
+
+	/// ID of the newest stored message, or 0 if there are none.
+	pub async fn newest_stored(&self) -> Result<u64> {
+		let mut newest = self.newest_created.lock().await;
+		self.load_newest(&mut newest).await
+	}
+
+	/// Records `message_id` as the newest stored message, unless a message at least as new is already stored.
+	/// Returns whether the message is new.
+	async fn claim_newest(&self, message_id: u64) -> Result<bool> {
+		let mut newest = self.newest_created.lock().await;
+		if message_id <= self.load_newest(&mut newest).await? {
+			return Ok(false);
+		}
+		*newest = Some(message_id);
+		Ok(true)
+	}
+
+	async fn load_newest(&self, newest: &mut Option<u64>) -> Result<u64> {
+		if let Some(id) = *newest {
+			return Ok(id);
+		}
+		let id = self.get_last_message_id().await?.unwrap_or(0);
+		*newest = Some(id);
+		Ok(id)
+	}
+
+	/// Drops the cached newest message ID, so it is re-read from the log on next use.
+	pub async fn forget_newest(&self) {
+		*self.newest_created.lock().await = None;
+	}
+
+	/// Adds newly stored messages to the guild's SQLite index, if enabled.
+	fn index(&self, messages: &[StoredMessage]) {
+		if let Some(guild_id) = self.guild_id
+			&& SETTINGS.storage.sqlite_index
+		{
+			index::insert(guild_id, messages.iter().map(|m| IndexedMessage::new(self.channel_id, m)).collect());
+		}
 	}
 
 	fn queue_attachments(state: &State, attachments: &[Attachment], folder: &Path) {
-		for att in attachments {
-			let filename = format!("{}_{}", int_to_str!(att.id.get(), u64), att.filename);
-			state.submit_download(att.url.clone(), folder.to_path_buf(), filename);
+		if !state.download_assets {
+			return;
 		}
+		let reqs = attachments
+			.iter()
+			.map(|att| DownloadRequest {
+				url: att.url.clone(),
+				folder: folder.to_path_buf(),
+				filename: attachment_filename(att),
+				kind: AssetKind::Attachment,
+			})
+			.collect();
+		state.submit_downloads(reqs);
+	}
+
+	/// Writes an event after any held back reactions, so the log stays in order.
+	fn append(&self, event: &MessageEvent) -> Result<()> {
+		self.flush_reactions()?;
+		Ok(self.log_store.append_at(event, event.event_time())?)
+	}
+
+	/// Holds a reaction event back for `storage.reaction_batch_window_ms`, or writes it right away if that is 0.
+	/// DMs don't get reactions in bulk, and nothing would write held back ones on time, so they are never held back.
+	fn append_reaction(&self, event: MessageEvent) -> Result<()> {
+		if SETTINGS.storage.reaction_batch_window_ms == 0 || self.guild_id.is_none() {
+			return Ok(self.log_store.append(&event)?);
+		}
+		let mut batch = self.reactions.lock().unwrap_or_else(PoisonError::into_inner);
+		let due = *batch
+			.due
+			.get_or_insert_with(|| Instant::now() + Duration::from_millis(SETTINGS.storage.reaction_batch_window_ms));
+		batch.events.push(event);
+		if due <= Instant::now() {
+			batch.due = None;
+			self.log_store.append_bulk(std::mem::take(&mut batch.events))?;
+		}
+		Ok(())
+	}
+
+	/// When the held back reactions are due to be written, if there are any.
+	pub fn reactions_due(&self) -> Option<Instant> {
+		self.reactions.lock().unwrap_or_else(PoisonError::into_inner).due
+	}
+
+	/// Writes the held back reactions now.
+	pub fn flush_reactions(&self) -> Result<()> {
+		let mut batch = self.reactions.lock().unwrap_or_else(PoisonError::into_inner);
+		if batch.events.is_empty() {
+			return Ok(());
+		}
+		batch.due = None;
+		Ok(self.log_store.append_bulk(std::mem::take(&mut batch.events))?)
 	}
 
 	pub async fn flush(&self) -> Result<()> {
+		self.flush_reactions()?;
 		self.log_store.flush().await
 	}
+
+	pub fn path(&self) -> &Path {
+		self.log_store.path()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mock_discord;
+	use serde_json::{Value, json};
+
+	fn attachment(id: u64, filename: &str) -> Value {
+		let url = format!("https://cdn.discordapp.com/attachments/2001/{id}/{filename}");
+		json!({
+			"id": id.to_string(),
+			"filename": filename,
+			"content_type": null,
+			"size": 1,
+			"url": url,
+			"proxy_url": url
+		})
+	}
+
+	/// A message with `attachments`, as their ID and name, in the order Discord lists them.
+	fn message_with(attachments: &[(u64, &str)]) -> Message {
+		let mut message = mock_discord::message(2001, 5001);
+		message["attachments"] = attachments.iter().map(|&(id, name)| attachment(id, name)).collect();
+		serde_json::from_value(message).unwrap()
+	}
+
+	#[test]
+	fn attachments_sharing_a_name_get_their_own_files() {
+		let stored = StoredMessage::from(message_with(&[(11, "image.png"), (12, "image.png")]));
+		assert_eq!(stored.attachment_files, ["11_image.png", "12_image.png"]);
+	}
+
+	#[test]
+	fn attachment_files_are_in_the_order_of_attachments() {
+		let attachments = [(13, "c.txt"), (11, "a.txt"), (12, "b.txt")];
+		let stored = StoredMessage::from(message_with(&attachments));
+		assert_eq!(stored.attachments, [13, 11, 12]);
+		assert_eq!(stored.attachment_files, ["13_c.txt", "11_a.txt", "12_b.txt"]);
+		// The same message, as seen again by a later catchup, names its files the same
+		assert_eq!(
+			StoredMessage::from(message_with(&attachments)).attachment_files,
+			stored.attachment_files
+		);
+	}
+
+	#[test]
+	fn attachment_files_are_sanitized() {
+		let stored = StoredMessage::from(message_with(&[(11, "../../evil.sh"), (12, "a\0b.png"), (13, "CON")]));
+		assert_eq!(stored.attachment_files, ["11_.._.._evil.sh", "12_a_b.png", "13__CON"]);
+
+		let long = format!("{}.png", "x".repeat(300));
+		let stored = StoredMessage::from(message_with(&[(14, long.as_str())]));
+		let file = &stored.attachment_files[0];
+		assert_eq!(file.len(), "14_".len() + MAX_ATTACHMENT_NAME_BYTES);
+		assert!(file.starts_with("14_xxx") && file.ends_with("x.png"));
+	}
 }
@@ -1,22 +1,32 @@
 use crate::State;
-use crate::network::append_cdn;
-use crate::settings::SETTINGS;
-use crate::storage::LogStore;
+use crate::network::{AssetKind, append_cdn, append_cdn_sized, retry_http};
+use crate::presence::PresenceArchiver;
+use crate::settings::{MetadataKind, SETTINGS};
+use crate::storage::{LogEvent, LogStore};
 use crate::utils::{concat_str, create_path, get_current_time_millis, int_to_str};
-use anyhow::Result;
+use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use std::collections::{HashMap, HashSet};
-use std::hash::Hash;
+use std::hash::{DefaultHasher, Hash, Hasher};
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use tracing::{info, instrument};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, instrument, warn};
+use twilight_gateway::Event;
+use twilight_model::application::command::{Command, CommandOption, CommandType};
 use twilight_model::channel::Channel as TwilightChannel;
+use twilight_model::channel::forum::ForumTag;
 use twilight_model::channel::message::sticker::{Sticker as TwilightSticker, StickerFormatType};
+use twilight_model::gateway::event::DispatchEvent;
+use twilight_model::gateway::payload::incoming::InviteCreate;
+use twilight_model::gateway::presence::Presence;
+use twilight_model::guild::invite::Invite as TwilightInvite;
+use twilight_model::guild::{Ban, GuildIntegration, GuildIntegrationType};
 use twilight_model::guild::{Emoji as TwilightEmoji, Guild as TwilightGuild, Member as TwilightMember, PartialGuild, Role as TwilightRole};
 use twilight_model::id::Id;
-use twilight_model::id::marker::{GuildMarker, UserMarker};
-use twilight_model::util::ImageHash;
+use twilight_model::id::marker::GuildMarker;
 
 /// Event that can be replayed from a log.
 pub trait Replayable {
@@ -24,6 +34,29 @@ pub trait Replayable {
 	fn is_delete(&self) -> bool;
 }
 
+/// Current state after applying `events` in log order. A delete event drops the entity it refers to.
+pub fn replay<T: Replayable>(events: impl IntoIterator<Item = T>) -> HashMap<u64, T> {
+	let mut state = HashMap::new();
+	for data in events {
+		if data.is_delete() {
+			state.remove(&data.id());
+		} else {
+			state.insert(data.id(), data);
+		}
+	}
+	state
+}
+
+impl<T: Replayable> Replayable for LogEvent<T> {
+	fn id(&self) -> u64 {
+		self.payload.id()
+	}
+
+	fn is_delete(&self) -> bool {
+		self.payload.is_delete()
+	}
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct GuildEvent {
 	#[serde(rename = "n")]
@@ -36,6 +69,8 @@ pub struct GuildEvent {
 	pub description: Option<String>,
 	#[serde(rename = "s", skip_serializing_if = "Option::is_none")]
 	pub splash: Option<String>,
+	#[serde(rename = "vu", skip_serializing_if = "Option::is_none")]
+	pub vanity_url_code: Option<String>,
 }
 
 impl Replayable for GuildEvent {
@@ -55,6 +90,7 @@ impl From<&PartialGuild> for GuildEvent {
 			banner: g.banner.map(|h| h.to_string()),
 			description: g.description.clone(),
 			splash: g.splash.map(|h| h.to_string()),
+			vanity_url_code: g.vanity_url_code.clone(),
 		}
 	}
 }
@@ -67,6 +103,7 @@ impl From<&TwilightGuild> for GuildEvent {
 			banner: g.banner.map(|h| h.to_string()),
 			description: g.description.clone(),
 			splash: g.splash.map(|h| h.to_string()),
+			vanity_url_code: g.vanity_url_code.clone(),
 		}
 	}
 }
@@ -81,6 +118,12 @@ pub struct MemberEvent {
 	pub global_name: Option<String>,
 	#[serde(rename = "a", skip_serializing_if = "Option::is_none")]
 	pub avatar: Option<String>,
+	#[serde(rename = "ad", skip_serializing_if = "Option::is_none")]
+	pub avatar_decoration: Option<String>,
+	#[serde(rename = "ads", skip_serializing_if = "Option::is_none")]
+	pub avatar_decoration_sku: Option<u64>,
+	#[serde(rename = "bn", skip_serializing_if = "Option::is_none")]
+	pub banner: Option<String>,
 	#[serde(rename = "j", skip_serializing_if = "Option::is_none")]
 	pub joined_at: Option<u64>,
 	#[serde(rename = "l", skip_serializing_if = "Option::is_none")]
@@ -104,11 +147,15 @@ impl Replayable for MemberEvent {
 
 impl MemberEvent {
 	pub fn from_add_or_update(m: &TwilightMember) -> Self {
+		let decoration = m.avatar_decoration_data.as_ref().or(m.user.avatar_decoration_data.as_ref());
 		Self {
 			user_id: m.user.id.get(),
 			username: m.user.name.clone(),
 			global_name: m.user.global_name.clone(),
 			avatar: m.avatar.map(|h| h.to_string()).or_else(|| m.user.avatar.map(|h| h.to_string())),
+			avatar_decoration: decoration.map(|d| d.asset.to_string()),
+			avatar_decoration_sku: decoration.map(|d| d.sku_id.get()),
+			banner: m.banner.or(m.user.banner).map(|h| h.to_string()),
 			joined_at: m.joined_at.map(|t| t.as_micros().cast_unsigned() / 1000),
 			left_at: None,
 			roles: m.roles.iter().map(|r| r.get()).collect(),
@@ -116,6 +163,23 @@ impl MemberEvent {
 			bot: m.user.bot,
 		}
 	}
+
+	pub fn from_remove(user_id: u64, left_at: u64) -> Self {
+		Self {
+			user_id,
+			username: "UNKNOWN".into(),
+			global_name: None,
+			avatar: None,
+			avatar_decoration: None,
+			avatar_decoration_sku: None,
+			banner: None,
+			joined_at: None,
+			left_at: Some(left_at),
+			roles: vec![],
+			nickname: None,
+			bot: false,
+		}
+	}
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
@@ -204,10 +268,58 @@ pub struct ChannelEvent {
 	pub parent_id: Option<u64>,
 	#[serde(rename = "ns", skip_serializing_if = "<&bool as std::ops::Not>::not", default)]
 	pub nsfw: bool,
+	/// Tags posts can use. Forum and media channels only.
+	#[serde(rename = "tg", skip_serializing_if = "Vec::is_empty", default)]
+	pub available_tags: Vec<ForumTagData>,
+	/// IDs of the parent's tags applied to this post. Forum and media posts (threads) only.
+	#[serde(rename = "at", skip_serializing_if = "Vec::is_empty", default)]
+	pub applied_tags: Vec<u64>,
+	/// Slowmode, in seconds.
+	#[serde(rename = "rl", skip_serializing_if = "Option::is_none")]
+	pub rate_limit_per_user: Option<u16>,
+	/// Slowmode of new threads, in seconds.
+	#[serde(rename = "trl", skip_serializing_if = "Option::is_none")]
+	pub default_thread_rate_limit_per_user: Option<u16>,
+	/// Minutes of inactivity before new threads are archived.
+	#[serde(rename = "aa", skip_serializing_if = "Option::is_none")]
+	pub default_auto_archive_duration: Option<u16>,
+	/// Voice channels only.
+	#[serde(rename = "ul", skip_serializing_if = "Option::is_none")]
+	pub user_limit: Option<u32>,
+	/// Voice channels only.
+	#[serde(rename = "br", skip_serializing_if = "Option::is_none")]
+	pub bitrate: Option<u32>,
 	#[serde(rename = "d", skip_serializing_if = "<&bool as std::ops::Not>::not", default)]
 	pub deleted: bool,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct ForumTagData {
+	#[serde(rename = "i")]
+	pub id: u64,
+	#[serde(rename = "n")]
+	pub name: String,
+	/// Only moderators can apply the tag.
+	#[serde(rename = "m", skip_serializing_if = "<&bool as std::ops::Not>::not", default)]
+	pub moderated: bool,
+	#[serde(rename = "ei", skip_serializing_if = "Option::is_none", default)]
+	pub emoji_id: Option<u64>,
+	#[serde(rename = "en", skip_serializing_if = "Option::is_none", default)]
+	pub emoji_name: Option<String>,
+}
+
+impl From<&ForumTag> for ForumTagData {
+	fn from(tag: &ForumTag) -> Self {
+		Self {
+			id: tag.id.get(),
+			name: tag.name.clone(),
+			moderated: tag.moderated,
+			emoji_id: tag.emoji_id.map(Id::get),
+			emoji_name: tag.emoji_name.clone(),
+		}
+	}
+}
+
 impl Replayable for ChannelEvent {
 	fn id(&self) -> u64 {
 		self.channel_id
@@ -227,6 +339,13 @@ impl ChannelEvent {
 			position: c.position.unwrap_or_default(),
 			parent_id: c.parent_id.map(Id::get),
 			nsfw: c.nsfw.unwrap_or(false),
+			available_tags: c.available_tags.iter().flatten().map(ForumTagData::from).collect(),
+			applied_tags: c.applied_tags.iter().flatten().copied().map(Id::get).collect(),
+			rate_limit_per_user: c.rate_limit_per_user,
+			default_thread_rate_limit_per_user: c.default_thread_rate_limit_per_user,
+			default_auto_archive_duration: c.default_auto_archive_duration.map(u16::from),
+			user_limit: c.user_limit,
+			bitrate: c.bitrate,
 			deleted: false,
 		}
 	}
@@ -240,6 +359,13 @@ impl ChannelEvent {
 			position: c.position.unwrap_or_default(),
 			parent_id: c.parent_id.map(Id::get),
 			nsfw: c.nsfw.unwrap_or(false),
+			available_tags: c.available_tags.iter().flatten().map(ForumTagData::from).collect(),
+			applied_tags: c.applied_tags.iter().flatten().copied().map(Id::get).collect(),
+			rate_limit_per_user: c.rate_limit_per_user,
+			default_thread_rate_limit_per_user: c.default_thread_rate_limit_per_user,
+			default_auto_archive_duration: c.default_auto_archive_duration.map(u16::from),
+			user_limit: c.user_limit,
+			bitrate: c.bitrate,
 			deleted: false,
 		}
 	}
@@ -253,6 +379,13 @@ impl ChannelEvent {
 			position: 0,
 			parent_id: None,
 			nsfw: false,
+			available_tags: Vec::new(),
+			applied_tags: Vec::new(),
+			rate_limit_per_user: None,
+			default_thread_rate_limit_per_user: None,
+			default_auto_archive_duration: None,
+			user_limit: None,
+			bitrate: None,
 			deleted: true,
 		}
 	}
@@ -266,6 +399,18 @@ pub struct EmojiEvent {
 	pub name: String,
 	#[serde(rename = "a")]
 	pub animated: bool,
+	/// Roles allowed to use the emoji. Empty if everyone can.
+	#[serde(rename = "r", skip_serializing_if = "Vec::is_empty", default)]
+	pub roles: Vec<u64>,
+	/// Managed by an integration, like Twitch subscriber emojis.
+	#[serde(rename = "m", skip_serializing_if = "<&bool as std::ops::Not>::not", default)]
+	pub managed: bool,
+	/// Can't be used, as the guild lost the boost level that gave it the emoji slot.
+	#[serde(rename = "ua", skip_serializing_if = "<&bool as std::ops::Not>::not", default)]
+	pub unavailable: bool,
+	/// Who uploaded the emoji. Discord only tells bots with the Manage Expressions permission, and only through the API.
+	#[serde(rename = "u", skip_serializing_if = "Option::is_none", default)]
+	pub creator_id: Option<u64>,
 	#[serde(rename = "d", skip_serializing_if = "<&bool as std::ops::Not>::not", default)]
 	pub deleted: bool,
 }
@@ -285,6 +430,10 @@ impl EmojiEvent {
 			id: e.id.get(),
 			name: e.name.clone(),
 			animated: e.animated,
+			roles: e.roles.iter().map(|r| r.get()).collect(),
+			managed: e.managed,
+			unavailable: !e.available,
+			creator_id: e.user.as_ref().map(|u| u.id.get()),
 			deleted: false,
 		}
 	}
@@ -293,6 +442,10 @@ impl EmojiEvent {
 			id,
 			name: String::new(),
 			animated: false,
+			roles: Vec::new(),
+			managed: false,
+			unavailable: false,
+			creator_id: None,
 			deleted: true,
 		}
 	}
@@ -306,6 +459,17 @@ pub struct StickerEvent {
 	pub name: String,
 	#[serde(rename = "f")]
 	pub format_type: StickerFormatType,
+	#[serde(rename = "ds", skip_serializing_if = "Option::is_none", default)]
+	pub description: Option<String>,
+	/// Autocomplete and suggestion tags, comma separated. For guild stickers, the name of the related emoji.
+	#[serde(rename = "tg", skip_serializing_if = "String::is_empty", default)]
+	pub tags: String,
+	/// Can't be used, as the guild lost the boost level that gave it the sticker slot.
+	#[serde(rename = "ua", skip_serializing_if = "<&bool as std::ops::Not>::not", default)]
+	pub unavailable: bool,
+	/// Who uploaded the sticker. Like for emojis, only known with the Manage Expressions permission.
+	#[serde(rename = "u", skip_serializing_if = "Option::is_none", default)]
+	pub creator_id: Option<u64>,
 	#[serde(rename = "d", skip_serializing_if = "<&bool as std::ops::Not>::not", default)]
 	pub deleted: bool,
 }
@@ -325,6 +489,10 @@ impl StickerEvent {
 			id: s.id.get(),
 			name: s.name.clone(),
 			format_type: s.format_type,
+			description: s.description.clone(),
+			tags: s.tags.clone(),
+			unavailable: !s.available,
+			creator_id: s.user.as_ref().map(|u| u.id.get()),
 			deleted: false,
 		}
 	}
@@ -333,6 +501,284 @@ impl StickerEvent {
 			id,
 			name: String::new(),
 			format_type: StickerFormatType::Png,
+			description: None,
+			tags: String::new(),
+			unavailable: false,
+			creator_id: None,
+			deleted: true,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct InviteEvent {
+	#[serde(rename = "c")]
+	pub code: String,
+	#[serde(rename = "ch", skip_serializing_if = "Option::is_none")]
+	pub channel_id: Option<u64>,
+	#[serde(rename = "iv", skip_serializing_if = "Option::is_none")]
+	pub inviter_id: Option<u64>,
+	#[serde(rename = "u", skip_serializing_if = "Option::is_none")]
+	pub uses: Option<u64>,
+	#[serde(rename = "mu", skip_serializing_if = "Option::is_none")]
+	pub max_uses: Option<u64>,
+	#[serde(rename = "ma", skip_serializing_if = "Option::is_none")]
+	pub max_age: Option<u64>,
+	#[serde(rename = "tm", skip_serializing_if = "<&bool as std::ops::Not>::not", default)]
+	pub temporary: bool,
+	#[serde(rename = "ca", skip_serializing_if = "Option::is_none")]
+	pub created_at: Option<u64>,
+	#[serde(rename = "d", skip_serializing_if = "<&bool as std::ops::Not>::not", default)]
+	pub deleted: bool,
+}
+
+impl Replayable for InviteEvent {
+	fn id(&self) -> u64 {
+		invite_key(&self.code)
+	}
+	fn is_delete(&self) -> bool {
+		self.deleted
+	}
+}
+
+/// Invites are identified by their code rather than a snowflake, so they are keyed by its hash.
+/// The key only has to be stable within a single run, as state is rebuilt from the log on startup.
+fn invite_key(code: &str) -> u64 {
+	let mut hasher = DefaultHasher::new();
+	code.hash(&mut hasher);
+	hasher.finish()
+}
+
+/// API maximum.
+const BAN_PAGE_SIZE: u16 = 1000;
+
+/// Lists every ban of the guild, a page at a time.
+async fn fetch_bans(state: &State, guild_id: Id<GuildMarker>) -> Result<Vec<Ban>> {
+	let mut bans: Vec<Ban> = Vec::new();
+	loop {
+		let after = bans.last().map(|b| b.user.id);
+		let page = retry_http(|| async {
+			let http = state.http();
+			let mut request = http.bans(guild_id).limit(BAN_PAGE_SIZE);
+			if let Some(after) = after {
+				request = request.after(after);
+			}
+			Ok(request.await?.models().await?)
+		})
+		.await?;
+
+		let done = page.len() < usize::from(BAN_PAGE_SIZE);
+		bans.extend(page);
+		if done {
+			return Ok(bans);
+		}
+	}
+}
+
+impl InviteEvent {
+	pub fn from_api(i: &TwilightInvite) -> Self {
+		Self {
+			code: i.code.clone(),
+			channel_id: i.channel.as_ref().map(|c| c.id.get()),
+			inviter_id: i.inviter.as_ref().map(|u| u.id.get()),
+			uses: i.uses,
+			max_uses: i.max_uses,
+			max_age: i.max_age,
+			temporary: i.temporary.unwrap_or(false),
+			created_at: i.created_at.map(|t| t.as_micros().cast_unsigned() / 1000),
+			deleted: false,
+		}
+	}
+
+	pub fn from_create(i: &InviteCreate) -> Self {
+		Self {
+			code: i.code.clone(),
+			channel_id: Some(i.channel_id.get()),
+			inviter_id: i.inviter.as_ref().map(|u| u.id.get()),
+			uses: Some(u64::from(i.uses)),
+			max_uses: Some(i.max_uses),
+			max_age: Some(i.max_age),
+			temporary: i.temporary,
+			created_at: Some(i.created_at.as_micros().cast_unsigned() / 1000),
+			deleted: false,
+		}
+	}
+
+	pub const fn from_delete(code: String) -> Self {
+		Self {
+			code,
+			channel_id: None,
+			inviter_id: None,
+			uses: None,
+			max_uses: None,
+			max_age: None,
+			temporary: false,
+			created_at: None,
+			deleted: true,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct IntegrationEvent {
+	#[serde(rename = "i")]
+	pub id: u64,
+	#[serde(rename = "n")]
+	pub name: String,
+	#[serde(rename = "k", skip_serializing_if = "Option::is_none")]
+	pub kind: Option<GuildIntegrationType>,
+	#[serde(rename = "ai", skip_serializing_if = "String::is_empty", default)]
+	pub account_id: String,
+	#[serde(rename = "an", skip_serializing_if = "String::is_empty", default)]
+	pub account_name: String,
+	#[serde(rename = "e", skip_serializing_if = "Option::is_none")]
+	pub enabled: Option<bool>,
+	#[serde(rename = "r", skip_serializing_if = "Option::is_none")]
+	pub role_id: Option<u64>,
+	#[serde(rename = "d", skip_serializing_if = "<&bool as std::ops::Not>::not", default)]
+	pub deleted: bool,
+}
+
+impl Replayable for IntegrationEvent {
+	fn id(&self) -> u64 {
+		self.id
+	}
+	fn is_delete(&self) -> bool {
+		self.deleted
+	}
+}
+
+impl IntegrationEvent {
+	pub fn from_api(i: &GuildIntegration) -> Self {
+		Self {
+			id: i.id.get(),
+			name: i.name.clone(),
+			kind: Some(i.kind.clone()),
+			account_id: i.account.id.clone(),
+			account_name: i.account.name.clone(),
+			enabled: i.enabled,
+			role_id: i.role_id.map(Id::get),
+			deleted: false,
+		}
+	}
+
+	pub fn from_owned(i: GuildIntegration) -> Self {
+		Self::from_api(&i)
+	}
+
+	pub const fn from_delete(id: u64) -> Self {
+		Self {
+			id,
+			name: String::new(),
+			kind: None,
+			account_id: String::new(),
+			account_name: String::new(),
+			enabled: None,
+			role_id: None,
+			deleted: true,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct BanEvent {
+	#[serde(rename = "i")]
+	pub user_id: u64,
+	#[serde(rename = "rs", skip_serializing_if = "Option::is_none")]
+	pub reason: Option<String>,
+	#[serde(rename = "d", skip_serializing_if = "<&bool as std::ops::Not>::not", default)]
+	pub deleted: bool,
+}
+
+impl Replayable for BanEvent {
+	fn id(&self) -> u64 {
+		self.user_id
+	}
+	fn is_delete(&self) -> bool {
+		self.deleted
+	}
+}
+
+impl BanEvent {
+	pub fn from_ban(b: Ban) -> Self {
+		Self {
+			user_id: b.user.id.get(),
+			reason: b.reason,
+			deleted: false,
+		}
+	}
+
+	pub const fn from_delete(user_id: u64) -> Self {
+		Self {
+			user_id,
+			reason: None,
+			deleted: true,
+		}
+	}
+}
+
+/// A slash command the bot registered in the guild.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct CommandEvent {
+	#[serde(rename = "i")]
+	pub id: u64,
+	#[serde(rename = "n")]
+	pub name: String,
+	#[serde(rename = "ds", skip_serializing_if = "String::is_empty", default)]
+	pub description: String,
+	/// Chat input, user or message command.
+	#[serde(rename = "k", skip_serializing_if = "Option::is_none", default)]
+	pub kind: Option<CommandType>,
+	/// Options as Discord sends them, with their full field names.
+	#[serde(rename = "o", skip_serializing_if = "Vec::is_empty", default)]
+	pub options: Vec<CommandOption>,
+	/// Permissions needed to use the command by default, as a bitfield string.
+	#[serde(rename = "ps", skip_serializing_if = "Option::is_none", default)]
+	pub default_member_permissions: Option<String>,
+	#[serde(rename = "ns", skip_serializing_if = "<&bool as std::ops::Not>::not", default)]
+	pub nsfw: bool,
+	/// Changes with every update of the command.
+	#[serde(rename = "v", default)]
+	pub version: u64,
+	#[serde(rename = "d", skip_serializing_if = "<&bool as std::ops::Not>::not", default)]
+	pub deleted: bool,
+}
+
+impl Replayable for CommandEvent {
+	fn id(&self) -> u64 {
+		self.id
+	}
+	fn is_delete(&self) -> bool {
+		self.deleted
+	}
+}
+
+impl CommandEvent {
+	/// `None` for a command without an ID, which Discord only leaves out when creating one.
+	pub fn from_api(c: Command) -> Option<Self> {
+		Some(Self {
+			id: c.id?.get(),
+			name: c.name,
+			description: c.description,
+			kind: Some(c.kind),
+			options: c.options,
+			default_member_permissions: c.default_member_permissions.map(|p| p.bits().to_string()),
+			nsfw: c.nsfw.unwrap_or_default(),
+			version: c.version.get(),
+			deleted: false,
+		})
+	}
+
+	pub const fn from_delete(id: u64) -> Self {
+		Self {
+			id,
+			name: String::new(),
+			description: String::new(),
+			kind: None,
+			options: Vec::new(),
+			default_member_permissions: None,
+			nsfw: false,
+			version: 0,
 			deleted: true,
 		}
 	}
@@ -340,45 +786,148 @@ impl StickerEvent {
 
 #[derive(Debug)]
 struct EntityManager<T> {
+	kind: MetadataKind,
 	state: HashMap<u64, T>,
-	log_store: LogStore,
+	/// `None` when the kind is left out of `metadata.enabled`. Nothing is tracked or stored then.
+	log_store: Option<LogStore>,
 }
 
 impl<T> EntityManager<T>
 where
 	T: Serialize + DeserializeOwned + Clone + PartialEq + Send + Sync + Replayable + 'static,
 {
-	async fn new(guild_id: Id<GuildMarker>, entity_type: &'static str, shutdown: Arc<AtomicBool>) -> Self {
-		let path = create_path(&[&guild_id.to_string(), "metadata", &concat_str!(16, &entity_type, ".ndjson")]);
-		let log_store = LogStore::new(path, &shutdown).expect("Failed to create log store");
-
-		let mut state = HashMap::new();
-		if let Ok(events) = log_store.read_all::<T>().await {
-			for event in events {
-				let data = event.payload;
-				if data.is_delete() {
-					state.remove(&data.id());
-				} else {
-					state.insert(data.id(), data);
-				}
+	async fn new(guild_id: Id<GuildMarker>, kind: MetadataKind, shutdown: Arc<AtomicBool>) -> Result<Self> {
+		if !kind.is_enabled() {
+			return Ok(Self {
+				kind,
+				state: HashMap::new(),
+				log_store: None,
+			});
+		}
+		let path = create_path(&[&guild_id.to_string(), "metadata", &concat_str!(16, kind.name(), ".ndjson")]);
+		let log_store = LogStore::new(path, &shutdown)?;
+
+		let state = log_store
+			.read_all::<T>()
+			.await
+			.map(|events| replay(events.into_iter().map(|event| event.payload)))
+			.unwrap_or_default();
+
+		Ok(Self {
+			kind,
+			state,
+			log_store: Some(log_store),
+		})
+	}
+
+	const fn enabled(&self) -> bool {
+		self.log_store.is_some()
+	}
+
+	/// Rewrites the log down to the last record of every entity that still exists, if it is listed in `storage.compact_metadata`.
+	/// Nothing may be appended meanwhile. Returns the number of records dropped.
+	/// Fails without touching the log if any record can't be read, as the rewrite would drop it.
+	async fn compact(&self) -> Result<usize> {
+		let Some(log_store) = &self.log_store else { return Ok(0) };
+		if !self.kind.is_compacted() {
+			return Ok(0);
+		}
+
+		// Buffered records would be missed by the read, and then lost to the rewrite
+		log_store.flush().await?;
+		let events = log_store
+			.read_all_exact::<T>()
+			.await
+			.with_context(|| format!("Not compacting the {} log", self.kind.name()))?;
+		let total = events.len();
+		let mut kept: Vec<LogEvent<T>> = replay(events).into_values().collect();
+		if kept.len() == total {
+			return Ok(0);
+		}
+		kept.sort_by_key(|event| event.timestamp);
+
+		log_store.rewrite(&kept).await?;
+		info!(
+			kind = self.kind.name(),
+			kept = kept.len(),
+			dropped = total - kept.len(),
+			"Compacted metadata log."
+		);
+		Ok(total - kept.len())
+	}
+
+	/// Replays the log and compares it to the state in memory, warning about every kind of difference.
+	/// Returns the number of entities that differ.
+	async fn verify(&self) -> Result<usize> {
+		/// IDs listed in a warning, enough to look into it without flooding the log.
+		const SAMPLE: usize = 5;
+
+		let Some(log_store) = &self.log_store else { return Ok(0) };
+		log_store.flush().await?;
+		let stored = replay(log_store.read_all::<T>().await?.into_iter().map(|event| event.payload));
+
+		let mut changed = Vec::new();
+		let mut unstored = Vec::new();
+		for (id, item) in &self.state {
+			match stored.get(id) {
+				Some(stored_item) if stored_item == item => {}
+				Some(_) => changed.push(*id),
+				None => unstored.push(*id),
 			}
 		}
+		let mut stale: Vec<u64> = stored.keys().filter(|id| !self.state.contains_key(id)).copied().collect();
 
-		Self { state, log_store }
+		let kind = self.kind.name();
+		for (ids, problem) in [
+			(&mut changed, "Stored entities differ from the ones in memory."),
+			(&mut unstored, "Entities in memory are missing from the log."),
+			(&mut stale, "Entities in the log are gone from memory."),
+		] {
+			if !ids.is_empty() {
+				ids.sort_unstable();
+				warn!(kind, count = ids.len(), ids = ?&ids[..ids.len().min(SAMPLE)], "{problem}");
+			}
+		}
+		Ok(changed.len() + unstored.len() + stale.len())
 	}
 
 	fn handle_update(&mut self, id: u64, data: T) -> Result<bool> {
+		let Some(log_store) = &self.log_store else { return Ok(false) };
 		if self.state.get(&id) == Some(&data) {
 			return Ok(false);
 		}
-		self.log_store.append(&data)?;
+		log_store.append(&data)?;
 		self.state.insert(id, data);
 		Ok(true)
 	}
 
 	fn handle_delete(&mut self, id: u64, delete_event_generator: impl FnOnce() -> T) -> Result<()> {
-		if self.state.contains_key(&id) {
-			self.log_store.append(&delete_event_generator())?;
+		if let Some(log_store) = &self.log_store
+			&& self.state.contains_key(&id)
+		{
+			log_store.append(&delete_event_generator())?;
+			self.state.remove(&id);
+		}
+		Ok(())
+	}
+
+	/// Like `handle_update`, but writes every changed item in a single append.
+	fn handle_update_bulk(&mut self, items: impl IntoIterator<Item = T>) -> Result<()> {
+		let Some(log_store) = &self.log_store else { return Ok(()) };
+		let changed: Vec<T> = items.into_iter().filter(|item| self.state.get(&item.id()) != Some(item)).collect();
+		log_store.append_bulk(changed.clone())?;
+		for item in changed {
+			self.state.insert(item.id(), item);
+		}
+		Ok(())
+	}
+
+	/// Like `handle_delete`, but writes all tombstones in a single append.
+	fn handle_delete_bulk(&mut self, ids: &[u64], delete_event_generator: impl Fn(u64) -> T) -> Result<()> {
+		let Some(log_store) = &self.log_store else { return Ok(()) };
+		let deleted: Vec<u64> = ids.iter().copied().filter(|id| self.state.contains_key(id)).collect();
+		log_store.append_bulk(deleted.iter().copied().map(delete_event_generator).collect())?;
+		for id in deleted {
 			self.state.remove(&id);
 		}
 		Ok(())
@@ -409,12 +958,23 @@ where
 	}
 }
 
+/// When a slow step last completed for a guild: the member list sync in `metadata/member_sync.json`,
+/// or the full catchup in `metadata/catchup_complete.json`.
+#[derive(Debug, Serialize, Deserialize)]
+struct CompletionMarker {
+	#[serde(rename = "ts")]
+	timestamp: u64,
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum GuildUpdate<'a> {
 	Partial(&'a PartialGuild),
 	Full(&'a TwilightGuild),
 }
 
+/// How long role moves are held back for more of the same reorder to come in.
+const ROLE_MOVE_WINDOW: Duration = Duration::from_secs(1);
+
 #[derive(Debug)]
 pub struct MetadataArchiver {
 	guild_id_str: String,
@@ -424,28 +984,86 @@ pub struct MetadataArchiver {
 	guild_info: EntityManager<GuildEvent>,
 	emojis: EntityManager<EmojiEvent>,
 	stickers: EntityManager<StickerEvent>,
+	invites: EntityManager<InviteEvent>,
+	integrations: EntityManager<IntegrationEvent>,
+	bans: EntityManager<BanEvent>,
+	commands: EntityManager<CommandEvent>,
+	/// Roles whose position changed, and nothing else. Held back to be written together, see `process_role_update`.
+	role_moves: HashMap<u64, RoleEvent>,
+	/// When the first held back role move is due to be written.
+	role_moves_due: Option<Instant>,
+	presences: Option<PresenceArchiver>,
+	unhandled: Option<LogStore>,
+	stats: Option<LogStore>,
+}
+
+/// A sample of the guild's size, see `stats_interval_secs`.
+#[derive(Debug, Serialize)]
+struct GuildStats {
+	#[serde(rename = "mc", skip_serializing_if = "Option::is_none")]
+	member_count: Option<u64>,
+	#[serde(rename = "pc", skip_serializing_if = "Option::is_none")]
+	presence_count: Option<u64>,
+}
+
+/// A gateway event without a handler, stored as JSON. See `capture_unhandled_events`.
+#[derive(Debug, Serialize)]
+struct UnhandledEvent {
+	#[serde(rename = "k")]
+	kind: String,
+	#[serde(rename = "d")]
+	data: sonic_rs::Value,
 }
 
 impl MetadataArchiver {
-	pub async fn new(guild_id: Id<GuildMarker>, shutdown: Arc<AtomicBool>) -> Self {
-		let (members, roles, channels, guild_info, emojis, stickers) = tokio::join!(
-			EntityManager::new(guild_id, "members", shutdown.clone()),
-			EntityManager::new(guild_id, "roles", shutdown.clone()),
-			EntityManager::new(guild_id, "channels", shutdown.clone()),
-			EntityManager::new(guild_id, "guild", shutdown.clone()),
-			EntityManager::new(guild_id, "emojis", shutdown.clone()),
-			EntityManager::new(guild_id, "stickers", shutdown.clone()),
+	pub async fn new(guild_id: Id<GuildMarker>, shutdown: Arc<AtomicBool>) -> Result<Self> {
+		let (members, roles, channels, guild_info, emojis, stickers, invites, integrations, bans, commands) = tokio::join!(
+			EntityManager::new(guild_id, MetadataKind::Members, shutdown.clone()),
+			EntityManager::new(guild_id, MetadataKind::Roles, shutdown.clone()),
+			EntityManager::new(guild_id, MetadataKind::Channels, shutdown.clone()),
+			EntityManager::new(guild_id, MetadataKind::Guild, shutdown.clone()),
+			EntityManager::new(guild_id, MetadataKind::Emojis, shutdown.clone()),
+			EntityManager::new(guild_id, MetadataKind::Stickers, shutdown.clone()),
+			EntityManager::new(guild_id, MetadataKind::Invites, shutdown.clone()),
+			EntityManager::new(guild_id, MetadataKind::Integrations, shutdown.clone()),
+			EntityManager::new(guild_id, MetadataKind::Bans, shutdown.clone()),
+			EntityManager::new(guild_id, MetadataKind::Commands, shutdown.clone()),
 		);
 
-		Self {
-			guild_id_str: int_to_str!(guild_id.get(), u64),
-			members,
-			roles,
-			channels,
-			guild_info,
-			emojis,
-			stickers,
-		}
+		let presences = SETTINGS
+			.metadata
+			.archive_presences
+			.then(|| PresenceArchiver::new(guild_id, &shutdown))
+			.transpose()?;
+
+		let guild_id_str = int_to_str!(guild_id.get(), u64);
+		let unhandled = SETTINGS
+			.metadata
+			.capture_unhandled_events
+			.then(|| LogStore::new(create_path(&[&guild_id_str, "metadata", "unhandled.ndjson"]), &shutdown))
+			.transpose()?;
+		let stats = (SETTINGS.metadata.stats_interval_secs > 0)
+			.then(|| LogStore::new(create_path(&[&guild_id_str, "metadata", "stats.ndjson"]), &shutdown))
+			.transpose()?;
+
+		Ok(Self {
+			guild_id_str,
+			members: members?,
+			roles: roles?,
+			channels: channels?,
+			guild_info: guild_info?,
+			emojis: emojis?,
+			stickers: stickers?,
+			invites: invites?,
+			integrations: integrations?,
+			bans: bans?,
+			commands: commands?,
+			role_moves: HashMap::new(),
+			role_moves_due: None,
+			presences,
+			unhandled,
+			stats,
+		})
 	}
 
 	fn asset_path(&self, folder: &str) -> PathBuf {
@@ -482,36 +1100,189 @@ impl MetadataArchiver {
 		Ok(())
 	}
 
+	/// Dragging a role in Discord sends an update for every role it moved past.
+	/// Updates that only change the position are held back for `ROLE_MOVE_WINDOW`, and written in one batch by `flush_role_moves`.
 	pub fn process_role_update(&mut self, role: &TwilightRole) -> Result<()> {
-		self.roles.handle_update(role.id.get(), RoleEvent::from_role_ref(role))?;
+		let event = RoleEvent::from_role_ref(role);
+		let moved_only = self.roles.state.get(&event.role_id).is_some_and(|old| {
+			old.position != event.position
+				&& *old
+					== RoleEvent {
+						position: old.position,
+						..event.clone()
+					}
+		});
+		if moved_only {
+			self.role_moves_due.get_or_insert_with(|| Instant::now() + ROLE_MOVE_WINDOW);
+			self.role_moves.insert(event.role_id, event);
+			return Ok(());
+		}
+
+		self.flush_role_moves()?;
+		self.roles.handle_update(role.id.get(), event)?;
 		Ok(())
 	}
 
 	pub fn process_role_delete(&mut self, role_id: u64) -> Result<()> {
+		self.flush_role_moves()?;
 		self.roles.handle_delete(role_id, || RoleEvent::from_delete(role_id))?;
 		Ok(())
 	}
 
+	/// When the held back role moves are due to be written, if there are any.
+	pub const fn role_moves_due(&self) -> Option<Instant> {
+		self.role_moves_due
+	}
+
+	/// Writes the held back role moves, all with the same timestamp.
+	pub fn flush_role_moves(&mut self) -> Result<()> {
+		self.role_moves_due = None;
+		if self.role_moves.is_empty() {
+			return Ok(());
+		}
+		let mut moves: Vec<RoleEvent> = self.role_moves.drain().map(|(_, event)| event).collect();
+		moves.sort_unstable_by_key(|event| event.position);
+		self.roles.handle_update_bulk(moves)
+	}
+
 	pub fn process_member_update(&mut self, state: &State, member: &TwilightMember) -> Result<()> {
 		let event = MemberEvent::from_add_or_update(member);
 		if self.members.handle_update(member.user.id.get(), event)? {
-			self.queue_avatar(state, member.user.id, member.avatar.or(member.user.avatar));
+			self.queue_avatar(state, member);
 		}
 		Ok(())
 	}
 
 	pub fn process_member_remove(&mut self, user_id: u64) -> Result<()> {
 		let ts = get_current_time_millis()?;
-		self.members.handle_delete(user_id, || MemberEvent {
+		self.members.handle_delete(user_id, || MemberEvent::from_remove(user_id, ts))?;
+		Ok(())
+	}
+
+	pub fn process_invite_create(&mut self, invite: &InviteCreate) -> Result<()> {
+		let event = InviteEvent::from_create(invite);
+		self.invites.handle_update(event.id(), event)?;
+		Ok(())
+	}
+
+	pub fn process_invite_delete(&mut self, code: &str) -> Result<()> {
+		self.invites
+			.handle_delete(invite_key(code), || InviteEvent::from_delete(code.to_owned()))?;
+		Ok(())
+	}
+
+	pub fn process_integration_update(&mut self, integration: &GuildIntegration) -> Result<()> {
+		self.integrations
+			.handle_update(integration.id.get(), IntegrationEvent::from_api(integration))?;
+		Ok(())
+	}
+
+	pub fn process_integration_delete(&mut self, integration_id: u64) -> Result<()> {
+		self.integrations
+			.handle_delete(integration_id, || IntegrationEvent::from_delete(integration_id))?;
+		Ok(())
+	}
+
+	pub fn process_ban_add(&mut self, user_id: u64, reason: Option<String>) -> Result<()> {
+		self.bans.handle_update(
 			user_id,
-			username: "UNKNOWN".into(),
-			global_name: None,
-			avatar: None,
-			joined_at: None,
-			left_at: Some(ts),
-			roles: vec![],
-			nickname: None,
-			bot: false,
+			BanEvent {
+				user_id,
+				reason,
+				deleted: false,
+			},
+		)?;
+		Ok(())
+	}
+
+	pub fn process_ban_remove(&mut self, user_id: u64) -> Result<()> {
+		self.bans.handle_delete(user_id, || BanEvent::from_delete(user_id))?;
+		Ok(())
+	}
+
+	pub fn process_presence_update(&mut self, presence: &Presence) -> Result<()> {
+		if let Some(presences) = &mut self.presences {
+			presences.process_update(presence)?;
+		}
+		Ok(())
+	}
+
+	pub fn process_unhandled(&self, event: Event) -> Result<()> {
+		let Some(log_store) = &self.unhandled else { return Ok(()) };
+		let kind = event.kind();
+		// Non-dispatch events (like gateway heartbeats) carry no data of the guild
+		let Ok(dispatch) = DispatchEvent::try_from(event) else {
+			return Ok(());
+		};
+		log_store.append(&UnhandledEvent {
+			kind: kind.name().map_or_else(|| format!("{kind:?}"), str::to_owned),
+			data: sonic_rs::to_value(&dispatch)?,
+		})?;
+		Ok(())
+	}
+
+	/// Compacts the metadata logs listed in `storage.compact_metadata`. Runs on the guild processor, so no event is archived meanwhile.
+	pub async fn compact(&mut self) -> Result<()> {
+		if SETTINGS.dry_run {
+			info!("Dry run: skipping metadata compaction.");
+			return Ok(());
+		}
+		// Held back role moves would otherwise be written during the rewrite
+		self.flush_role_moves()?;
+
+		let dropped = self.members.compact().await?
+			+ self.roles.compact().await?
+			+ self.channels.compact().await?
+			+ self.guild_info.compact().await?
+			+ self.emojis.compact().await?
+			+ self.stickers.compact().await?
+			+ self.invites.compact().await?
+			+ self.integrations.compact().await?
+			+ self.bans.compact().await?
+			+ self.commands.compact().await?;
+		if dropped > 0 {
+			info!(dropped, "Compacted metadata logs.");
+		}
+		Ok(())
+	}
+
+	/// Checks every metadata log against the state in memory, see `metadata.verify_after_catchup`.
+	async fn verify(&mut self) -> Result<()> {
+		// Nothing was stored to compare with
+		if SETTINGS.dry_run {
+			return Ok(());
+		}
+		// Held back role moves are in neither yet
+		self.flush_role_moves()?;
+
+		let mismatches = self.members.verify().await?
+			+ self.roles.verify().await?
+			+ self.channels.verify().await?
+			+ self.guild_info.verify().await?
+			+ self.emojis.verify().await?
+			+ self.stickers.verify().await?
+			+ self.invites.verify().await?
+			+ self.integrations.verify().await?
+			+ self.bans.verify().await?
+			+ self.commands.verify().await?;
+		if mismatches == 0 {
+			info!("Verified metadata logs, they match the state in memory.");
+		} else {
+			warn!(
+				mismatches,
+				"Metadata logs don't match the state in memory. Some records were likely lost."
+			);
+		}
+		Ok(())
+	}
+
+	/// Records the guild's approximate member and online counts, as reported by Discord.
+	pub async fn sample_stats(&self, state: &State, guild_id: Id<GuildMarker>) -> Result<()> {
+		let Some(log_store) = &self.stats else { return Ok(()) };
+		let guild = retry_http(|| async { Ok(state.http().guild(guild_id).with_counts(true).await?.model().await?) }).await?;
+		log_store.append(&GuildStats {
+			member_count: guild.approximate_member_count,
+			presence_count: guild.approximate_presence_count,
 		})?;
 		Ok(())
 	}
@@ -520,45 +1291,158 @@ impl MetadataArchiver {
 	pub async fn do_full_catchup(&mut self, state: &State, guild_id: Id<GuildMarker>) -> Result<()> {
 		info!("Starting full metadata catchup");
 
-		let (channels, roles, guild) = tokio::try_join!(
-			state.http.guild_channels(guild_id).into_future(),
-			state.http.roles(guild_id).into_future(),
-			state.http.guild(guild_id).into_future()
-		)?;
-
-		self.channels
-			.reconcile(channels.models().await?, ChannelEvent::from_channel, ChannelEvent::from_delete)?;
-		self.roles
-			.reconcile(roles.models().await?, RoleEvent::from_role, RoleEvent::from_delete)?;
+		// Kinds left out of `metadata.enabled` aren't fetched at all
+		if self.channels.enabled() {
+			let channels = state.http().guild_channels(guild_id).await?.models().await?;
+			self.channels
+				.reconcile(channels, ChannelEvent::from_channel, ChannelEvent::from_delete)?;
+		}
+		if self.roles.enabled() {
+			self.flush_role_moves()?;
+			let roles = state.http().roles(guild_id).await?.models().await?;
+			self.roles.reconcile(roles, RoleEvent::from_role, RoleEvent::from_delete)?;
+		}
+		if self.guild_info.enabled() || self.emojis.enabled() || self.stickers.enabled() {
+			let guild_model = state.http().guild(guild_id).await?.model().await?;
+			self.process_guild_update(state, GuildUpdate::Full(&guild_model))?;
+		}
 
-		let guild_model = guild.model().await?;
-		self.process_guild_update(state, GuildUpdate::Full(&guild_model))?;
+		// Listing invites requires the Manage Server permission, so a failure here shouldn't stop the catchup
+		if self.invites.enabled() {
+			match state.http().guild_invites(guild_id).await {
+				Ok(invites) => self.sync_invites(&invites.models().await?)?,
+				Err(e) => warn!(error = ?e, "Failed to fetch guild invites. Skipping invite sync."),
+			}
+		}
+		// Same for integrations
+		if self.integrations.enabled() {
+			match state.http().guild_integrations(guild_id).await {
+				Ok(integrations) => {
+					self.integrations.reconcile(
+						integrations.models().await?,
+						IntegrationEvent::from_owned,
+						IntegrationEvent::from_delete,
+					)?;
+				}
+				Err(e) => warn!(error = ?e, "Failed to fetch guild integrations. Skipping integration sync."),
+			}
+		}
+		// And bans, which need the Ban Members permission
+		if self.bans.enabled() {
+			match fetch_bans(state, guild_id).await {
+				Ok(bans) => self.bans.reconcile(bans, BanEvent::from_ban, BanEvent::from_delete)?,
+				Err(e) => warn!(error = ?e, "Failed to fetch guild bans. Skipping ban sync."),
+			}
+		}
+		// Commands change without any gateway event, so catchup is the only time they are seen
+		if self.commands.enabled() {
+			let application_id = Id::new_checked(SETTINGS.metadata.application_id).context("Invalid `metadata.application_id`")?;
+			match state.http().interaction(application_id).guild_commands(guild_id).await {
+				Ok(commands) => {
+					let commands = commands.models().await?.into_iter().filter_map(CommandEvent::from_api).collect();
+					self.commands
+						.reconcile(commands, std::convert::identity, CommandEvent::from_delete)?;
+				}
+				Err(e) => warn!(error = ?e, "Failed to fetch guild commands. Check `metadata.application_id`. Skipping command sync."),
+			}
+		}
 		if state.shutdown.load(Ordering::SeqCst) {
 			return Ok(());
 		}
-		self.sync_members_iterative(state, guild_id).await?;
+		if self.members.enabled() {
+			if self.member_sync_due().await {
+				if self.sync_members_iterative(state, guild_id).await? {
+					self.save_member_sync().await?;
+				}
+			} else {
+				info!("Skipping member sync, the last one is recent enough.");
+			}
+		}
+
+		// Only a check, so failing it doesn't fail the catchup
+		if SETTINGS.metadata.verify_after_catchup
+			&& !state.shutdown.load(Ordering::SeqCst)
+			&& let Err(e) = self.verify().await
+		{
+			warn!(error = ?e, "Failed to verify metadata logs");
+		}
 
 		info!("Metadata catchup complete.");
 		Ok(())
 	}
 
-	async fn sync_members_iterative(&mut self, state: &State, guild_id: Id<GuildMarker>) -> Result<()> {
+	fn marker_path(&self, name: &str) -> PathBuf {
+		create_path(&[&self.guild_id_str, "metadata", name])
+	}
+
+	/// Whether `interval_secs` has passed since the completion recorded in the marker file `name`.
+	async fn marker_due(&self, name: &str, interval_secs: u64) -> bool {
+		if interval_secs == 0 {
+			return true;
+		}
+		let Ok(bytes) = tokio::fs::read(self.marker_path(name)).await else {
+			return true;
+		};
+		let Ok(last) = sonic_rs::from_slice::<CompletionMarker>(&bytes) else {
+			return true;
+		};
+		get_current_time_millis().is_ok_and(|now| now.saturating_sub(last.timestamp) >= interval_secs.saturating_mul(1000))
+	}
+
+	async fn save_marker(&self, name: &str) -> Result<()> {
+		// Nothing was stored
+		if SETTINGS.dry_run {
+			return Ok(());
+		}
+		let marker = CompletionMarker {
+			timestamp: get_current_time_millis()?,
+		};
+		tokio::fs::write(self.marker_path(name), sonic_rs::to_vec(&marker)?).await?;
+		Ok(())
+	}
+
+	/// Whether `metadata.member_sync_interval_secs` has passed since the last complete member sync.
+	async fn member_sync_due(&self) -> bool {
+		self.marker_due("member_sync.json", SETTINGS.metadata.member_sync_interval_secs)
+			.await
+	}
+
+	async fn save_member_sync(&self) -> Result<()> {
+		self.save_marker("member_sync.json").await
+	}
+
+	/// Whether `catchup.full_catchup_interval_secs` has passed since the last complete full catchup.
+	pub async fn full_catchup_due(&self) -> bool {
+		self.marker_due("catchup_complete.json", SETTINGS.catchup.full_catchup_interval_secs)
+			.await
+	}
+
+	pub async fn save_catchup_complete(&self) -> Result<()> {
+		self.save_marker("catchup_complete.json").await
+	}
+
+	/// Fetches the whole member list, recording changes and departures. Returns false if it was interrupted by shutdown.
+	async fn sync_members_iterative(&mut self, state: &State, guild_id: Id<GuildMarker>) -> Result<bool> {
 		let mut after = Id::new(1);
 		let mut seen_members = HashSet::new();
 		let mut total = 0;
 
 		loop {
 			if state.shutdown.load(Ordering::SeqCst) {
-				break;
+				// Members not seen yet aren't gone, so departures can't be told
+				return Ok(false);
 			}
-			let members = state
-				.http
-				.guild_members(guild_id)
-				.limit(SETTINGS.metadata.member_fetch_limit)
-				.after(after)
-				.await?
-				.models()
-				.await?;
+			let members = retry_http(|| async {
+				Ok(state
+					.http()
+					.guild_members(guild_id)
+					.limit(SETTINGS.metadata.member_fetch_limit)
+					.after(after)
+					.await?
+					.models()
+					.await?)
+			})
+			.await?;
 			if members.is_empty() {
 				break;
 			}
@@ -575,26 +1459,28 @@ impl MetadataArchiver {
 			}
 		}
 
-		let cached: Vec<u64> = self.members.state.keys().copied().collect();
-		for id in cached {
-			if !seen_members.contains(&id) {
-				self.process_member_remove(id)?;
-			}
-		}
-		info!("Synced {} members.", total);
-		Ok(())
+		let ts = get_current_time_millis()?;
+		let departed: Vec<u64> = self.members.state.keys().copied().filter(|id| !seen_members.contains(id)).collect();
+		self.members.handle_delete_bulk(&departed, |id| MemberEvent::from_remove(id, ts))?;
+		info!("Synced {} members ({} departed).", total, departed.len());
+		Ok(true)
 	}
 
 	fn sync_emojis(&mut self, state: &State, emojis: &[TwilightEmoji]) -> Result<()> {
 		let mut seen = HashSet::new();
 		for emoji in emojis {
 			seen.insert(emoji.id.get());
-			let evt = EmojiEvent::from_api(emoji);
+			let mut evt = EmojiEvent::from_api(emoji);
+			// Gateway updates leave the creator out, which isn't a change
+			if evt.creator_id.is_none() {
+				evt.creator_id = self.emojis.state.get(&evt.id).and_then(|e| e.creator_id);
+			}
 			if self.emojis.handle_update(emoji.id.get(), evt)? {
 				let id = int_to_str!(emoji.id.get(), u64);
 				let ext = if emoji.animated { ".gif" } else { ".png" };
 				state.submit_download(
-					append_cdn(&["emojis/", &id, ext]),
+					AssetKind::Emoji,
+					append_cdn_sized(&["emojis/", &id, ext]),
 					self.asset_path("emojis"),
 					concat_str!(36, &id, ext),
 				);
@@ -609,11 +1495,34 @@ impl MetadataArchiver {
 		Ok(())
 	}
 
+	fn sync_invites(&mut self, invites: &[TwilightInvite]) -> Result<()> {
+		let mut seen = HashSet::new();
+		for invite in invites {
+			let evt = InviteEvent::from_api(invite);
+			seen.insert(evt.id());
+			self.invites.handle_update(evt.id(), evt)?;
+		}
+		let gone: Vec<String> = self
+			.invites
+			.state
+			.iter()
+			.filter(|(id, _)| !seen.contains(*id))
+			.map(|(_, invite)| invite.code.clone())
+			.collect();
+		for code in gone {
+			self.process_invite_delete(&code)?;
+		}
+		Ok(())
+	}
+
 	fn sync_stickers(&mut self, state: &State, stickers: &[TwilightSticker]) -> Result<()> {
 		let mut seen = HashSet::new();
 		for sticker in stickers {
 			seen.insert(sticker.id.get());
-			let evt = StickerEvent::from_api(sticker);
+			let mut evt = StickerEvent::from_api(sticker);
+			if evt.creator_id.is_none() {
+				evt.creator_id = self.stickers.state.get(&evt.id).and_then(|s| s.creator_id);
+			}
 			if self.stickers.handle_update(sticker.id.get(), evt)? {
 				let id = int_to_str!(sticker.id.get(), u64);
 				let ext = match sticker.format_type {
@@ -622,11 +1531,12 @@ impl MetadataArchiver {
 					StickerFormatType::Gif => ".gif",
 					_ => ".bin",
 				};
-				state.submit_download(
-					append_cdn(&["stickers/", &id, ext]),
-					self.asset_path("stickers"),
-					concat_str!(36, &id, ext),
-				);
+				// Lottie stickers are vector animations, with no size to ask for
+				let url = match ext {
+					".png" | ".gif" => append_cdn_sized(&["stickers/", &id, ext]),
+					_ => append_cdn(&["stickers/", &id, ext]),
+				};
+				state.submit_download(AssetKind::Sticker, url, self.asset_path("stickers"), concat_str!(36, &id, ext));
 			}
 		}
 		let cached: Vec<u64> = self.stickers.state.keys().copied().collect();
@@ -638,24 +1548,65 @@ impl MetadataArchiver {
 		Ok(())
 	}
 
-	fn queue_avatar(&self, state: &State, user_id: Id<UserMarker>, hash: Option<ImageHash>) {
-		if let Some(h) = hash {
-			let user_id = int_to_str!(user_id.get(), u64);
+	fn queue_avatar(&self, state: &State, member: &TwilightMember) {
+		if !state.download_assets {
+			return;
+		}
+		let user_id = int_to_str!(member.user.id.get(), u64);
+
+		if let Some(h) = member.avatar.or(member.user.avatar) {
 			let ext = if h.is_animated() { ".gif" } else { ".png" };
 			state.submit_download(
-				append_cdn(&["avatars/", &user_id, "/", &h.to_string(), ext]),
+				AssetKind::Avatar,
+				append_cdn_sized(&["avatars/", &user_id, "/", &h.to_string(), ext]),
 				self.asset_path("avatars"),
 				concat_str!(57, &user_id, "_", &h.to_string(), ext),
 			);
 		}
+
+		// Decorations are shared presets, so they are stored by asset hash alone
+		if let Some(decoration) = member
+			.avatar_decoration_data
+			.as_ref()
+			.or(member.user.avatar_decoration_data.as_ref())
+		{
+			let asset = decoration.asset.to_string();
+			state.submit_download(
+				AssetKind::AvatarDecoration,
+				append_cdn_sized(&["avatar-decoration-presets/", &asset, ".png"]),
+				self.asset_path("decorations"),
+				concat_str!(36, &asset, ".png"),
+			);
+		}
+
+		if let Some(h) = member.banner.or(member.user.banner) {
+			let hash = h.to_string();
+			let ext = if h.is_animated() { ".gif" } else { ".png" };
+			// Guild-specific banners live under a different CDN path than global profile banners
+			let url = if member.banner.is_some() {
+				append_cdn_sized(&["guilds/", &self.guild_id_str, "/users/", &user_id, "/banners/", &hash, ext])
+			} else {
+				append_cdn_sized(&["banners/", &user_id, "/", &hash, ext])
+			};
+			state.submit_download(
+				AssetKind::MemberBanner,
+				url,
+				self.asset_path("member_banners"),
+				concat_str!(57, &user_id, "_", &hash, ext),
+			);
+		}
 	}
 
 	fn queue_guild_assets(&self, state: &State, guild: &TwilightGuild) {
+		if !state.download_assets {
+			return;
+		}
 		let gid = &self.guild_id_str;
 		if let Some(icon) = guild.icon {
 			let ext = if icon.is_animated() { ".gif" } else { ".png" };
 			state.submit_download(
-				append_cdn(&["icons/", gid, "/", &icon.to_string(), ext]),
+				AssetKind::GuildIcon,
+				append_cdn_sized(&["icons/", gid, "/", &icon.to_string(), ext]),
 				self.asset_path("icons"),
 				concat_str!(36, &icon.to_string(), ext),
 			);
@@ -663,17 +1614,121 @@ impl MetadataArchiver {
 		if let Some(banner) = guild.banner {
 			let ext = if banner.is_animated() { ".gif" } else { ".png" };
 			state.submit_download(
-				append_cdn(&["banners/", gid, "/", &banner.to_string(), ext]),
+				AssetKind::GuildBanner,
+				append_cdn_sized(&["banners/", gid, "/", &banner.to_string(), ext]),
 				self.asset_path("banners"),
 				concat_str!(36, &banner.to_string(), ext),
 			);
 		}
 		if let Some(splash) = guild.splash {
 			state.submit_download(
-				append_cdn(&["splashes/", gid, "/", &splash.to_string(), ".png"]),
+				AssetKind::GuildSplash,
+				append_cdn_sized(&["splashes/", gid, "/", &splash.to_string(), ".png"]),
 				self.asset_path("splashes"),
 				concat_str!(36, &splash.to_string(), ".png"),
 			);
 		}
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mock_discord::{self, MockDiscord};
+	use crate::storage::{self, for_each_record};
+	use serde_json::json;
+
+	/// Every record of one of the guild's metadata logs, in the order they were written.
+	fn read_log<T: DeserializeOwned>(guild_id: u64, kind: MetadataKind) -> Vec<T> {
+		let path = create_path(&[&guild_id.to_string(), "metadata", &format!("{}.ndjson", kind.name())]);
+		let mut events = Vec::new();
+		for_each_record(&path, |event: LogEvent<T>| events.push(event.payload)).unwrap();
+		events
+	}
+
+	/// Serves what a full catchup of the guild fetches, with `members` as the IDs of its members and their roles.
+	async fn serve_guild(discord: &MockDiscord, guild_id: u64, members: &[(u64, Vec<u64>)]) {
+		let guild = format!("guilds/{guild_id}");
+		discord.serve_json(&guild, mock_discord::guild(guild_id)).await;
+		let channels = json!([mock_discord::channel(guild_id, 2002, None)]);
+		discord.serve_json(&format!("{guild}/channels"), channels).await;
+		let roles = json!([
+			mock_discord::role(guild_id, "@everyone", 0),
+			mock_discord::role(3002, "Moderator", 1)
+		]);
+		discord.serve_json(&format!("{guild}/roles"), roles).await;
+		let members = members.iter().map(|(id, roles)| (*id, mock_discord::member(*id, roles))).collect();
+		discord
+			.serve(&format!("{guild}/members"), mock_discord::paged(members, false))
+			.await;
+		for route in ["invites", "integrations", "bans"] {
+			discord.serve_json(&format!("{guild}/{route}"), json!([])).await;
+		}
+	}
+
+	fn member_roles(members: &[MemberEvent]) -> Vec<(u64, Vec<u64>)> {
+		members.iter().map(|m| (m.user_id, m.roles.clone())).collect()
+	}
+
+	#[tokio::test]
+	async fn full_catchup_stores_roles_and_members() {
+		let guild_id = 1002;
+		let discord = MockDiscord::start().await;
+		serve_guild(&discord, guild_id, &[(4001, vec![3002]), (4002, vec![]), (4003, vec![3002])]).await;
+
+		let mut archiver = MetadataArchiver::new(Id::new(guild_id), discord.state.shutdown.clone())
+			.await
+			.unwrap();
+		archiver.do_full_catchup(&discord.state, Id::new(guild_id)).await.unwrap();
+		storage::flush_all().await;
+
+		let roles: Vec<RoleEvent> = read_log(guild_id, MetadataKind::Roles);
+		let roles: Vec<(u64, &str)> = roles.iter().map(|r| (r.role_id, r.name.as_str())).collect();
+		assert_eq!(roles, [(guild_id, "@everyone"), (3002, "Moderator")]);
+		let members: Vec<MemberEvent> = read_log(guild_id, MetadataKind::Members);
+		assert_eq!(member_roles(&members), [(4001, vec![3002]), (4002, vec![]), (4003, vec![3002])]);
+		// Pages go on after the last member of the previous one, until an empty one
+		assert_eq!(discord.afters(&format!("guilds/{guild_id}/members")).await, [Some(1), Some(4003)]);
+
+		// A member missing from the next catchup has left. Nothing else changed, so nothing else is written
+		discord.server.reset().await;
+		serve_guild(&discord, guild_id, &[(4001, vec![3002]), (4003, vec![3002])]).await;
+		archiver.do_full_catchup(&discord.state, Id::new(guild_id)).await.unwrap();
+		storage::flush_all().await;
+
+		let members: Vec<MemberEvent> = read_log(guild_id, MetadataKind::Members);
+		assert_eq!(members.len(), 4);
+		let left = members.last().unwrap();
+		assert_eq!(left.user_id, 4002);
+		assert!(left.left_at.is_some());
+		assert_eq!(read_log::<RoleEvent>(guild_id, MetadataKind::Roles).len(), 2);
+	}
+
+	#[tokio::test]
+	async fn role_moves_are_due_a_window_after_the_first() {
+		let guild_id = 1004;
+		let discord = MockDiscord::start().await;
+		let mut archiver = MetadataArchiver::new(Id::new(guild_id), discord.state.shutdown.clone())
+			.await
+			.unwrap();
+		let role = |position| serde_json::from_value::<TwilightRole>(mock_discord::role(3003, "Moderator", position)).unwrap();
+
+		archiver.process_role_update(&role(1)).unwrap();
+		assert_eq!(archiver.role_moves_due(), None);
+
+		archiver.process_role_update(&role(2)).unwrap();
+		let due = archiver.role_moves_due().unwrap();
+		// More moves don't push it back
+		archiver.process_role_update(&role(3)).unwrap();
+		assert_eq!(archiver.role_moves_due(), Some(due));
+
+		archiver.flush_role_moves().unwrap();
+		assert_eq!(archiver.role_moves_due(), None);
+		storage::flush_all().await;
+		let positions: Vec<i64> = read_log::<RoleEvent>(guild_id, MetadataKind::Roles)
+			.iter()
+			.map(|r| r.position)
+			.collect();
+		assert_eq!(positions, [1, 3]);
+	}
+}
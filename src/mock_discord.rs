@@ -0,0 +1,259 @@
+use crate::State;
+use crate::network::{DownloadTracker, QueuedDownload};
+use crate::settings::TestData;
+use crate::utils::{millis_to_snowflake, snowflake_to_millis};
+use serde_json::{Value, json};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use tokio::sync::mpsc;
+use twilight_cache_inmemory::DefaultInMemoryCache;
+use twilight_http::Client as HttpClient;
+use twilight_model::channel::Channel;
+use twilight_model::gateway::payload::incoming::ChannelCreate;
+use twilight_model::util::Timestamp;
+use wiremock::matchers::{method, path};
+use wiremock::{Mock, MockServer, Request, Respond, ResponseTemplate};
+
+/// Author of every canned message.
+const AUTHOR_ID: u64 = 9000;
+/// When the first canned message was sent, and every canned member joined, in unix millis.
+const START_MS: u64 = 1_700_000_000_000;
+
+/// A stand-in for the Discord API serving canned responses, and a `State` whose client sends its requests to it.
+/// Routes that aren't served answer 404, like an unknown route on Discord.
+pub struct MockDiscord {
+	pub server: MockServer,
+	pub state: State,
+	/// Kept open so submitted downloads queue up instead of failing. Nothing is downloaded.
+	_downloads: mpsc::Receiver<QueuedDownload>,
+	/// Everything the test stores goes here. Declared last so it outlives `state`.
+	_data: TestData,
+}
+
+impl MockDiscord {
+	pub async fn start() -> Self {
+		let data = TestData::hold();
+		let server = MockServer::start().await;
+		// Like `network::discord_client` with an `http://` `discord_api_base`. Mock responses carry no rate limit headers
+		let http = HttpClient::builder()
+			.token("test".to_owned())
+			.proxy(server.address().to_string(), true)
+			.ratelimiter(None)
+			.build();
+		let shutdown = Arc::new(AtomicBool::new(false));
+		let (file_downloader, downloads) = mpsc::channel(1000);
+		let download_tracker = Arc::new(DownloadTracker::new(&shutdown).unwrap());
+		let state = State::new(
+			Arc::new(http),
+			Arc::new(DefaultInMemoryCache::new()),
+			file_downloader,
+			Arc::new(AtomicUsize::new(0)),
+			download_tracker,
+			shutdown,
+		);
+		Self {
+			server,
+			state,
+			_downloads: downloads,
+			_data: data,
+		}
+	}
+
+	/// Adds a text channel of the guild to the cache, as the gateway does once the guild is available.
+	pub fn add_channel(&self, guild_id: u64, channel_id: u64, last_message_id: Option<u64>) {
+		let channel: Channel = serde_json::from_value(channel(guild_id, channel_id, last_message_id)).unwrap();
+		self.state.cache.update(&ChannelCreate(channel));
+	}
+
+	/// Answers GET requests to `route`, relative to the API root like `guilds/1/roles`, with `response`.
+	pub async fn serve(&self, route: &str, response: impl Respond + 'static) {
+		Mock::given(method("GET"))
+			.and(path(format!("/api/v10/{route}")))
+			.respond_with(response)
+			.mount(&self.server)
+			.await;
+	}
+
+	pub async fn serve_json(&self, route: &str, body: Value) {
+		self.serve(route, ResponseTemplate::new(200).set_body_json(body)).await;
+	}
+
+	/// Serves a message for each of `ids` in the channel, paged like the API does.
+	pub async fn serve_messages(&self, channel_id: u64, ids: &[u64]) {
+		let messages = ids.iter().map(|&id| (id, message(channel_id, id))).collect();
+		self.serve(&format!("channels/{channel_id}/messages"), paged(messages, true)).await;
+	}
+
+	/// The `after` parameter of every request made to `route` so far, in order.
+	pub async fn afters(&self, route: &str) -> Vec<Option<u64>> {
+		let path = format!("/api/v10/{route}");
+		self.server
+			.received_requests()
+			.await
+			.unwrap_or_default()
+			.iter()
+			.filter(|request| request.url.path() == path)
+			.map(|request| query(request, "after"))
+			.collect()
+	}
+}
+
+/// Answers with the items after the `after` parameter, up to `limit` of them, like the API pages through lists.
+/// `items` are paired with their ID, and sorted by it. Pages are newest first if `newest_first`, as pages of messages are.
+pub fn paged(items: Vec<(u64, Value)>, newest_first: bool) -> impl Fn(&Request) -> ResponseTemplate {
+	move |request| {
+		let after = query(request, "after").unwrap_or(0);
+		let limit = query(request, "limit").map_or(50, |limit| usize::try_from(limit).unwrap());
+		let mut page: Vec<&Value> = items
+			.iter()
+			.filter(|(id, _)| *id > after)
+			.take(limit)
+			.map(|(_, item)| item)
+			.collect();
+		if newest_first {
+			page.reverse();
+		}
+		ResponseTemplate::new(200).set_body_json(page)
+	}
+}
+
+fn query(request: &Request, name: &str) -> Option<u64> {
+	request
+		.url
+		.query_pairs()
+		.find(|(key, _)| key == name)
+		.and_then(|(_, value)| value.parse().ok())
+}
+
+/// IDs of `count` messages sent a minute apart, oldest first.
+pub fn message_ids(count: u64) -> Vec<u64> {
+	(0..count).map(|i| millis_to_snowflake(START_MS + i * 60_000)).collect()
+}
+
+/// The text canned message `id` has.
+pub fn message_content(id: u64) -> String {
+	format!("Message {id}")
+}
+
+/// `millis` the way the API writes timestamps.
+fn timestamp(millis: u64) -> String {
+	let micros = i64::try_from(millis * 1000).unwrap();
+	Timestamp::from_micros(micros).unwrap().iso_8601().to_string()
+}
+
+pub fn user(id: u64) -> Value {
+	json!({
+		"id": id.to_string(),
+		"username": format!("user{id}"),
+		"discriminator": "0",
+		"global_name": null,
+		"avatar": null,
+		"bot": false
+	})
+}
+
+pub fn message(channel_id: u64, id: u64) -> Value {
+	json!({
+		"id": id.to_string(),
+		"channel_id": channel_id.to_string(),
+		"type": 0,
+		"content": message_content(id),
+		"author": user(AUTHOR_ID),
+		"timestamp": timestamp(snowflake_to_millis(id)),
+		"edited_timestamp": null,
+		"tts": false,
+		"mention_everyone": false,
+		"mentions": [],
+		"mention_roles": [],
+		"attachments": [],
+		"embeds": [],
+		"components": [],
+		"pinned": false,
+		"flags": 0
+	})
+}
+
+pub fn channel(guild_id: u64, channel_id: u64, last_message_id: Option<u64>) -> Value {
+	json!({
+		"id": channel_id.to_string(),
+		"type": 0,
+		"guild_id": guild_id.to_string(),
+		"name": format!("channel-{channel_id}"),
+		"position": 0,
+		"permission_overwrites": [],
+		"parent_id": null,
+		"topic": null,
+		"nsfw": false,
+		"rate_limit_per_user": 0,
+		"last_message_id": last_message_id.map(|id| id.to_string()),
+		"flags": 0
+	})
+}
+
+pub fn role(id: u64, name: &str, position: i64) -> Value {
+	json!({
+		"id": id.to_string(),
+		"name": name,
+		"color": 0,
+		"colors": { "primary_color": 0, "secondary_color": null, "tertiary_color": null },
+		"hoist": false,
+		"icon": null,
+		"unicode_emoji": null,
+		"position": position,
+		"permissions": "0",
+		"managed": false,
+		"mentionable": false,
+		"flags": 0
+	})
+}
+
+pub fn member(user_id: u64, roles: &[u64]) -> Value {
+	json!({
+		"user": user(user_id),
+		"nick": null,
+		"avatar": null,
+		"banner": null,
+		"roles": roles.iter().map(u64::to_string).collect::<Vec<_>>(),
+		"joined_at": timestamp(START_MS),
+		"premium_since": null,
+		"deaf": false,
+		"mute": false,
+		"flags": 0,
+		"pending": false,
+		"communication_disabled_until": null
+	})
+}
+
+pub fn guild(guild_id: u64) -> Value {
+	json!({
+		"id": guild_id.to_string(),
+		"name": format!("Guild {guild_id}"),
+		"icon": null,
+		"splash": null,
+		"discovery_splash": null,
+		"banner": null,
+		"description": null,
+		"owner_id": AUTHOR_ID.to_string(),
+		"afk_channel_id": null,
+		"afk_timeout": 300,
+		"verification_level": 0,
+		"default_message_notifications": 0,
+		"explicit_content_filter": 0,
+		"roles": [],
+		"emojis": [],
+		"stickers": [],
+		"features": [],
+		"mfa_level": 0,
+		"system_channel_id": null,
+		"system_channel_flags": 0,
+		"rules_channel_id": null,
+		"public_updates_channel_id": null,
+		"safety_alerts_channel_id": null,
+		"vanity_url_code": null,
+		"premium_tier": 0,
+		"premium_subscription_count": 0,
+		"preferred_locale": "en-US",
+		"nsfw_level": 0,
+		"premium_progress_bar_enabled": false
+	})
+}
@@ -0,0 +1,689 @@
+use crate::messages;
+use crate::network;
+use crate::settings::{LogFormat, SETTINGS, StorageBackend};
+use crate::utils::{get_current_time_millis, to_hex};
+use anyhow::{Context, Result, bail};
+use hmac::{Hmac, Mac};
+use reqwest::header::{AUTHORIZATION, CONTENT_LENGTH};
+use reqwest::{Client, Method, RequestBuilder, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Write as _;
+use std::fs::{self, File};
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Mutex, OnceLock, PoisonError};
+use std::time::Duration;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tracing::{debug, info, warn};
+
+/// Tracks how much of every log file has been uploaded. Lives next to the logs, but is never uploaded itself.
+const OFFSETS_FILE: &str = "object_store.json";
+/// Largest log segment uploaded as a single object.
+const MAX_SEGMENT_BYTES: u64 = 64 * 1024 * 1024;
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+static OBJECT_STORE: OnceLock<ObjectStore> = OnceLock::new();
+
+/// Logs rewritten since the uploader last looked, see `log_rewritten`.
+static REWRITTEN_LOGS: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+
+/// Returns the object store, or `None` when using the local backend.
+pub fn object_store() -> Option<&'static ObjectStore> {
+	OBJECT_STORE.get()
+}
+
+/// Tells the uploader the log at `path` was changed by something other than an append, so the segments uploaded so far
+/// no longer match it. It's uploaded again from the start as a new generation, and the old segments are deleted.
+pub fn log_rewritten(path: &Path) {
+	if SETTINGS.storage.backend == StorageBackend::Local || SETTINGS.dry_run {
+		return;
+	}
+	REWRITTEN_LOGS
+		.lock()
+		.unwrap_or_else(PoisonError::into_inner)
+		.push(path.to_path_buf());
+}
+
+/// A minimal client for S3-compatible object storage (AWS S3, `MinIO`, R2, etc.), using path-style requests.
+#[derive(Debug)]
+pub struct ObjectStore {
+	client: Client,
+	endpoint: String,
+	host: String,
+}
+
+impl ObjectStore {
+	fn from_settings() -> Result<Self> {
+		let s3 = &SETTINGS.storage.s3;
+		if s3.endpoint.is_empty() || s3.bucket.is_empty() {
+			bail!("`storage.s3.endpoint` and `storage.s3.bucket` must be set to use the S3 storage backend");
+		}
+
+		let endpoint = Url::parse(&s3.endpoint).context("Invalid `storage.s3.endpoint`")?;
+		let host = match (endpoint.host_str(), endpoint.port()) {
+			(Some(host), Some(port)) => format!("{host}:{port}"),
+			(Some(host), None) => host.to_owned(),
+			(None, _) => bail!("`storage.s3.endpoint` has no host"),
+		};
+
+		let client = network::with_proxy(Client::builder().timeout(Duration::from_secs(SETTINGS.network.timeout)))?
+			.build()
+			.context("Failed to create object storage client")?;
+
+		Ok(Self {
+			client,
+			endpoint: s3.endpoint.trim_end_matches('/').to_owned(),
+			host,
+		})
+	}
+
+	/// Object key of a file in the data path. The key mirrors the path relative to `data_path`.
+	fn key(path: &Path) -> Option<String> {
+		let relative = path.strip_prefix(&SETTINGS.data_path).ok()?;
+		let mut key = SETTINGS.storage.s3.prefix.trim_matches('/').to_owned();
+		for component in relative.components() {
+			if let Component::Normal(part) = component {
+				if !key.is_empty() {
+					key.push('/');
+				}
+				key.push_str(&part.to_string_lossy());
+			}
+		}
+		Some(key)
+	}
+
+	/// Builds a request for `key`, signed with AWS Signature Version 4. `query` has to be sorted by name.
+	fn request(&self, method: &Method, key: &str, query: &[(&str, &str)]) -> Result<RequestBuilder> {
+		let s3 = &SETTINGS.storage.s3;
+		let path = format!("/{}/{}", uri_encode(&s3.bucket, false), uri_encode(key, false));
+		let query = canonical_query(query);
+		let (date, datetime) = amz_timestamps(get_current_time_millis()? / 1000);
+
+		let headers = [
+			("host", self.host.as_str()),
+			("x-amz-content-sha256", UNSIGNED_PAYLOAD),
+			("x-amz-date", datetime.as_str()),
+		];
+		let canonical_request = canonical_request(method, &path, &query, &headers, UNSIGNED_PAYLOAD);
+		let signature = signature(&s3.secret_access_key, &s3.region, &datetime, &canonical_request);
+		let authorization = format!(
+			"AWS4-HMAC-SHA256 Credential={}/{date}/{}/s3/aws4_request, SignedHeaders={}, Signature={signature}",
+			s3.access_key_id,
+			s3.region,
+			signed_headers(&headers)
+		);
+
+		let mut url = format!("{}{path}", self.endpoint);
+		if !query.is_empty() {
+			url.push('?');
+			url.push_str(&query);
+		}
+		Ok(self
+			.client
+			.request(method.clone(), url)
+			.header("x-amz-content-sha256", UNSIGNED_PAYLOAD)
+			.header("x-amz-date", datetime)
+			.header(AUTHORIZATION, authorization))
+	}
+
+	async fn put(&self, key: &str, body: Vec<u8>) -> Result<()> {
+		self.request(&Method::PUT, key, &[])?
+			.body(body)
+			.send()
+			.await
+			.with_context(|| format!("Failed to upload object {key}"))?
+			.error_for_status()
+			.with_context(|| format!("Object storage rejected upload of {key}"))?;
+		Ok(())
+	}
+
+	async fn delete(&self, key: &str) -> Result<()> {
+		self.request(&Method::DELETE, key, &[])?
+			.send()
+			.await
+			.with_context(|| format!("Failed to delete object {key}"))?
+			.error_for_status()
+			.with_context(|| format!("Object storage rejected deletion of {key}"))?;
+		Ok(())
+	}
+
+	/// Keys of every object starting with `prefix`.
+	async fn list(&self, prefix: &str) -> Result<Vec<String>> {
+		let mut keys = Vec::new();
+		let mut continuation: Option<String> = None;
+		loop {
+			let mut query = Vec::with_capacity(3);
+			if let Some(token) = &continuation {
+				query.push(("continuation-token", token.as_str()));
+			}
+			query.extend([("list-type", "2"), ("prefix", prefix)]);
+
+			let listing = self
+				.request(&Method::GET, "", &query)?
+				.send()
+				.await
+				.with_context(|| format!("Failed to list objects under {prefix}"))?
+				.error_for_status()
+				.with_context(|| format!("Object storage rejected listing objects under {prefix}"))?
+				.text()
+				.await?;
+
+			keys.extend(xml_values(&listing, "Key"));
+			continuation = xml_values(&listing, "NextContinuationToken").pop();
+			if continuation.is_none() {
+				break;
+			}
+		}
+		Ok(keys)
+	}
+
+	/// Whether the file at `path` was already moved to object storage.
+	pub async fn exists(&self, path: &Path) -> Result<bool> {
+		let key = Self::key(path).context("File is outside the data path")?;
+		let response = self
+			.request(&Method::HEAD, &key, &[])?
+			.send()
+			.await
+			.with_context(|| format!("Failed to look up object {key}"))?;
+
+		match response.status() {
+			status if status.is_success() => Ok(true),
+			StatusCode::NOT_FOUND => Ok(false),
+			status => bail!("Looking up object {key} failed with status {status}"),
+		}
+	}
+
+	/// Uploads the file at `path`, then deletes the local copy.
+	pub async fn move_file(&self, path: &Path) -> Result<()> {
+		let key = Self::key(path).context("File is outside the data path")?;
+		let file = tokio::fs::File::open(path)
+			.await
+			.with_context(|| format!("Failed to open {} for upload", path.display()))?;
+		let len = file.metadata().await?.len();
+
+		self.request(&Method::PUT, &key, &[])?
+			.header(CONTENT_LENGTH, len)
+			.body(file)
+			.send()
+			.await
+			.with_context(|| format!("Failed to upload object {key}"))?
+			.error_for_status()
+			.with_context(|| format!("Object storage rejected upload of {key}"))?;
+
+		tokio::fs::remove_file(path)
+			.await
+			.with_context(|| format!("Failed to remove uploaded file {}", path.display()))?;
+		Ok(())
+	}
+}
+
+/// Handle to the background task that uploads new log data.
+#[derive(Debug)]
+pub struct LogUploader {
+	stop: oneshot::Sender<()>,
+	task: JoinHandle<()>,
+}
+
+impl LogUploader {
+	/// Runs a last upload pass and stops the uploader.
+	/// Call this after the log writers have been flushed, so nothing written before shutdown is left out.
+	pub async fn finish(self) {
+		let _ = self.stop.send(());
+		let _ = self.task.await;
+	}
+}
+
+/// Sets up the configured storage backend.
+/// Returns the log uploader when logs are mirrored to object storage.
+pub fn init() -> Result<Option<LogUploader>> {
+	if SETTINGS.storage.backend == StorageBackend::Local || SETTINGS.dry_run {
+		return Ok(None);
+	}
+
+	let store = ObjectStore::from_settings()?;
+	let store = OBJECT_STORE.get_or_init(|| store);
+	let uploads = load_uploads()?;
+	info!(endpoint = %store.endpoint, bucket = %SETTINGS.storage.s3.bucket, "Using S3 storage backend.");
+
+	let (stop, stop_rx) = oneshot::channel();
+	let task = tokio::spawn(run_log_uploader(store, uploads, stop_rx));
+	Ok(Some(LogUploader { stop, task }))
+}
+
+/// How far a log has been uploaded.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct LogUpload {
+	/// Bumped whenever the log is rewritten, so its new contents get segments of their own.
+	generation: u32,
+	/// Bytes of the current generation uploaded so far.
+	offset: u64,
+	/// Earlier generations whose segments are yet to be deleted. They're kept until the current one has caught up.
+	#[serde(default, skip_serializing_if = "Vec::is_empty")]
+	superseded: Vec<u32>,
+}
+
+impl LogUpload {
+	/// Starts uploading the log again from its start, superseding the segments uploaded so far.
+	fn supersede(&mut self) {
+		if self.offset == 0 {
+			return;
+		}
+		self.superseded.push(self.generation);
+		self.generation += 1;
+		self.offset = 0;
+	}
+}
+
+async fn run_log_uploader(store: &'static ObjectStore, mut uploads: HashMap<String, LogUpload>, mut stop: oneshot::Receiver<()>) {
+	let mut interval = tokio::time::interval(Duration::from_secs(SETTINGS.storage.s3.upload_interval_secs.max(1)));
+	interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+	loop {
+		let stopping = tokio::select! {
+			_ = interval.tick() => false,
+			_ = &mut stop => true,
+		};
+
+		if let Err(e) = upload_new_segments(store, &mut uploads).await {
+			warn!(error = ?e, "Failed to upload log segments. Will retry on the next pass.");
+		}
+
+		if stopping {
+			break;
+		}
+	}
+	debug!("Log uploader shutting down.");
+}
+
+/// Uploads everything appended to the logs since the last pass, as new segment objects.
+/// Rewritten logs start over as a new generation, and the segments of earlier ones are deleted once it's caught up.
+async fn upload_new_segments(store: &ObjectStore, uploads: &mut HashMap<String, LogUpload>) -> Result<()> {
+	let rewritten: HashSet<String> = std::mem::take(&mut *REWRITTEN_LOGS.lock().unwrap_or_else(PoisonError::into_inner))
+		.iter()
+		.filter_map(|path| ObjectStore::key(path))
+		.collect();
+	for key in &rewritten {
+		if let Some(upload) = uploads.get_mut(key) {
+			upload.supersede();
+		}
+	}
+
+	let logs = tokio::task::spawn_blocking(list_logs).await??;
+
+	let mut result = Ok(());
+	for (path, len) in logs {
+		let Some(key) = ObjectStore::key(&path) else { continue };
+		let upload = uploads.entry(key.clone()).or_default();
+
+		if len < upload.offset {
+			warn!(
+				?path,
+				"Log file shrank since it was last uploaded. Uploading it again from the start."
+			);
+			upload.supersede();
+		}
+
+		if let Err(e) = upload_log(store, &path, &key, upload, len).await {
+			result = Err(e);
+			break;
+		}
+		if let Err(e) = delete_superseded(store, &key, upload).await {
+			result = Err(e);
+			break;
+		}
+	}
+
+	// Save progress even after a failure, so finished segments aren't uploaded twice
+	save_uploads(uploads).await?;
+	result
+}
+
+async fn upload_log(store: &ObjectStore, path: &Path, key: &str, upload: &mut LogUpload, len: u64) -> Result<()> {
+	while upload.offset < len {
+		let (path, from) = (path.to_path_buf(), upload.offset);
+		let Some(segment) = tokio::task::spawn_blocking(move || read_segment(&path, from, len)).await?? else {
+			break;
+		};
+
+		let segment_len = segment.len() as u64;
+		store.put(&segment_key(key, upload.generation, upload.offset), segment).await?;
+		upload.offset += segment_len;
+	}
+	Ok(())
+}
+
+/// Deletes the segments of the log's superseded generations.
+async fn delete_superseded(store: &ObjectStore, key: &str, upload: &mut LogUpload) -> Result<()> {
+	let (name, _) = split_key(key);
+	while let Some(&generation) = upload.superseded.first() {
+		for object in store.list(&format!("{name}.{generation}.")).await? {
+			if is_segment_of(&object, key, generation) {
+				store.delete(&object).await?;
+			}
+		}
+		upload.superseded.remove(0);
+	}
+	Ok(())
+}
+
+/// Segments are named after the log's generation and the byte offset they start at,
+/// so listing those of a generation in order and concatenating them gives back the full log.
+fn segment_key(key: &str, generation: u32, offset: u64) -> String {
+	let (name, ext) = split_key(key);
+	format!("{name}.{generation}.{offset:020}.{ext}")
+}
+
+/// Whether `object` is a segment of the given generation of the log at `key`, see `segment_key`.
+fn is_segment_of(object: &str, key: &str, generation: u32) -> bool {
+	let (name, ext) = split_key(key);
+	object
+		.strip_prefix(&format!("{name}.{generation}."))
+		.and_then(|rest| rest.strip_suffix(&format!(".{ext}")))
+		.is_some_and(|offset| offset.len() == 20 && offset.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn split_key(key: &str) -> (&str, &str) {
+	key.rsplit_once('.').unwrap_or((key, "ndjson"))
+}
+
+/// Reads complete records between `offset` and `len`, up to `MAX_SEGMENT_BYTES`.
+fn read_segment(path: &Path, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+	let mut file = File::open(path).with_context(|| format!("Failed to open log {} for upload", path.display()))?;
+	file.seek(SeekFrom::Start(offset))?;
+
+	let mut buffer = Vec::new();
+	file.take((len - offset).min(MAX_SEGMENT_BYTES)).read_to_end(&mut buffer)?;
+
+	// Leave a partially written last record for the next pass
+	let end = if path.extension().is_some_and(|ext| ext == LogFormat::Msgpack.extension()) {
+		complete_msgpack_len(&buffer)
+	} else {
+		buffer.iter().rposition(|&b| b == b'\n').map_or(0, |newline| newline + 1)
+	};
+	if end == 0 {
+		return Ok(None);
+	}
+	buffer.truncate(end);
+	Ok(Some(buffer))
+}
+
+/// Length of the complete length-framed records at the start of `buffer`.
+fn complete_msgpack_len(buffer: &[u8]) -> usize {
+	let mut pos = 0;
+	while let Some(header) = buffer.get(pos..pos + 4) {
+		let len = u32::from_le_bytes(header.try_into().expect("Slice is 4 bytes long")) as usize;
+		let next = pos + len + 8;
+		if next > buffer.len() {
+			break;
+		}
+		pos = next;
+	}
+	pos
+}
+
+/// Lists every log file in the data path along with its current length.
+fn list_logs() -> Result<Vec<(PathBuf, u64)>> {
+	let root = Path::new(&SETTINGS.data_path);
+
+	let mut dirs = vec![root.to_path_buf()];
+	for entry in fs::read_dir(root)? {
+		let path = entry?.path();
+		if path.is_dir() {
+			dirs.push(path.join("metadata"));
+			if let Some(guild) = path.file_name().and_then(|name| name.to_str()) {
+				dirs.extend(messages::message_log_dirs(guild));
+			}
+		}
+	}
+
+	let mut logs = Vec::new();
+	for dir in dirs {
+		let Ok(entries) = fs::read_dir(&dir) else { continue };
+		for entry in entries {
+			let entry = entry?;
+			let path = entry.path();
+			// The download tracker is cleared whenever the queue empties, there's nothing worth keeping in it
+			let is_log = path
+				.extension()
+				.is_some_and(|ext| ext == "ndjson" || ext == LogFormat::Msgpack.extension());
+			if !is_log || path.file_stem().is_some_and(|name| name == "downloads") {
+				continue;
+			}
+			let metadata = entry.metadata()?;
+			if metadata.is_file() {
+				logs.push((path, metadata.len()));
+			}
+		}
+	}
+	Ok(logs)
+}
+
+fn load_uploads() -> Result<HashMap<String, LogUpload>> {
+	let path = Path::new(&SETTINGS.data_path).join(OFFSETS_FILE);
+	match fs::read(&path) {
+		Ok(bytes) => sonic_rs::from_slice(&bytes).with_context(|| format!("Failed to parse {}", path.display())),
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+		Err(e) => Err(e).with_context(|| format!("Failed to read {}", path.display())),
+	}
+}
+
+async fn save_uploads(uploads: &HashMap<String, LogUpload>) -> Result<()> {
+	let path = Path::new(&SETTINGS.data_path).join(OFFSETS_FILE);
+	let temp_path = path.with_extension("json.part");
+
+	tokio::fs::write(&temp_path, sonic_rs::to_vec(uploads)?)
+		.await
+		.with_context(|| format!("Failed to write {}", temp_path.display()))?;
+	tokio::fs::rename(&temp_path, &path)
+		.await
+		.with_context(|| format!("Failed to replace {}", path.display()))?;
+	Ok(())
+}
+
+/// Query parameters the way they are signed and sent. They have to be sorted by name already.
+fn canonical_query(query: &[(&str, &str)]) -> String {
+	query
+		.iter()
+		.map(|(name, value)| format!("{}={}", uri_encode(name, true), uri_encode(value, true)))
+		.collect::<Vec<_>>()
+		.join("&")
+}
+
+/// The canonical request of AWS Signature Version 4. `headers` are the signed ones, lowercase and sorted by name.
+fn canonical_request(method: &Method, path: &str, query: &str, headers: &[(&str, &str)], payload_hash: &str) -> String {
+	let mut canonical = format!("{method}\n{path}\n{query}\n");
+	for (name, value) in headers {
+		let _ = writeln!(canonical, "{name}:{value}");
+	}
+	let _ = write!(canonical, "\n{}\n{payload_hash}", signed_headers(headers));
+	canonical
+}
+
+fn signed_headers(headers: &[(&str, &str)]) -> String {
+	headers.iter().map(|(name, _)| *name).collect::<Vec<_>>().join(";")
+}
+
+/// Signature of a canonical request made at `datetime` (`YYYYMMDDTHHMMSSZ`) to S3 in `region`, as hex.
+fn signature(secret_access_key: &str, region: &str, datetime: &str, canonical_request: &str) -> String {
+	let date = &datetime[..8];
+	let string_to_sign = format!(
+		"AWS4-HMAC-SHA256\n{datetime}\n{date}/{region}/s3/aws4_request\n{}",
+		to_hex(&Sha256::digest(canonical_request))
+	);
+
+	let mut signing_key = hmac_sha256(format!("AWS4{secret_access_key}").as_bytes(), date);
+	for part in [region, "s3", "aws4_request"] {
+		signing_key = hmac_sha256(&signing_key, part);
+	}
+	to_hex(&hmac_sha256(&signing_key, &string_to_sign))
+}
+
+/// Values of the `tag` elements in an XML response, unescaped. Enough for the flat listings S3 answers with.
+fn xml_values(xml: &str, tag: &str) -> Vec<String> {
+	let (open, close) = (format!("<{tag}>"), format!("</{tag}>"));
+	xml.split(open.as_str())
+		.skip(1)
+		.filter_map(|rest| rest.split_once(close.as_str()))
+		.map(|(value, _)| {
+			value
+				.replace("&lt;", "<")
+				.replace("&gt;", ">")
+				.replace("&quot;", "\"")
+				.replace("&apos;", "'")
+				.replace("&amp;", "&")
+		})
+		.collect()
+}
+
+fn hmac_sha256(key: &[u8], data: &str) -> Vec<u8> {
+	let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+	mac.update(data.as_bytes());
+	mac.finalize().into_bytes().to_vec()
+}
+
+/// Percent-encodes a path or query value the way request signing expects.
+/// Slashes are kept as separators in paths, but encoded in query values.
+fn uri_encode(value: &str, encode_slash: bool) -> String {
+	let mut encoded = String::with_capacity(value.len());
+	for b in value.bytes() {
+		match b {
+			b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => encoded.push(char::from(b)),
+			b'/' if !encode_slash => encoded.push('/'),
+			_ => {
+				let _ = write!(encoded, "%{b:02X}");
+			}
+		}
+	}
+	encoded
+}
+
+/// Formats Unix seconds as the `YYYYMMDD` date and `YYYYMMDDTHHMMSSZ` timestamp used in request signing.
+fn amz_timestamps(secs: u64) -> (String, String) {
+	let (days, time) = (secs / 86_400, secs % 86_400);
+
+	// Civil date from days since the Unix epoch (Howard Hinnant's algorithm)
+	let z = days + 719_468;
+	let era = z / 146_097;
+	let doe = z - era * 146_097;
+	let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+	let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+	let mp = (5 * doy + 2) / 153;
+	let day = doy - (153 * mp + 2) / 5 + 1;
+	let month = if mp < 10 { mp + 3 } else { mp - 9 };
+	let year = yoe + era * 400 + u64::from(month <= 2);
+
+	let date = format!("{year:04}{month:02}{day:02}");
+	let datetime = format!("{date}T{:02}{:02}{:02}Z", time / 3600, time % 3600 / 60, time % 60);
+	(date, datetime)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Credentials and hashes of the examples in the AWS documentation on signing S3 requests.
+	const SECRET_ACCESS_KEY: &str = "wJalrXUtnFEMI/K7MDENG/bPxRfiCYEXAMPLEKEY";
+	const DATETIME: &str = "20130524T000000Z";
+	const EMPTY_PAYLOAD: &str = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+
+	#[test]
+	fn hmac_sha256_known_answer() {
+		// RFC 4231, test case 2
+		let mac = hmac_sha256(b"Jefe", "what do ya want for nothing?");
+		assert_eq!(to_hex(&mac), "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843");
+	}
+
+	#[test]
+	fn signs_get_object_example() {
+		let headers = [
+			("host", "examplebucket.s3.amazonaws.com"),
+			("range", "bytes=0-9"),
+			("x-amz-content-sha256", EMPTY_PAYLOAD),
+			("x-amz-date", DATETIME),
+		];
+		let canonical = canonical_request(&Method::GET, "/test.txt", "", &headers, EMPTY_PAYLOAD);
+		assert_eq!(
+			to_hex(&Sha256::digest(&canonical)),
+			"7344ae5b7ee6c3e7e6b0fe0640412a37625d1fbfff95c48bbb2dc43964946972"
+		);
+		assert_eq!(
+			signature(SECRET_ACCESS_KEY, "us-east-1", DATETIME, &canonical),
+			"f0e8bdb87c964420e857bd35b5d6ed310bd44f0170aba48dd91039c6036bdb41"
+		);
+	}
+
+	#[test]
+	fn signs_list_objects_example() {
+		let query = canonical_query(&[("max-keys", "2"), ("prefix", "J")]);
+		assert_eq!(query, "max-keys=2&prefix=J");
+		let headers = [
+			("host", "examplebucket.s3.amazonaws.com"),
+			("x-amz-content-sha256", EMPTY_PAYLOAD),
+			("x-amz-date", DATETIME),
+		];
+		let canonical = canonical_request(&Method::GET, "/", &query, &headers, EMPTY_PAYLOAD);
+		assert_eq!(
+			to_hex(&Sha256::digest(&canonical)),
+			"df57d21db20da04d7fa30298dd4488ba3a2b47ca3a489c74750e0f1e7df1b9b7"
+		);
+		assert_eq!(
+			signature(SECRET_ACCESS_KEY, "us-east-1", DATETIME, &canonical),
+			"34b48302e7b5fa45bde8084f4b7868a86f0a534bc59db6670ed5711ef69dc6f7"
+		);
+	}
+
+	#[test]
+	fn uri_encode_keeps_only_unreserved_characters() {
+		assert_eq!(uri_encode("AZaz09-._~", false), "AZaz09-._~");
+		assert_eq!(
+			uri_encode("guild/messages/a b+c*.ndjson", false),
+			"guild/messages/a%20b%2Bc%2A.ndjson"
+		);
+		assert_eq!(uri_encode("guild/é", false), "guild/%C3%A9");
+		assert_eq!(uri_encode("guild/messages/", true), "guild%2Fmessages%2F");
+	}
+
+	#[test]
+	fn amz_timestamps_of_example() {
+		assert_eq!(amz_timestamps(1_369_353_600), ("20130524".to_owned(), DATETIME.to_owned()));
+		assert_eq!(amz_timestamps(951_868_799).1, "20000229T235959Z");
+	}
+
+	#[test]
+	fn segments_are_keyed_by_generation() {
+		let key = "1/messages/2.ndjson";
+		assert_eq!(segment_key(key, 0, 0), "1/messages/2.0.00000000000000000000.ndjson");
+		assert_eq!(segment_key(key, 3, 1024), "1/messages/2.3.00000000000000001024.ndjson");
+		assert!(is_segment_of(&segment_key(key, 3, 1024), key, 3));
+		assert!(!is_segment_of(&segment_key(key, 3, 1024), key, 2));
+		// Another format of the same log, and another log sharing the name's start
+		assert!(!is_segment_of(&segment_key("1/messages/2.msgpack", 3, 0), key, 3));
+		assert!(!is_segment_of(&segment_key("1/messages/2.3.ndjson", 0, 0), key, 3));
+	}
+
+	#[test]
+	fn superseding_starts_a_new_generation() {
+		let mut upload = LogUpload::default();
+		upload.supersede();
+		assert_eq!((upload.generation, upload.offset, upload.superseded.len()), (0, 0, 0));
+
+		upload.offset = 100;
+		upload.supersede();
+		upload.offset = 50;
+		upload.supersede();
+		assert_eq!((upload.generation, upload.offset), (2, 0));
+		assert_eq!(upload.superseded, [0, 1]);
+	}
+
+	#[test]
+	fn xml_values_are_unescaped() {
+		let listing = "<ListBucketResult><Contents><Key>a&amp;b.0.ndjson</Key></Contents><Contents><Key>c</Key></Contents>\
+			<NextContinuationToken>token</NextContinuationToken></ListBucketResult>";
+		assert_eq!(xml_values(listing, "Key"), ["a&b.0.ndjson", "c"]);
+		assert_eq!(xml_values(listing, "NextContinuationToken"), ["token"]);
+		assert!(xml_values(listing, "IsTruncated").is_empty());
+	}
+}
@@ -0,0 +1,195 @@
+use crate::messages;
+use crate::settings::SETTINGS;
+use crate::storage::LogEvent;
+use crate::utils::get_current_time_millis;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, IntoInnerError, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, PoisonError};
+use std::time::{Duration, SystemTime};
+use tracing::{info, instrument, warn};
+
+/// Subfolder of an asset folder holding its packs and their manifest.
+const PACKS_DIR: &str = ".packs";
+const MANIFEST: &str = "manifest.ndjson";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct PackedFile {
+	#[serde(rename = "f")]
+	filename: String,
+	#[serde(rename = "p")]
+	pack: String,
+	/// Byte offset of the file contents inside the pack.
+	#[serde(rename = "o")]
+	offset: u64,
+	#[serde(rename = "l")]
+	len: u64,
+}
+
+/// Names of packed files per asset folder. A folder is loaded from its manifest the first time it is looked up.
+static PACKED: LazyLock<Mutex<HashMap<PathBuf, HashSet<String>>>> = LazyLock::new(|| Mutex::new(HashMap::new()));
+
+/// Whether `filename` was moved from `folder` into a pack, so it must not be downloaded again.
+pub async fn is_packed(folder: &Path, filename: &str) -> bool {
+	if SETTINGS.storage.pack_assets_after_days == 0 {
+		return false;
+	}
+
+	let cached = PACKED
+		.lock()
+		.unwrap_or_else(PoisonError::into_inner)
+		.get(folder)
+		.map(|files| files.contains(filename));
+	if let Some(packed) = cached {
+		return packed;
+	}
+
+	let folder_buf = folder.to_path_buf();
+	let files = tokio::task::spawn_blocking(move || read_manifest(&folder_buf))
+		.await
+		.unwrap_or_default();
+	let packed = files.contains(filename);
+	PACKED
+		.lock()
+		.unwrap_or_else(PoisonError::into_inner)
+		.entry(folder.to_path_buf())
+		.or_insert(files);
+	packed
+}
+
+fn read_manifest(folder: &Path) -> HashSet<String> {
+	let Ok(file) = File::open(folder.join(PACKS_DIR).join(MANIFEST)) else {
+		return HashSet::new();
+	};
+
+	BufReader::new(file)
+		.lines()
+		.map_while(Result::ok)
+		.filter_map(|line| sonic_rs::from_str::<PackedFile>(&line).ok())
+		.map(|entry| entry.filename)
+		.collect()
+}
+
+/// A long-running task that periodically packs old assets and attachments into tar files.
+/// Lots of small files waste space to filesystem block rounding, and are slow to copy around.
+#[instrument(skip_all)]
+pub async fn asset_packer(shutdown: Arc<AtomicBool>) {
+	let mut interval = tokio::time::interval(Duration::from_secs(SETTINGS.storage.asset_pack_interval_secs.max(1)));
+	interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+	loop {
+		interval.tick().await;
+		if shutdown.load(Ordering::Relaxed) {
+			break;
+		}
+
+		let sd = shutdown.clone();
+		match tokio::task::spawn_blocking(move || pack_all(&sd)).await {
+			Ok(Ok(0)) => {}
+			Ok(Ok(packed)) => info!(packed, "Packed old assets."),
+			Ok(Err(e)) => warn!(error = ?e, "Asset packing failed"),
+			Err(e) => warn!(error = ?e, "Asset packer task failed"),
+		}
+	}
+}
+
+/// Packs every asset and attachment folder. Returns the number of files packed.
+fn pack_all(shutdown: &AtomicBool) -> Result<usize> {
+	let cutoff = SystemTime::now() - Duration::from_secs(SETTINGS.storage.pack_assets_after_days.saturating_mul(86_400));
+	let mut packed = 0;
+
+	for guild in fs::read_dir(&SETTINGS.data_path)? {
+		let guild = guild?.path();
+		let Some(guild_name) = guild.file_name().and_then(|name| name.to_str()) else {
+			continue;
+		};
+		let log_dirs = messages::message_log_dirs(guild_name).into_iter().map(|dir| (dir, true));
+		for (parent, holds_logs) in std::iter::once((guild.join("assets"), false)).chain(log_dirs) {
+			let Ok(folders) = fs::read_dir(&parent) else { continue };
+			for folder in folders {
+				if shutdown.load(Ordering::Relaxed) {
+					return Ok(packed);
+				}
+
+				let folder = folder?.path();
+				// Routed log folders are walked as parents instead, their logs must never be packed
+				if !folder.is_dir() || (holds_logs && messages::is_log_folder(&folder)) {
+					continue;
+				}
+				match pack_folder(&folder, cutoff) {
+					Ok(count) => packed += count,
+					Err(e) => warn!(?folder, error = ?e, "Failed to pack asset folder"),
+				}
+			}
+		}
+	}
+	Ok(packed)
+}
+
+/// Moves files last modified before `cutoff` into a new tar in the folder's `.packs`, and records where each went in the manifest.
+fn pack_folder(folder: &Path, cutoff: SystemTime) -> Result<usize> {
+	let mut candidates = Vec::new();
+	for entry in fs::read_dir(folder)? {
+		let entry = entry?;
+		let metadata = entry.metadata()?;
+		if !metadata.is_file() {
+			continue;
+		}
+
+		let Ok(name) = entry.file_name().into_string() else { continue };
+		// A download is writing into this folder. Leave it to the next run instead of racing it
+		if name.ends_with(".part") {
+			return Ok(0);
+		}
+		if metadata.len() > 0 && metadata.modified()? < cutoff {
+			candidates.push((entry.path(), name, metadata.len()));
+		}
+	}
+
+	if candidates.is_empty() {
+		return Ok(0);
+	}
+
+	let packs_dir = folder.join(PACKS_DIR);
+	fs::create_dir_all(&packs_dir)?;
+	let pack = format!("{}.tar", get_current_time_millis()?);
+
+	let mut builder = tar::Builder::new(BufWriter::new(File::create_new(packs_dir.join(&pack))?));
+	let mut entries = Vec::with_capacity(candidates.len());
+	for (path, name, len) in &candidates {
+		builder.append_path_with_name(path, name)?;
+		// Entry data is padded to 512 byte blocks and ends where the builder is now
+		let end = builder.get_mut().stream_position()?;
+		entries.push(PackedFile {
+			filename: name.clone(),
+			pack: pack.clone(),
+			offset: end - len.div_ceil(512) * 512,
+			len: *len,
+		});
+	}
+	builder.into_inner()?.into_inner().map_err(IntoInnerError::into_error)?.sync_all()?;
+
+	let timestamp = get_current_time_millis()?;
+	let mut lines = Vec::new();
+	for entry in &entries {
+		lines.extend_from_slice(&sonic_rs::to_vec(&LogEvent { timestamp, payload: entry })?);
+		lines.push(b'\n');
+	}
+	let mut manifest = OpenOptions::new().create(true).append(true).open(packs_dir.join(MANIFEST))?;
+	manifest.write_all(&lines)?;
+	manifest.sync_all()?;
+
+	// Downloads have to see the files as packed before they disappear, or they would be fetched again
+	if let Some(files) = PACKED.lock().unwrap_or_else(PoisonError::into_inner).get_mut(folder) {
+		files.extend(entries.into_iter().map(|entry| entry.filename));
+	}
+
+	for (path, _, _) in &candidates {
+		fs::remove_file(path)?;
+	}
+	Ok(candidates.len())
+}
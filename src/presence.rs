@@ -0,0 +1,123 @@
+use crate::settings::SETTINGS;
+use crate::storage::LogStore;
+use crate::utils::{create_path, get_current_time_millis, int_to_str};
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use twilight_model::gateway::presence::{ActivityType, Presence, Status};
+use twilight_model::id::Id;
+use twilight_model::id::marker::GuildMarker;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub struct PresenceEvent {
+	#[serde(rename = "i")]
+	pub user_id: u64,
+	#[serde(rename = "s")]
+	pub status: Status,
+	#[serde(rename = "a", skip_serializing_if = "Option::is_none")]
+	pub activity: Option<String>,
+	#[serde(rename = "cs", skip_serializing_if = "Option::is_none")]
+	pub custom_status: Option<String>,
+}
+
+impl PresenceEvent {
+	pub fn from_presence(p: &Presence) -> Self {
+		let activity = p.activities.iter().find(|a| a.kind != ActivityType::Custom).map(|a| a.name.clone());
+		let custom_status = p
+			.activities
+			.iter()
+			.find(|a| a.kind == ActivityType::Custom)
+			.and_then(|a| a.state.clone());
+
+		Self {
+			user_id: p.user.id().get(),
+			status: p.status,
+			activity,
+			custom_status,
+		}
+	}
+}
+
+/// Records coarse presence history for a guild.
+///
+/// Presence updates are extremely frequent, so only changes to status, activity name and custom status are kept.
+/// A user's changes are written at most once per `presence_min_interval_secs`; anything in between is collapsed into the latest state.
+#[derive(Debug)]
+pub struct PresenceArchiver {
+	log_store: LogStore,
+	/// Last written presence of each user, and when it was written.
+	recorded: HashMap<u64, (PresenceEvent, u64)>,
+	/// Changes held back because the user's last presence was written too recently.
+	pending: HashMap<u64, PresenceEvent>,
+	last_sweep: u64,
+}
+
+impl PresenceArchiver {
+	pub fn new(guild_id: Id<GuildMarker>, shutdown: &Arc<AtomicBool>) -> Result<Self> {
+		let path = create_path(&[&int_to_str!(guild_id.get(), u64), "metadata", "presences.ndjson"]);
+		Ok(Self {
+			log_store: LogStore::new(path, shutdown)?,
+			recorded: HashMap::new(),
+			pending: HashMap::new(),
+			last_sweep: 0,
+		})
+	}
+
+	pub fn process_update(&mut self, presence: &Presence) -> Result<()> {
+		let now = get_current_time_millis()?;
+		let min_interval = SETTINGS.metadata.presence_min_interval_secs * 1000;
+		let event = PresenceEvent::from_presence(presence);
+
+		match self.recorded.get(&event.user_id) {
+			// Only activity details changed (elapsed time, song progress, etc.)
+			Some((last, _)) if *last == event => {
+				self.pending.remove(&event.user_id);
+			}
+			Some((_, written_at)) if now.saturating_sub(*written_at) < min_interval => {
+				self.pending.insert(event.user_id, event);
+			}
+			_ => self.write(event, now)?,
+		}
+
+		if now.saturating_sub(self.last_sweep) >= min_interval {
+			self.sweep(now, min_interval)?;
+		}
+		Ok(())
+	}
+
+	fn write(&mut self, event: PresenceEvent, now: u64) -> Result<()> {
+		self.log_store.append(&event)?;
+		self.recorded.insert(event.user_id, (event, now));
+		Ok(())
+	}
+
+	/// Writes held back changes whose interval has elapsed.
+	fn sweep(&mut self, now: u64, min_interval: u64) -> Result<()> {
+		self.last_sweep = now;
+
+		let due: Vec<u64> = self
+			.pending
+			.keys()
+			.copied()
+			.filter(|id| self.recorded.get(id).is_none_or(|(_, at)| now.saturating_sub(*at) >= min_interval))
+			.collect();
+
+		for id in due {
+			if let Some(event) = self.pending.remove(&id) {
+				self.write(event, now)?;
+			}
+		}
+		Ok(())
+	}
+}
+
+impl Drop for PresenceArchiver {
+	fn drop(&mut self) {
+		// Don't lose the latest state of users that changed shortly before shutdown
+		for (_, event) in self.pending.drain() {
+			let _ = self.log_store.append(&event);
+		}
+	}
+}
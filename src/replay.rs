@@ -0,0 +1,138 @@
+use crate::metadata::{
+	self, BanEvent, ChannelEvent, CommandEvent, EmojiEvent, GuildEvent, IntegrationEvent, InviteEvent, MemberEvent, Replayable, RoleEvent,
+	StickerEvent,
+};
+use crate::settings::{MetadataKind, SETTINGS};
+use crate::storage::{self, LogEvent};
+use crate::utils::{create_path, int_to_str};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::io::Write;
+use twilight_model::id::Id;
+use twilight_model::id::marker::GuildMarker;
+
+/// Metadata of a guild as the archiver would hold it in memory after starting up.
+#[derive(Serialize)]
+pub struct GuildState {
+	pub guild: Option<GuildEvent>,
+	pub members: Vec<MemberEvent>,
+	pub roles: Vec<RoleEvent>,
+	pub channels: Vec<ChannelEvent>,
+	pub emojis: Vec<EmojiEvent>,
+	pub stickers: Vec<StickerEvent>,
+	pub invites: Vec<InviteEvent>,
+	pub integrations: Vec<IntegrationEvent>,
+	pub bans: Vec<BanEvent>,
+	pub commands: Vec<CommandEvent>,
+}
+
+/// Replays a guild's metadata logs the same way the archiver does on startup, and prints the resulting state to stdout as JSON.
+/// Run with `BigBrother replay <guild_id>`. Nothing is written and Discord isn't contacted, so it's safe to run next to the bot.
+///
+/// Entities are sorted by ID and keep the short field names used in the logs.
+/// Useful for checking what the archiver believes the guild looks like when a reconciliation writes unexpected events.
+pub fn run(guild_id: &str) -> Result<()> {
+	let guild_id: Id<GuildMarker> = guild_id.parse().with_context(|| format!("Invalid guild ID: {guild_id}"))?;
+	let guild = int_to_str!(guild_id.get(), u64);
+	if !create_path(&[&guild, "metadata"]).is_dir() {
+		anyhow::bail!("No metadata archived for guild {guild}");
+	}
+
+	let state = guild_state(&guild)?;
+	let mut out = std::io::stdout().lock();
+	out.write_all(&sonic_rs::to_vec_pretty(&state)?)?;
+	writeln!(out)?;
+	Ok(())
+}
+
+/// Replays every metadata log of `guild`. Kinds that were never archived come out empty.
+pub fn guild_state(guild: &str) -> Result<GuildState> {
+	Ok(GuildState {
+		guild: current::<GuildEvent>(guild, MetadataKind::Guild)?.pop(),
+		members: current(guild, MetadataKind::Members)?,
+		roles: current(guild, MetadataKind::Roles)?,
+		channels: current(guild, MetadataKind::Channels)?,
+		emojis: current(guild, MetadataKind::Emojis)?,
+		stickers: current(guild, MetadataKind::Stickers)?,
+		invites: current(guild, MetadataKind::Invites)?,
+		integrations: current(guild, MetadataKind::Integrations)?,
+		bans: current(guild, MetadataKind::Bans)?,
+		commands: current(guild, MetadataKind::Commands)?,
+	})
+}
+
+/// Entities of `kind` left after replaying its log. A missing log means none were archived.
+fn current<T: DeserializeOwned + Replayable>(guild: &str, kind: MetadataKind) -> Result<Vec<T>> {
+	let mut state: Vec<T> = metadata::replay(read_log(guild, kind, u64::MAX)?).into_values().collect();
+	state.sort_unstable_by_key(T::id);
+	Ok(state)
+}
+
+/// Payloads of the records of `kind`'s log written at or before `until` (unix millis), in order.
+fn read_log<T: DeserializeOwned>(guild: &str, kind: MetadataKind, until: u64) -> Result<Vec<T>> {
+	let path = create_path(&[
+		guild,
+		"metadata",
+		&format!("{}.{}", kind.name(), SETTINGS.storage.log_format.extension()),
+	]);
+	let mut events = Vec::new();
+	storage::for_each_record(&path, |event: LogEvent<T>| {
+		if event.timestamp <= until {
+			events.push(event.payload);
+		}
+	})
+	.with_context(|| format!("Failed to read {}", path.display()))?;
+	Ok(events)
+}
+
+/// A channel with what is below it: channels of a category, or threads of a channel.
+#[derive(Serialize)]
+pub struct ChannelNode {
+	#[serde(flatten)]
+	pub channel: ChannelEvent,
+	#[serde(skip_serializing_if = "Vec::is_empty")]
+	pub children: Vec<Self>,
+}
+
+/// Prints the guild's channel tree as it was at `at` (unix millis, or now if unset) to stdout as JSON.
+/// Run with `BigBrother tree <guild_id> [unix_millis]`. Like `replay`, it only reads the data folder.
+pub fn print_tree(guild_id: &str, at: Option<&str>) -> Result<()> {
+	let guild_id: Id<GuildMarker> = guild_id.parse().with_context(|| format!("Invalid guild ID: {guild_id}"))?;
+	let at = at.map(str::parse).transpose().context("Invalid timestamp, expected unix millis")?;
+	let guild = int_to_str!(guild_id.get(), u64);
+
+	let tree = channel_tree(&guild, at.unwrap_or(u64::MAX))?;
+	let mut out = std::io::stdout().lock();
+	out.write_all(&sonic_rs::to_vec_pretty(&tree)?)?;
+	writeln!(out)?;
+	Ok(())
+}
+
+/// Rebuilds the guild's channel tree from the channel log as of `at` (unix millis): categories and the channels outside any,
+/// each with its channels, and those with their threads. Siblings are in the order the client lists them.
+///
+/// Goes by the `ts` of the records, which is when the bot saw the change. Changes made while it was offline show up at the next catchup.
+/// A channel whose parent isn't known at that time is listed at the top, so nothing goes missing.
+pub fn channel_tree(guild: &str, at: u64) -> Result<Vec<ChannelNode>> {
+	let channels = metadata::replay(read_log::<ChannelEvent>(guild, MetadataKind::Channels, at)?);
+	let mut children: HashMap<Option<u64>, Vec<ChannelEvent>> = HashMap::new();
+	for channel in channels.values() {
+		let parent = channel.parent_id.filter(|id| channels.contains_key(id));
+		children.entry(parent).or_default().push(channel.clone());
+	}
+	Ok(take_children(&mut children, None))
+}
+
+fn take_children(children: &mut HashMap<Option<u64>, Vec<ChannelEvent>>, parent: Option<u64>) -> Vec<ChannelNode> {
+	let mut channels = children.remove(&parent).unwrap_or_default();
+	channels.sort_unstable_by_key(|c| (c.position, c.channel_id));
+	channels
+		.into_iter()
+		.map(|channel| {
+			let children = take_children(children, Some(channel.channel_id));
+			ChannelNode { channel, children }
+		})
+		.collect()
+}
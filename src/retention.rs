@@ -0,0 +1,131 @@
+use crate::dispatch::ArchiverCache;
+use crate::index;
+use crate::messages;
+use crate::settings::SETTINGS;
+use crate::storage;
+use crate::utils::{get_current_time_millis, int_to_str, snowflake_to_millis};
+use anyhow::Result;
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+use tracing::{info, instrument};
+use twilight_model::id::Id;
+use twilight_model::id::marker::GuildMarker;
+
+/// Retention period of a guild in days, or 0 if its data is kept forever.
+pub fn max_age_days(guild_id: Id<GuildMarker>) -> u64 {
+	SETTINGS
+		.retention
+		.guild_max_age_days
+		.get(&guild_id.to_string())
+		.copied()
+		.unwrap_or(SETTINGS.retention.max_age_days)
+}
+
+/// Unix timestamp in milliseconds before which the guild's messages are pruned, if it has a retention period.
+pub fn cutoff_ms(guild_id: Id<GuildMarker>) -> Result<Option<u64>> {
+	let days = max_age_days(guild_id);
+	if days == 0 {
+		return Ok(None);
+	}
+	Ok(Some(
+		get_current_time_millis()?.saturating_sub(days.saturating_mul(24 * 60 * 60 * 1000)),
+	))
+}
+
+/// The parts of a message log record needed to tell its age.
+#[derive(Deserialize)]
+struct RecordAge {
+	#[serde(rename = "ts")]
+	timestamp: u64,
+	/// Message ID, for events about a single message.
+	#[serde(rename = "i", default)]
+	message_id: Option<u64>,
+	/// Message IDs of a bulk delete.
+	#[serde(rename = "is", default)]
+	message_ids: Vec<u64>,
+}
+
+impl RecordAge {
+	/// Creation time of the newest message the record is about.
+	/// Records that aren't about a message (e.g. typing) use the time they were written.
+	fn millis(&self) -> u64 {
+		self.message_id
+			.into_iter()
+			.chain(self.message_ids.iter().copied())
+			.max()
+			.map_or(self.timestamp, snowflake_to_millis)
+	}
+}
+
+/// Drops messages older than the guild's retention period from its message logs, and deletes their attachments.
+#[instrument(skip_all, fields(guild_id = %guild_id))]
+pub async fn prune_guild(guild_id: Id<GuildMarker>, chan_archivers: &mut ArchiverCache) -> Result<()> {
+	let Some(cutoff) = cutoff_ms(guild_id)? else {
+		return Ok(());
+	};
+	if SETTINGS.dry_run {
+		info!("Dry run: skipping retention pruning.");
+		return Ok(());
+	}
+
+	// Logs are replaced on disk, so nothing may keep writing to the old files
+	chan_archivers.close_all().await;
+
+	let dirs = messages::message_log_dirs(&int_to_str!(guild_id.get(), u64));
+	let (records, attachments) = tokio::task::spawn_blocking(move || {
+		dirs.iter().try_fold((0, 0), |(records, attachments), dir| {
+			let (r, a) = prune_messages_dir(dir, cutoff)?;
+			anyhow::Ok((records + r, attachments + a))
+		})
+	})
+	.await??;
+	if records > 0 || attachments > 0 {
+		info!(records, attachments, "Pruned data past the retention period.");
+	}
+	index::prune(guild_id.get(), cutoff);
+	Ok(())
+}
+
+fn prune_messages_dir(dir: &Path, cutoff: u64) -> Result<(usize, usize)> {
+	let entries = match fs::read_dir(dir) {
+		Ok(entries) => entries,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok((0, 0)),
+		Err(e) => return Err(e.into()),
+	};
+
+	let (mut records, mut attachments) = (0, 0);
+	for entry in entries {
+		let path = entry?.path();
+		// Routed log folders are pruned on their own
+		if path.is_dir() && !messages::is_log_folder(&path) {
+			attachments += prune_attachments(&path, cutoff)?;
+		} else if storage::is_log_file(&path) {
+			records += storage::retain_records(&path, |record: RecordAge| record.millis() >= cutoff)?;
+		}
+	}
+	Ok((records, attachments))
+}
+
+/// Attachments are named `{attachment_id}_{filename}`, and the ID tells when they were uploaded.
+fn prune_attachments(folder: &Path, cutoff: u64) -> Result<usize> {
+	let mut removed = 0;
+	for entry in fs::read_dir(folder)? {
+		let entry = entry?;
+		let file_name = entry.file_name();
+		let Some(name) = file_name.to_str() else { continue };
+		// Leave in-progress downloads alone
+		if name.ends_with(".part") || !entry.file_type()?.is_file() {
+			continue;
+		}
+
+		let Some(id) = name.split_once('_').and_then(|(id, _)| id.parse::<u64>().ok()) else {
+			continue;
+		};
+		if snowflake_to_millis(id) < cutoff {
+			fs::remove_file(entry.path())?;
+			removed += 1;
+		}
+	}
+	Ok(removed)
+}
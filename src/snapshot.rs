@@ -0,0 +1,346 @@
+use crate::replay;
+use crate::settings::{LogFormat, SETTINGS};
+use crate::storage::{self, LOG_VERSION};
+use crate::utils::{create_path, get_current_time_millis, int_to_str, to_hex};
+use anyhow::{Context, Result, bail};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path};
+use tracing::{info, warn};
+use twilight_model::id::Id;
+use twilight_model::id::marker::GuildMarker;
+
+/// First entry of every snapshot, describing the rest.
+const SNAPSHOT_MANIFEST: &str = "snapshot.json";
+/// The guild's replayed metadata, as printed by `BigBrother replay`. Informational, import doesn't need it.
+const STATE: &str = "state.json";
+/// Bumped on changes to the snapshot layout that older importers would get wrong.
+const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotManifest {
+	version: u32,
+	guild_id: u64,
+	/// When the snapshot was made (unix millis).
+	created_at: u64,
+	/// Layout version and format of the logs, see `storage.log_format`.
+	log_version: u32,
+	log_format: LogFormat,
+	/// Whether the logs are encrypted, in which case importing needs the same key.
+	encrypted: bool,
+	channels: Vec<SnapshotChannel>,
+	files: Vec<SnapshotFile>,
+}
+
+/// A channel with a message log in the snapshot.
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotChannel {
+	channel_id: u64,
+	/// Current name, if the channel still exists in the replayed metadata.
+	name: Option<String>,
+	log: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotFile {
+	/// Path relative to the data folder, with `/` separators.
+	path: String,
+	len: u64,
+	sha256: String,
+}
+
+/// Writes everything archived for a guild into a single tar file at `out`: every file of its folder, plus a manifest listing them
+/// with their hashes, its channels and the replayed metadata state. Run with `BigBrother export <guild_id> <file>`.
+///
+/// Stop the bot first, as logs changing while being exported fail the import's checks.
+/// Files already moved to object storage aren't local, and so aren't included.
+pub fn export(guild_id: &str, out: &str) -> Result<()> {
+	let guild_id: Id<GuildMarker> = guild_id.parse().with_context(|| format!("Invalid guild ID: {guild_id}"))?;
+	let guild = int_to_str!(guild_id.get(), u64);
+	let guild_dir = create_path(&[&guild]);
+	if !guild_dir.is_dir() {
+		bail!("Nothing archived for guild {guild}");
+	}
+
+	let mut files = Vec::new();
+	collect_files(&guild_dir, &guild, &mut files)?;
+	files.sort_unstable_by(|a, b| a.path.cmp(&b.path));
+
+	let state = replay::guild_state(&guild)?;
+	let names: HashMap<u64, &str> = state.channels.iter().map(|c| (c.channel_id, c.name.as_str())).collect();
+	let messages_prefix = format!("{guild}/messages/");
+	let channels = files
+		.iter()
+		.filter_map(|file| {
+			let log = Path::new(file.path.strip_prefix(&messages_prefix)?);
+			// Only channel logs, named after the channel
+			let channel_id = log.file_stem().and_then(|s| s.to_str()).and_then(|s| s.parse().ok())?;
+			storage::is_log_file(log).then(|| SnapshotChannel {
+				channel_id,
+				name: names.get(&channel_id).map(|&n| n.to_owned()),
+				log: file.path.clone(),
+			})
+		})
+		.collect();
+
+	let manifest = SnapshotManifest {
+		version: SNAPSHOT_VERSION,
+		guild_id: guild_id.get(),
+		created_at: get_current_time_millis()?,
+		log_version: LOG_VERSION,
+		log_format: SETTINGS.storage.log_format,
+		encrypted: SETTINGS.storage.encrypt_logs,
+		channels,
+		files,
+	};
+
+	let mut builder = tar::Builder::new(BufWriter::new(
+		File::create(out).with_context(|| format!("Failed to create {out}"))?,
+	));
+	append_bytes(&mut builder, SNAPSHOT_MANIFEST, &sonic_rs::to_vec_pretty(&manifest)?)?;
+	append_bytes(&mut builder, STATE, &sonic_rs::to_vec_pretty(&state)?)?;
+	for file in &manifest.files {
+		builder
+			.append_path_with_name(create_path(&[&file.path]), &file.path)
+			.with_context(|| format!("Failed to add {} to the snapshot", file.path))?;
+	}
+	builder
+		.into_inner()?
+		.into_inner()
+		.map_err(io::IntoInnerError::into_error)?
+		.sync_all()?;
+
+	let bytes: u64 = manifest.files.iter().map(|f| f.len).sum();
+	info!(files = manifest.files.len(), bytes, "Exported guild {guild} to {out}.");
+	Ok(())
+}
+
+/// Restores a snapshot made by `export` into the data folder. Run with `BigBrother import <file>`.
+/// The guild must not be archived there yet. Every file is checked against the manifest,
+/// and nothing is put in place unless all of them match.
+pub fn import(path: &str) -> Result<()> {
+	let mut archive = tar::Archive::new(BufReader::new(File::open(path).with_context(|| format!("Failed to open {path}"))?));
+	let mut entries = archive.entries()?;
+
+	let mut first = entries.next().context("Snapshot is empty")??;
+	if first.path()?.as_os_str() != SNAPSHOT_MANIFEST {
+		bail!("Not a snapshot: {path} doesn't start with {SNAPSHOT_MANIFEST}");
+	}
+	let mut raw = Vec::new();
+	first.read_to_end(&mut raw)?;
+	let manifest: SnapshotManifest = sonic_rs::from_slice(&raw).context("Invalid snapshot manifest")?;
+	if manifest.version > SNAPSHOT_VERSION {
+		bail!(
+			"Snapshot version {} is newer than this build supports ({SNAPSHOT_VERSION})",
+			manifest.version
+		);
+	}
+	if manifest.log_version > LOG_VERSION {
+		bail!(
+			"Snapshot logs are version {}, newer than this build supports ({LOG_VERSION})",
+			manifest.log_version
+		);
+	}
+	if manifest.log_format != SETTINGS.storage.log_format {
+		warn!(
+			snapshot = ?manifest.log_format,
+			"The snapshot's logs are in a different format than `storage.log_format`. New logs will be started next to them."
+		);
+	}
+	if manifest.encrypted {
+		warn!("The snapshot's logs are encrypted. They can only be read with the key they were written with.");
+	}
+
+	let guild = int_to_str!(manifest.guild_id, u64);
+	let guild_dir = create_path(&[&guild]);
+	if guild_dir.exists() {
+		bail!(
+			"{} already exists. Import into a data folder that doesn't have this guild yet.",
+			guild_dir.display()
+		);
+	}
+
+	// Unpacked next to it and renamed once verified, so a failed import leaves nothing behind
+	let staging = create_path(&[&format!("{guild}.import")]);
+	let _ = fs::remove_dir_all(&staging);
+	if let Err(e) = unpack(entries, &manifest, &guild, &staging) {
+		let _ = fs::remove_dir_all(&staging);
+		return Err(e);
+	}
+	fs::rename(&staging, &guild_dir).with_context(|| format!("Failed to move the import into {}", guild_dir.display()))?;
+
+	info!(
+		files = manifest.files.len(),
+		channels = manifest.channels.len(),
+		"Imported guild {guild} from {path}."
+	);
+	Ok(())
+}
+
+/// Extracts the files of a snapshot into `staging`, checking each against the manifest.
+fn unpack<R: Read>(entries: tar::Entries<'_, R>, manifest: &SnapshotManifest, guild: &str, staging: &Path) -> Result<()> {
+	let mut expected: HashMap<&str, &SnapshotFile> = manifest.files.iter().map(|f| (f.path.as_str(), f)).collect();
+	let prefix = format!("{guild}/");
+
+	for entry in entries {
+		let mut entry = entry?;
+		let name = entry.path()?.to_string_lossy().into_owned();
+		if name == STATE {
+			continue;
+		}
+		let Some(file) = expected.remove(name.as_str()) else {
+			bail!("Snapshot contains {name}, which isn't in its manifest");
+		};
+		// Manifest paths are trusted no more than the entries, so nothing can be written outside the guild folder
+		let relative = name.strip_prefix(&prefix).map(Path::new);
+		let Some(relative) = relative.filter(|p| p.components().all(|c| matches!(c, Component::Normal(_)))) else {
+			bail!("Snapshot contains {name}, which is outside the guild's folder");
+		};
+
+		let dest = staging.join(relative);
+		if let Some(parent) = dest.parent() {
+			fs::create_dir_all(parent)?;
+		}
+		let mut out = BufWriter::new(File::create(&dest)?);
+		let (len, sha256) = copy_hashed(&mut entry, &mut out)?;
+		out.into_inner().map_err(io::IntoInnerError::into_error)?.sync_all()?;
+		if len != file.len || sha256 != file.sha256 {
+			bail!("{name} doesn't match the manifest. The snapshot is corrupt, or the guild was archived to while exporting.");
+		}
+	}
+
+	if let Some(missing) = expected.keys().next() {
+		bail!("Snapshot is missing {} files, like {missing}", expected.len());
+	}
+	Ok(())
+}
+
+/// Lists every file under `dir` with its size and hash. Paths start with `name`, standing for `dir`.
+fn collect_files(dir: &Path, name: &str, files: &mut Vec<SnapshotFile>) -> Result<()> {
+	for entry in fs::read_dir(dir).with_context(|| format!("Failed to read {}", dir.display()))? {
+		let entry = entry?;
+		let Some(file_name) = entry.file_name().to_str().map(|n| format!("{name}/{n}")) else {
+			warn!(path = ?entry.path(), "Skipping file with a non UTF-8 name.");
+			continue;
+		};
+		if entry.file_type()?.is_dir() {
+			collect_files(&entry.path(), &file_name, files)?;
+		} else {
+			let (len, sha256) = copy_hashed(&mut File::open(entry.path())?, &mut io::sink())?;
+			files.push(SnapshotFile {
+				path: file_name,
+				len,
+				sha256,
+			});
+		}
+	}
+	Ok(())
+}
+
+/// Copies `reader` into `writer`, returning the number of bytes and their SHA-256 in hex.
+fn copy_hashed(reader: &mut impl Read, writer: &mut impl Write) -> io::Result<(u64, String)> {
+	let mut hasher = Sha256::new();
+	let mut buf = vec![0; 64 * 1024];
+	let mut len = 0;
+	loop {
+		let n = reader.read(&mut buf)?;
+		if n == 0 {
+			break;
+		}
+		hasher.update(&buf[..n]);
+		writer.write_all(&buf[..n])?;
+		len += n as u64;
+	}
+	Ok((len, to_hex(&hasher.finalize())))
+}
+
+fn append_bytes(builder: &mut tar::Builder<impl Write>, name: &str, bytes: &[u8]) -> Result<()> {
+	let mut header = tar::Header::new_gnu();
+	header.set_size(bytes.len() as u64);
+	header.set_mode(0o644);
+	header.set_mtime(get_current_time_millis()? / 1000);
+	header.set_cksum();
+	builder.append_data(&mut header, name, bytes)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::settings::TestData;
+	use std::collections::BTreeMap;
+	use std::path::PathBuf;
+
+	const ATTACHMENT: &[u8] = b"attachment bytes";
+
+	/// Archives a file of each kind for `guild`, and returns the guild's folder.
+	fn archive_guild(guild: &str) -> PathBuf {
+		let files: [(&[&str], &[u8]); 4] = [
+			(&["messages", "2001.ndjson"], b"{\"v\":1}\n{\"t\":\"c\",\"i\":5001}\n"),
+			(&["messages", "2001", "11_image.png"], ATTACHMENT),
+			(&["assets", "avatars", "4001.png"], b"avatar bytes"),
+			(&["metadata", "stats.ndjson"], b"{\"v\":1}\n{\"mc\":3}\n"),
+		];
+		for (path, contents) in files {
+			let path = create_path(&[[guild].as_slice(), path].concat());
+			fs::create_dir_all(path.parent().unwrap()).unwrap();
+			fs::write(path, contents).unwrap();
+		}
+		create_path(&[guild])
+	}
+
+	/// Every file under `dir`, by its path relative to `dir`, with its contents.
+	fn tree(dir: &Path) -> BTreeMap<PathBuf, Vec<u8>> {
+		let mut files = BTreeMap::new();
+		let mut dirs = vec![dir.to_path_buf()];
+		while let Some(current) = dirs.pop() {
+			for entry in fs::read_dir(current).unwrap() {
+				let path = entry.unwrap().path();
+				if path.is_dir() {
+					dirs.push(path);
+				} else {
+					files.insert(path.strip_prefix(dir).unwrap().to_path_buf(), fs::read(&path).unwrap());
+				}
+			}
+		}
+		files
+	}
+
+	#[test]
+	fn import_restores_an_exported_guild() {
+		let _data = TestData::hold();
+		let guild_dir = archive_guild("1672");
+		let snapshot = create_path(&["1672.tar"]).to_string_lossy().into_owned();
+		export("1672", &snapshot).unwrap();
+		let exported = tree(&guild_dir);
+		fs::remove_dir_all(&guild_dir).unwrap();
+
+		import(&snapshot).unwrap();
+		assert_eq!(tree(&guild_dir), exported);
+		assert!(!create_path(&["1672.import"]).exists());
+	}
+
+	#[test]
+	fn import_refuses_files_that_dont_match_the_manifest() {
+		let _data = TestData::hold();
+		let guild_dir = archive_guild("1673");
+		let snapshot = create_path(&["1673.tar"]).to_string_lossy().into_owned();
+		export("1673", &snapshot).unwrap();
+		fs::remove_dir_all(&guild_dir).unwrap();
+
+		// Same length, so only the hash can tell
+		let mut bytes = fs::read(&snapshot).unwrap();
+		let at = bytes.windows(ATTACHMENT.len()).position(|window| window == ATTACHMENT).unwrap();
+		bytes[at] ^= 1;
+		fs::write(&snapshot, bytes).unwrap();
+
+		let error = import(&snapshot).unwrap_err();
+		assert!(error.to_string().contains("doesn't match the manifest"), "{error:#}");
+		// Nothing is left behind
+		assert!(!guild_dir.exists());
+		assert!(!create_path(&["1673.import"]).exists());
+	}
+}
@@ -1,16 +1,27 @@
-use crate::settings::SETTINGS;
-use crate::utils::get_current_time_millis;
-use anyhow::{Context, Result};
+use crate::alerts;
+use crate::error::ArchiveError;
+use crate::object_store;
+use crate::settings::{LogFormat, SETTINGS, TimestampSource};
+use crate::utils::{get_current_time_millis, to_hex};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{Context, Result, bail};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
 use serde::{Serialize, de::DeserializeOwned};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::collections::hash_map::{DefaultHasher, Entry};
 use std::fs::File as StdFile;
 use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
 use std::io::{BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, PoisonError};
+use std::time::{Duration, Instant};
 use tokio::sync::{mpsc, oneshot};
-use tracing::{debug, error};
+use tracing::{debug, error, info, trace, warn};
 
 #[derive(serde::Deserialize, serde::Serialize, Debug, Clone)]
 pub struct LogEvent<T> {
@@ -20,164 +31,624 @@ pub struct LogEvent<T> {
 	pub payload: T,
 }
 
+/// Version of the log record layout, bumped on changes that older readers would misread.
+/// Written in a header record at the start of every new log.
+pub const LOG_VERSION: u32 = 1;
+
+/// First record of a log, telling which layout version its records follow.
+/// Logs created before headers existed have none. They are version 0, laid out the same as version 1.
+/// It has no `ts`, so readers of `LogEvent`s skip it like any record they can't parse.
+#[derive(serde::Deserialize, serde::Serialize, Debug)]
+struct LogHeader {
+	#[serde(rename = "lv")]
+	version: u32,
+}
+
 enum StoreCommand {
 	Write(Vec<u8>),
 	Flush(oneshot::Sender<()>),
+	/// Flushes and opens the log again, after it was replaced on disk. See `LogStore::rewrite`.
+	Reopen(oneshot::Sender<()>),
 }
 
-#[derive(Debug, Clone)]
-pub struct LogStore {
-	path: PathBuf,
-	tx: mpsc::UnboundedSender<StoreCommand>,
+/// Process-wide counters of the log writers. Only kept when `storage.metrics_interval_secs` is set.
+struct WriterMetrics {
+	/// Commands sent to a writer that it hasn't handled yet.
+	queued: AtomicUsize,
+	peak_queued: AtomicUsize,
+	/// Writes that reached a log file since the last report, and how long they took.
+	io_ops: AtomicU64,
+	io_micros: AtomicU64,
+	max_io_micros: AtomicU64,
 }
 
-impl LogStore {
-	pub fn new(path: PathBuf, shutdown: &Arc<AtomicBool>) -> Result<Self> {
-		let (tx, mut rx) = mpsc::unbounded_channel();
-		let path_clone = path.clone();
-		let shutdown_for_writer = shutdown.clone();
+static METRICS: WriterMetrics = WriterMetrics {
+	queued: AtomicUsize::new(0),
+	peak_queued: AtomicUsize::new(0),
+	io_ops: AtomicU64::new(0),
+	io_micros: AtomicU64::new(0),
+	max_io_micros: AtomicU64::new(0),
+};
 
-		if let Some(parent) = path.parent() {
-			fs::create_dir_all(parent).with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+/// A queue this deep that keeps growing between reports means the disk can't keep up.
+const QUEUE_WARN_DEPTH: usize = 10_000;
+
+fn metrics_enabled() -> bool {
+	SETTINGS.storage.metrics_interval_secs > 0
+}
+
+fn note_queued() {
+	if metrics_enabled() {
+		let queued = METRICS.queued.fetch_add(1, Ordering::Relaxed) + 1;
+		METRICS.peak_queued.fetch_max(queued, Ordering::Relaxed);
+	}
+}
+
+fn note_dequeued() {
+	if metrics_enabled() {
+		METRICS.queued.fetch_sub(1, Ordering::Relaxed);
+	}
+}
+
+/// A log file that records how long writes to it take. Buffered, only the writes that reach the file are timed,
+/// rather than every copy into the buffer.
+#[derive(Debug)]
+struct TimedFile(File);
+
+impl Write for TimedFile {
+	fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+		timed_io(|| self.0.write(buf))
+	}
+
+	fn flush(&mut self) -> std::io::Result<()> {
+		self.0.flush()
+	}
+}
+
+/// Runs a disk operation, recording how long it took.
+fn timed_io<T>(op: impl FnOnce() -> T) -> T {
+	if !metrics_enabled() {
+		return op();
+	}
+	let start = Instant::now();
+	let result = op();
+	let micros = u64::try_from(start.elapsed().as_micros()).unwrap_or(u64::MAX);
+	METRICS.io_ops.fetch_add(1, Ordering::Relaxed);
+	METRICS.io_micros.fetch_add(micros, Ordering::Relaxed);
+	METRICS.max_io_micros.fetch_max(micros, Ordering::Relaxed);
+	result
+}
+
+/// A long-running task that periodically logs the log writers' queue depth and disk latency.
+/// The writers handle commands one at a time, so a growing queue is the first sign of the disk being the bottleneck.
+pub async fn log_writer_metrics(shutdown: Arc<AtomicBool>) {
+	let mut interval = tokio::time::interval(Duration::from_secs(SETTINGS.storage.metrics_interval_secs));
+	interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+	interval.tick().await;
+	let mut previous_queued = 0;
+
+	loop {
+		interval.tick().await;
+		if shutdown.load(Ordering::Relaxed) {
+			break;
 		}
 
-		tokio::task::spawn_blocking(move || {
-			let file = match OpenOptions::new().create(true).append(true).open(&path_clone) {
-				Ok(f) => f,
-				Err(e) => {
-					error!("FATAL: LogStore writer failed to open file {:?}: {}", path_clone, e);
-					return;
-				}
-			};
+		let queued = METRICS.queued.load(Ordering::Relaxed);
+		let peak_queued = METRICS.peak_queued.swap(queued, Ordering::Relaxed);
+		let io_ops = METRICS.io_ops.swap(0, Ordering::Relaxed);
+		let avg_io_us = METRICS.io_micros.swap(0, Ordering::Relaxed) / io_ops.max(1);
+		let max_io_us = METRICS.max_io_micros.swap(0, Ordering::Relaxed);
 
-			let mut writer = BufWriter::with_capacity(64 * 1024, file);
+		if queued >= QUEUE_WARN_DEPTH && queued > previous_queued {
+			warn!(queued, peak_queued, io_ops, avg_io_us, max_io_us, "Log writers are falling behind.");
+		} else {
+			info!(queued, peak_queued, io_ops, avg_io_us, max_io_us, "Log writer stats.");
+		}
+		previous_queued = queued;
+	}
+}
 
-			let mut scratchpad = Vec::with_capacity(8 * 1024);
+/// Every dedicated writer, so they can be flushed all at once. Weak, so closed logs don't linger.
+static DEDICATED_WRITERS: Mutex<Vec<mpsc::WeakUnboundedSender<StoreCommand>>> = Mutex::new(Vec::new());
 
-			loop {
-				if shutdown_for_writer.load(Ordering::Relaxed) {
-					while let Ok(cmd) = rx.try_recv() {
-						if let StoreCommand::Write(b) = cmd {
-							let _ = writer.write_all(&b);
-							let _ = writer.write_all(b"\n");
-						}
+/// Flushes every open log to disk, waiting until done. Records written after this starts may not be included.
+/// Used before reading logs from outside the bot, so they don't end in the middle of a buffered write.
+pub async fn flush_all() {
+	let dedicated: Vec<_> = {
+		let mut writers = DEDICATED_WRITERS.lock().unwrap_or_else(PoisonError::into_inner);
+		writers.retain(|tx| tx.strong_count() > 0);
+		writers.iter().filter_map(mpsc::WeakUnboundedSender::upgrade).collect()
+	};
+	for tx in dedicated {
+		let (respond_to, rx) = oneshot::channel();
+		note_queued();
+		if tx.send(StoreCommand::Flush(respond_to)).is_ok() {
+			let _ = rx.await;
+		} else {
+			note_dequeued();
+		}
+	}
+
+	let Some(pool) = WRITER_POOL.get() else { return };
+	for tx in pool {
+		let (respond_to, rx) = oneshot::channel();
+		if tx.send(PoolCommand::FlushAll(Some(respond_to))).is_ok() {
+			let _ = rx.await;
+		}
+	}
+}
+
+/// Spawns a writer task that owns a single log file.
+fn spawn_dedicated_writer(path: PathBuf, file: File, shutdown: &Arc<AtomicBool>) -> mpsc::UnboundedSender<StoreCommand> {
+	let (tx, mut rx) = mpsc::unbounded_channel();
+	let shutdown_for_writer = shutdown.clone();
+
+	tokio::task::spawn_blocking(move || {
+		let mut writer = BufWriter::with_capacity(64 * 1024, TimedFile(file));
+
+		let mut scratchpad = Vec::with_capacity(8 * 1024);
+		// Writes since the last flush
+		let mut unflushed = 0;
+
+		loop {
+			if shutdown_for_writer.load(Ordering::Relaxed) {
+				while let Ok(cmd) = rx.try_recv() {
+					note_dequeued();
+					if let StoreCommand::Write(b) = cmd {
+						let _ = writer.write_all(&b);
 					}
-					let _ = writer.flush();
-					break;
 				}
+				let _ = writer.flush();
+				break;
+			}
+
+			let Some(cmd) = rx.blocking_recv() else { break };
+			note_dequeued();
+
+			match cmd {
+				StoreCommand::Write(bytes) => {
+					scratchpad.clear();
+					scratchpad.extend_from_slice(&bytes);
+					unflushed += 1;
 
-				let Some(cmd) = rx.blocking_recv() else { break };
-
-				match cmd {
-					StoreCommand::Write(bytes) => {
-						scratchpad.clear();
-						scratchpad.extend_from_slice(&bytes);
-						scratchpad.push(b'\n');
-
-						let mut count = 0;
-						while count < 500 && scratchpad.len() < 1024 * 1024 {
-							match rx.try_recv() {
-								Ok(StoreCommand::Write(b)) => {
-									scratchpad.extend_from_slice(&b);
-									scratchpad.push(b'\n');
-									count += 1;
+					let mut count = 0;
+					while count < 500 && scratchpad.len() < 1024 * 1024 && !flush_due(unflushed) {
+						match rx.try_recv() {
+							Ok(StoreCommand::Write(b)) => {
+								note_dequeued();
+								scratchpad.extend_from_slice(&b);
+								count += 1;
+								unflushed += 1;
+							}
+							Ok(StoreCommand::Flush(tx)) => {
+								note_dequeued();
+								if let Err(e) = writer.write_all(&scratchpad) {
+									error!("Failed to write to log: {}", e);
+									alerts::check_disk_full(&e);
+								}
+								if let Err(e) = writer.flush() {
+									error!("Failed to flush log: {}", e);
+									alerts::check_disk_full(&e);
 								}
-								Ok(StoreCommand::Flush(tx)) => {
-									if let Err(e) = writer.write_all(&scratchpad) {
-										error!("Failed to write to log: {}", e);
-									}
-									if let Err(e) = writer.flush() {
-										error!("Failed to flush log: {}", e);
-									}
-									let _ = tx.send(());
-									scratchpad.clear();
-									break;
+								let _ = tx.send(());
+								scratchpad.clear();
+								unflushed = 0;
+								break;
+							}
+							Ok(StoreCommand::Reopen(tx)) => {
+								note_dequeued();
+								if let Err(e) = writer.write_all(&scratchpad) {
+									error!("Failed to write to log: {}", e);
+									alerts::check_disk_full(&e);
 								}
-								Err(_) => break,
+								reopen(&mut writer, &path);
+								let _ = tx.send(());
+								scratchpad.clear();
+								unflushed = 0;
+								break;
 							}
+							Err(_) => break,
 						}
+					}
 
-						if !scratchpad.is_empty()
-							&& let Err(e) = writer.write_all(&scratchpad)
-						{
-							error!("Failed to write to log: {}", e);
-						}
+					if !scratchpad.is_empty()
+						&& let Err(e) = writer.write_all(&scratchpad)
+					{
+						error!("Failed to write to log: {}", e);
+						alerts::check_disk_full(&e);
 					}
-					StoreCommand::Flush(respond_to) => {
+					if flush_due(unflushed) {
 						if let Err(e) = writer.flush() {
 							error!("Failed to flush log: {}", e);
+							alerts::check_disk_full(&e);
+						}
+						unflushed = 0;
+					}
+				}
+				StoreCommand::Flush(respond_to) => {
+					if let Err(e) = writer.flush() {
+						error!("Failed to flush log: {}", e);
+						alerts::check_disk_full(&e);
+					}
+					unflushed = 0;
+					let _ = respond_to.send(());
+				}
+				StoreCommand::Reopen(respond_to) => {
+					reopen(&mut writer, &path);
+					unflushed = 0;
+					let _ = respond_to.send(());
+				}
+			}
+		}
+		debug!("LogStore writer for {:?} shutting down.", path);
+	});
+
+	// Weak, so the writer exits once every LogStore handle is dropped
+	let tx_flush = tx.downgrade();
+	DEDICATED_WRITERS
+		.lock()
+		.unwrap_or_else(PoisonError::into_inner)
+		.push(tx.downgrade());
+	spawn_autoflush(shutdown, move || {
+		let (oneshot_tx, _) = oneshot::channel();
+		tx_flush
+			.upgrade()
+			.is_some_and(|tx| tx.send(StoreCommand::Flush(oneshot_tx)).is_ok())
+	});
+
+	tx
+}
+
+/// Flushes `writer` and swaps it for a new handle to `path`, which was replaced by a new file.
+fn reopen(writer: &mut BufWriter<TimedFile>, path: &Path) {
+	if let Err(e) = writer.flush() {
+		error!("Failed to flush log: {}", e);
+		alerts::check_disk_full(&e);
+	}
+	match OpenOptions::new().create(true).append(true).open(path) {
+		Ok(file) => *writer = BufWriter::with_capacity(64 * 1024, TimedFile(file)),
+		Err(e) => error!("LogStore writer failed to reopen file {:?}: {}", path, e),
+	}
+}
+
+/// Whether `unflushed` writes reach `storage.flush_every_n`, so the file must be flushed now instead of waiting for autoflush.
+fn flush_due(unflushed: usize) -> bool {
+	SETTINGS.storage.flush_every_n > 0 && unflushed >= SETTINGS.storage.flush_every_n
+}
+
+/// Periodically calls `send_flush` until it returns false or shutdown is requested.
+fn spawn_autoflush(shutdown: &Arc<AtomicBool>, send_flush: impl Fn() -> bool + Send + 'static) {
+	let shutdown_flush = shutdown.clone();
+	tokio::spawn(async move {
+		let mut interval = tokio::time::interval(Duration::from_millis(SETTINGS.storage.autoflush_interval_ms));
+		interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+
+		loop {
+			interval.tick().await;
+			if shutdown_flush.load(Ordering::Relaxed) {
+				break;
+			}
+			if !send_flush() {
+				break;
+			}
+		}
+	});
+}
+
+enum PoolCommand {
+	Store(Arc<Path>, StoreCommand),
+	/// Flushes every open file, answering once done if asked to.
+	FlushAll(Option<oneshot::Sender<()>>),
+	/// A handle to the file was created. Every log of the same path shares its open file.
+	Open(Arc<Path>),
+	/// A handle to the file was dropped. The file is closed once none are left.
+	Close(Arc<Path>),
+	Shutdown(oneshot::Sender<()>),
+}
+
+static WRITER_POOL: OnceLock<Vec<mpsc::UnboundedSender<PoolCommand>>> = OnceLock::new();
+
+/// Returns the shared writer threads, starting them on first use.
+fn writer_pool(shutdown: &Arc<AtomicBool>) -> &'static [mpsc::UnboundedSender<PoolCommand>] {
+	WRITER_POOL.get_or_init(|| {
+		(0..SETTINGS.storage.writer_threads)
+			.map(|i| {
+				let (tx, rx) = mpsc::unbounded_channel();
+				std::thread::Builder::new()
+					.name(format!("log-writer-{i}"))
+					.spawn(move || pooled_writer_loop(rx))
+					.expect("Failed to spawn log writer thread");
+
+				let tx_flush = tx.clone();
+				spawn_autoflush(shutdown, move || tx_flush.send(PoolCommand::FlushAll(None)).is_ok());
+				tx
+			})
+			.collect()
+	})
+}
+
+/// Flushes every file held by the shared writer threads and stops them.
+/// Does nothing if the pool was never started.
+pub async fn close_writer_pool() {
+	let Some(pool) = WRITER_POOL.get() else { return };
+	for tx in pool {
+		let (respond_to, rx) = oneshot::channel();
+		if tx.send(PoolCommand::Shutdown(respond_to)).is_ok() {
+			let _ = rx.await;
+		}
+	}
+}
+
+fn pooled_writer_loop(mut rx: mpsc::UnboundedReceiver<PoolCommand>) {
+	// Open files, with the number of writes since their last flush
+	let mut writers: HashMap<Arc<Path>, (BufWriter<TimedFile>, usize)> = HashMap::new();
+	// Handles to each file. A path always goes to the same thread, so its opens and closes arrive in order
+	let mut handles: HashMap<Arc<Path>, usize> = HashMap::new();
+
+	let flush = |path: &Path, (writer, unflushed): &mut (BufWriter<TimedFile>, usize)| {
+		if let Err(e) = writer.flush() {
+			error!("Failed to flush log {:?}: {}", path, e);
+			alerts::check_disk_full(&e);
+		}
+		*unflushed = 0;
+	};
+
+	while let Some(cmd) = rx.blocking_recv() {
+		match cmd {
+			PoolCommand::Store(path, StoreCommand::Write(bytes)) => {
+				note_dequeued();
+				let file = match writers.entry(path.clone()) {
+					Entry::Occupied(e) => e.into_mut(),
+					Entry::Vacant(e) => match OpenOptions::new().create(true).append(true).open(e.key()) {
+						Ok(f) => e.insert((BufWriter::with_capacity(64 * 1024, TimedFile(f)), 0)),
+						Err(err) => {
+							error!("LogStore writer failed to open file {:?}: {}", e.key(), err);
+							continue;
 						}
-						let _ = respond_to.send(());
+					},
+				};
+				if let Err(e) = file.0.write_all(&bytes) {
+					error!("Failed to write to log: {}", e);
+					alerts::check_disk_full(&e);
+				}
+				file.1 += 1;
+				if flush_due(file.1) {
+					flush(&path, file);
+				}
+			}
+			PoolCommand::Store(path, StoreCommand::Flush(respond_to)) => {
+				note_dequeued();
+				if let Some(file) = writers.get_mut(&path) {
+					flush(&path, file);
+				}
+				let _ = respond_to.send(());
+			}
+			PoolCommand::Store(path, StoreCommand::Reopen(respond_to)) => {
+				note_dequeued();
+				// Opened again on the next write
+				if let Some(mut file) = writers.remove(&path) {
+					flush(&path, &mut file);
+				}
+				let _ = respond_to.send(());
+			}
+			PoolCommand::FlushAll(respond_to) => {
+				for (path, file) in &mut writers {
+					flush(path, file);
+				}
+				if let Some(respond_to) = respond_to {
+					let _ = respond_to.send(());
+				}
+			}
+			PoolCommand::Open(path) => {
+				*handles.entry(path).or_default() += 1;
+			}
+			PoolCommand::Close(path) => {
+				if let Entry::Occupied(mut count) = handles.entry(path.clone()) {
+					*count.get_mut() -= 1;
+					if *count.get() > 0 {
+						continue;
 					}
+					count.remove();
+				}
+				if let Some(mut file) = writers.remove(&path) {
+					flush(&path, &mut file);
 				}
 			}
-			debug!("LogStore writer for {:?} shutting down.", path_clone);
-		});
-
-		let tx_flush = tx.clone();
-		let shutdown_flush = shutdown.clone();
-		tokio::spawn(async move {
-			let mut interval = tokio::time::interval(Duration::from_millis(SETTINGS.storage.autoflush_interval_ms));
-			interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-
-			loop {
-				interval.tick().await;
-				if shutdown_flush.load(Ordering::Relaxed) {
-					break;
+			PoolCommand::Shutdown(respond_to) => {
+				for (path, file) in &mut writers {
+					flush(path, file);
 				}
+				let _ = respond_to.send(());
+				break;
+			}
+		}
+	}
+	debug!("Pooled log writer shutting down.");
+}
+
+/// A log file multiplexed onto one of the shared writer threads.
+/// The file is closed on the writer thread once the last handle to it is dropped, from whichever `LogStore` of the path.
+#[derive(Debug)]
+struct PooledWriter {
+	path: Arc<Path>,
+	tx: mpsc::UnboundedSender<PoolCommand>,
+}
+
+impl PooledWriter {
+	fn new(path: PathBuf, shutdown: &Arc<AtomicBool>) -> Self {
+		let pool = writer_pool(shutdown);
+		let mut hasher = DefaultHasher::new();
+		path.hash(&mut hasher);
+		#[allow(clippy::cast_possible_truncation)]
+		let tx = pool[hasher.finish() as usize % pool.len()].clone();
 
-				let (oneshot_tx, _) = oneshot::channel();
-				if tx_flush.send(StoreCommand::Flush(oneshot_tx)).is_err() {
-					break;
+		let path: Arc<Path> = path.into();
+		let _ = tx.send(PoolCommand::Open(path.clone()));
+		Self { path, tx }
+	}
+}
+
+impl Drop for PooledWriter {
+	fn drop(&mut self) {
+		let _ = self.tx.send(PoolCommand::Close(self.path.clone()));
+	}
+}
+
+#[derive(Debug, Clone)]
+enum Writer {
+	/// A writer task owned by this log file alone.
+	Dedicated(mpsc::UnboundedSender<StoreCommand>),
+	/// A file multiplexed onto the shared writer pool.
+	Pooled(Arc<PooledWriter>),
+	/// Writes nothing, see `dry_run` in the settings.
+	DryRun(Arc<Path>),
+}
+
+/// Bytes of log records that would have been written in a dry run.
+static DRY_RUN_BYTES: AtomicU64 = AtomicU64::new(0);
+
+pub fn dry_run_log_bytes() -> u64 {
+	DRY_RUN_BYTES.load(Ordering::Relaxed)
+}
+
+impl Writer {
+	fn send(&self, cmd: StoreCommand) -> Result<(), ArchiveError> {
+		if let Self::DryRun(path) = self {
+			match cmd {
+				StoreCommand::Write(bytes) => {
+					trace!(?path, bytes = bytes.len(), "Dry run: skipping log write.");
+					DRY_RUN_BYTES.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+				}
+				StoreCommand::Flush(respond_to) | StoreCommand::Reopen(respond_to) => {
+					let _ = respond_to.send(());
 				}
 			}
-		});
+			return Ok(());
+		}
 
-		Ok(Self { path, tx })
+		// Counted before sending, so the writer can't handle it first
+		note_queued();
+		let sent = match self {
+			Self::Dedicated(tx) => tx.send(cmd).is_ok(),
+			Self::Pooled(w) => w.tx.send(PoolCommand::Store(w.path.clone(), cmd)).is_ok(),
+			Self::DryRun(_) => unreachable!("Handled above"),
+		};
+		if !sent {
+			note_dequeued();
+			return Err(ArchiveError::WriterClosed);
+		}
+		Ok(())
 	}
+}
 
-	pub fn append<P: Serialize + Sync + Send + 'static>(&self, payload: &P) -> Result<()> {
+#[derive(Debug, Clone)]
+pub struct LogStore {
+	path: PathBuf,
+	writer: Writer,
+}
+
+impl LogStore {
+	pub fn new(path: PathBuf, shutdown: &Arc<AtomicBool>) -> Result<Self> {
+		let path = path.with_extension(SETTINGS.storage.log_format.extension());
+		if SETTINGS.dry_run {
+			// Existing logs are still read, but nothing is created or repaired
+			let writer = Writer::DryRun(path.as_path().into());
+			return Ok(Self { path, writer });
+		}
+		if let Some(parent) = path.parent() {
+			fs::create_dir_all(parent).with_context(|| format!("Failed to create directory: {}", parent.display()))?;
+		}
+		match SETTINGS.storage.log_format {
+			LogFormat::Ndjson => repair_ndjson_tail(&path),
+			LogFormat::Msgpack => repair_msgpack_tail(&path),
+		}
+		.with_context(|| format!("Failed to check log file: {}", path.display()))?;
+		// Appending to a log of a newer layout would leave it unreadable by both versions
+		let version = read_version(&path)?;
+		if let Some(version) = version {
+			check_version(version, &path)?;
+		}
+
+		// Opened here rather than on the writer, so a file that can't be written fails loudly instead of dropping every write
+		let mut file = OpenOptions::new()
+			.create(true)
+			.append(true)
+			.open(&path)
+			.with_context(|| format!("Failed to open log file: {}", path.display()))?;
+		if version.is_none() {
+			file.write_all(&SETTINGS.storage.log_format.header()?)
+				.with_context(|| format!("Failed to write log header: {}", path.display()))?;
+		}
+
+		let writer = if SETTINGS.storage.writer_threads == 0 {
+			Writer::Dedicated(spawn_dedicated_writer(path.clone(), file, shutdown))
+		} else {
+			// The pool opens its own handle, and reopens the file whenever it was closed in between
+			drop(file);
+			Writer::Pooled(Arc::new(PooledWriter::new(path.clone(), shutdown)))
+		};
+
+		Ok(Self { path, writer })
+	}
+
+	pub fn append<P: Serialize + Sync + Send + 'static>(&self, payload: &P) -> Result<(), ArchiveError> {
+		self.append_at(payload, None)
+	}
+
+	/// Like `append`, with `event_time` being when the event happened on Discord, in unix millis.
+	/// It's used as the record's `ts` when `storage.timestamp_source` is `event`.
+	pub fn append_at<P: Serialize + Sync + Send + 'static>(&self, payload: &P, event_time: Option<u64>) -> Result<(), ArchiveError> {
 		let event = LogEvent {
-			timestamp: get_current_time_millis()?,
+			timestamp: record_time(event_time, get_current_time_millis()?),
 			payload,
 		};
-		let json_bytes = sonic_rs::to_vec(&event)?;
+		let mut record = Vec::new();
+		SETTINGS.storage.log_format.encode_into(&mut record, &event)?;
 
-		self.tx
-			.send(StoreCommand::Write(json_bytes))
-			.map_err(|_| anyhow::anyhow!("LogStore writer is closed"))?;
-		Ok(())
+		self.writer.send(StoreCommand::Write(record))
 	}
 
-	pub fn append_bulk<P: Serialize + Sync + Send + 'static>(&self, payloads: Vec<P>) -> Result<()> {
-		if payloads.is_empty() {
-			return Ok(());
-		}
+	pub fn append_bulk<P: Serialize + Sync + Send + 'static>(&self, payloads: Vec<P>) -> Result<(), ArchiveError> {
+		self.append_bulk_at(payloads.into_iter().map(|p| (p, None)))
+	}
+
+	/// Like `append_bulk`, with each payload paired with its event time, as in `append_at`.
+	pub fn append_bulk_at<P: Serialize + Sync + Send + 'static>(
+		&self,
+		payloads: impl IntoIterator<Item = (P, Option<u64>)>,
+	) -> Result<(), ArchiveError> {
 		let ts = get_current_time_millis()?;
 
 		let mut buffer = Vec::new();
 
-		for p in payloads {
-			let event = LogEvent { timestamp: ts, payload: p };
-			let bytes = sonic_rs::to_vec(&event)?;
-			buffer.extend_from_slice(&bytes);
-			buffer.push(b'\n');
+		for (p, event_time) in payloads {
+			let event = LogEvent {
+				timestamp: record_time(event_time, ts),
+				payload: p,
+			};
+			SETTINGS.storage.log_format.encode_into(&mut buffer, &event)?;
 		}
 
-		if let Some(last) = buffer.last()
-			&& *last == b'\n'
-		{
-			buffer.pop();
+		if buffer.is_empty() {
+			return Ok(());
 		}
+		self.writer.send(StoreCommand::Write(buffer))
+	}
 
-		self.tx
-			.send(StoreCommand::Write(buffer))
-			.map_err(|_| anyhow::anyhow!("LogStore writer is closed"))?;
+	/// Reads every record of the log. Records that can't be parsed as `P` are skipped,
+	/// including a last one cut short by a crash or by copying the file while it was written.
+	pub async fn read_all<P: DeserializeOwned + Send + 'static>(&self) -> Result<Vec<LogEvent<P>>> {
+		self.read_events(false).await
+	}
 
-		Ok(())
+	/// Like `read_all`, but fails if any record other than the header can't be parsed as `P`.
+	/// For reads whose result replaces the log, which would otherwise lose those records for good.
+	/// A torn last record still isn't read, `LogStore::new` cuts it off before anything is appended.
+	pub async fn read_all_exact<P: DeserializeOwned + Send + 'static>(&self) -> Result<Vec<LogEvent<P>>> {
+		self.read_events(true).await
 	}
 
-	pub async fn read_all<P: DeserializeOwned + Send + 'static>(&self) -> Result<Vec<LogEvent<P>>> {
+	async fn read_events<P: DeserializeOwned + Send + 'static>(&self, exact: bool) -> Result<Vec<LogEvent<P>>> {
 		let path = self.path.clone();
 		tokio::task::spawn_blocking(move || {
 			let file = match File::open(&path) {
@@ -186,18 +657,39 @@ impl LogStore {
 				Err(e) => return Err(e.into()),
 			};
 
+			let format = SETTINGS.storage.log_format;
 			let mut reader = BufReader::new(file);
-			let mut line_buf = String::new();
 			let mut events = Vec::new();
+			let mut index = 0usize;
+			let mut push = |record: &[u8]| -> Result<()> {
+				let first = index == 0;
+				index += 1;
+				match decode_record::<LogEvent<P>>(format, record) {
+					Some(e) => events.push(e),
+					None if exact && !(first && decode_record::<LogHeader>(format, record).is_some()) => {
+						bail!("Record {index} of {} can't be read", path.display());
+					}
+					None => {}
+				}
+				Ok(())
+			};
 
-			while reader.read_line(&mut line_buf)? > 0 {
-				let trimmed = line_buf.trim();
-				if !trimmed.is_empty()
-					&& let Ok(e) = sonic_rs::from_str::<LogEvent<P>>(trimmed)
-				{
-					events.push(e);
+			match format {
+				LogFormat::Ndjson => {
+					let mut line_buf = Vec::new();
+					while reader.read_until(b'\n', &mut line_buf)? > 0 {
+						let trimmed = line_buf.trim_ascii();
+						if !trimmed.is_empty() {
+							push(trimmed)?;
+						}
+						line_buf.clear();
+					}
+				}
+				LogFormat::Msgpack => {
+					while let Some(record) = read_msgpack_record(&mut reader)? {
+						push(&record)?;
+					}
 				}
-				line_buf.clear();
 			}
 			Ok(events)
 		})
@@ -206,6 +698,17 @@ impl LogStore {
 
 	/// Scans the log file backwards, deserializing entries into `P`.
 	pub async fn scan_last<P, R, F>(&self, scanner: F) -> Result<Option<R>>
+	where
+		P: DeserializeOwned + Send + 'static,
+		R: Send + 'static,
+		F: Fn(P) -> Option<R> + Send + Sync + 'static,
+	{
+		Ok(self.scan_last_since(None, scanner).await?.found)
+	}
+
+	/// Like `scan_last`, but stops at `since` instead of the start of the log, as what comes before it was scanned already.
+	/// `since` is ignored if the log was rewritten or cut short since it was taken, and the whole log is scanned.
+	pub async fn scan_last_since<P, R, F>(&self, since: Option<LogMark>, scanner: F) -> Result<TailScan<R>>
 	where
 		P: DeserializeOwned + Send + 'static,
 		R: Send + 'static,
@@ -216,96 +719,933 @@ impl LogStore {
 		let path = self.path.clone();
 
 		tokio::task::spawn_blocking(move || {
-			const CAP: usize = 64 * 1024;
-
 			let mut file = match StdFile::open(&path) {
 				Ok(f) => f,
-				Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+				Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+					return Ok(TailScan {
+						found: None,
+						resumed: false,
+						end: None,
+					});
+				}
 				Err(e) => return Err(e).context("Failed to open log file for scanning"),
 			};
 
 			let file_len = file.metadata()?.len();
-			if file_len == 0 {
-				return Ok(None);
+			let mut floor = None;
+			if let Some(since) = since
+				&& since.offset <= file_len
+				&& read_mark(&mut file, since.offset)?.before == since.before
+			{
+				floor = Some(since.offset);
 			}
 
-			let mut buffer = vec![0u8; CAP];
+			let found = if file_len == 0 {
+				None
+			} else {
+				match SETTINGS.storage.log_format {
+					LogFormat::Ndjson => scan_last_ndjson(&mut file, floor.unwrap_or(0), file_len, &scanner)?,
+					LogFormat::Msgpack => scan_last_msgpack(&mut file, floor.unwrap_or(0), file_len, &scanner)?,
+				}
+			};
+			let end = if ends_with_complete_record(&mut file, file_len)? {
+				Some(read_mark(&mut file, file_len)?)
+			} else {
+				None
+			};
+			Ok(TailScan {
+				found,
+				resumed: floor.is_some(),
+				end,
+			})
+		})
+		.await?
+	}
 
-			let mut line_suffix: Vec<u8> = Vec::new();
-			let mut file_pos = file_len;
+	pub async fn flush(&self) -> Result<()> {
+		let (tx, rx) = oneshot::channel();
+		self.writer.send(StoreCommand::Flush(tx))?;
+		rx.await.context("Flush responder dropped")?;
+		Ok(())
+	}
 
-			while file_pos > 0 {
-				#[allow(clippy::cast_possible_truncation)]
-				let read_len = std::cmp::min(file_pos, CAP as u64) as usize;
-				file_pos -= read_len as u64;
+	pub async fn clear(&self) -> Result<()> {
+		self.flush().await?;
+		if SETTINGS.dry_run {
+			return Ok(());
+		}
 
-				file.seek(SeekFrom::Start(file_pos))?;
-				file.read_exact(&mut buffer[..read_len])?;
+		let path = self.path.clone();
+		let header = SETTINGS.storage.log_format.header()?;
+		tokio::task::spawn_blocking(move || OpenOptions::new().write(true).truncate(true).open(path)?.write_all(&header)).await??;
+		object_store::log_rewritten(&self.path);
 
-				let window = &buffer[..read_len];
-				let mut cursor = read_len;
+		Ok(())
+	}
 
-				// Scan backwards for newlines within the chunk
-				while let Some(newline_idx) = window[..cursor].iter().rposition(|&b| b == b'\n') {
-					let line_slice = &window[newline_idx + 1..cursor];
+	/// Replaces the log's records with `events`, after flushing what is buffered.
+	/// They are written to a new file that is then moved over the log, so a crash leaves either the old or the new log,
+	/// and the writer is told to open it again. Records appended while this runs may be lost, so callers have to hold them back.
+	pub async fn rewrite<P: Serialize>(&self, events: &[LogEvent<P>]) -> Result<()> {
+		self.flush().await?;
+		if SETTINGS.dry_run {
+			return Ok(());
+		}
 
-					let bytes_to_parse = if line_suffix.is_empty() {
-						line_slice
-					} else {
-						&[line_slice, &line_suffix].concat()
-					};
+		let mut buffer = SETTINGS.storage.log_format.header()?;
+		for event in events {
+			SETTINGS.storage.log_format.encode_into(&mut buffer, event)?;
+		}
+		let path = self.path.clone();
+		tokio::task::spawn_blocking(move || -> Result<()> {
+			let temp_path = path.with_extension("rewrite");
+			let mut file = File::create(&temp_path)?;
+			file.write_all(&buffer)?;
+			file.sync_all()?;
+			fs::rename(&temp_path, &path).with_context(|| format!("Failed to replace log file: {}", path.display()))
+		})
+		.await??;
+		object_store::log_rewritten(&self.path);
 
-					if !bytes_to_parse.is_empty()
-						&& let Ok(entry) = sonic_rs::from_slice::<P>(bytes_to_parse)
-						&& let Some(found) = scanner(entry)
-					{
-						return Ok(Some(found));
-					}
+		let (tx, rx) = oneshot::channel();
+		self.writer.send(StoreCommand::Reopen(tx))?;
+		rx.await.context("Reopen responder dropped")?;
+		Ok(())
+	}
 
-					line_suffix.clear();
-					cursor = newline_idx;
-				}
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+}
+
+/// A position in a log, for `LogStore::scan_last_since`.
+/// Keeps the bytes just before it, to tell whether the log is still the same up to there.
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct LogMark {
+	#[serde(rename = "o")]
+	offset: u64,
+	#[serde(rename = "b")]
+	before: String,
+}
+
+/// What `LogStore::scan_last_since` found.
+pub struct TailScan<R> {
+	pub found: Option<R>,
+	/// Whether the scan stopped at `since`. If not, the whole log was scanned, and what was known up to `since` no longer holds.
+	pub resumed: bool,
+	/// The end of the log, to scan from next time.
+	/// `None` if the log doesn't end with a complete record, as a write was under way or cut short.
+	pub end: Option<LogMark>,
+}
+
+/// How many bytes before its offset a `LogMark` keeps.
+const MARK_BYTES: u64 = 32;
+
+fn read_mark(file: &mut StdFile, offset: u64) -> std::io::Result<LogMark> {
+	let start = offset.saturating_sub(MARK_BYTES);
+	#[allow(clippy::cast_possible_truncation)]
+	let mut bytes = vec![0u8; (offset - start) as usize];
+	file.seek(SeekFrom::Start(start))?;
+	file.read_exact(&mut bytes)?;
+	Ok(LogMark {
+		offset,
+		before: to_hex(&bytes),
+	})
+}
 
-				if cursor > 0 {
-					let prefix = &window[0..cursor];
-					let mut new_suffix = Vec::with_capacity(prefix.len() + line_suffix.len());
-					new_suffix.extend_from_slice(prefix);
-					new_suffix.append(&mut line_suffix);
-					line_suffix = new_suffix;
+/// Whether the last record of the log is whole.
+fn ends_with_complete_record(file: &mut StdFile, file_len: u64) -> std::io::Result<bool> {
+	if file_len == 0 {
+		return Ok(true);
+	}
+	match SETTINGS.storage.log_format {
+		LogFormat::Ndjson => {
+			let mut last = [0u8; 1];
+			file.seek(SeekFrom::Start(file_len - 1))?;
+			file.read_exact(&mut last)?;
+			Ok(last[0] == b'\n')
+		}
+		LogFormat::Msgpack => {
+			if file_len < 8 {
+				return Ok(false);
+			}
+			let mut trailer = [0u8; 4];
+			file.seek(SeekFrom::Start(file_len - 4))?;
+			file.read_exact(&mut trailer)?;
+			let Some(start) = file_len.checked_sub(u64::from(u32::from_le_bytes(trailer)) + 8) else {
+				return Ok(false);
+			};
+			let mut header = [0u8; 4];
+			file.seek(SeekFrom::Start(start))?;
+			file.read_exact(&mut header)?;
+			Ok(header == trailer)
+		}
+	}
+}
+
+/// The `ts` to write for an event, depending on `storage.timestamp_source`.
+fn record_time(event_time: Option<u64>, received: u64) -> u64 {
+	match (SETTINGS.storage.timestamp_source, event_time) {
+		(TimestampSource::Event, Some(t)) => t,
+		_ => received,
+	}
+}
+
+/// Reads every record of the log at `path` in order, in whichever format its extension says.
+/// Records that can't be parsed as `P` are skipped, including a torn last one. See `LogStore::read_all`.
+/// Meant for offline tools. Unlike `LogStore::read_all`, it doesn't collect the whole log in memory.
+pub fn for_each_record<P: DeserializeOwned>(path: &Path, mut f: impl FnMut(P)) -> Result<()> {
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+		Err(e) => return Err(e.into()),
+	};
+	let mut reader = BufReader::new(file);
+	let mut first = true;
+
+	if path.extension().is_some_and(|ext| ext == LogFormat::Msgpack.extension()) {
+		while let Some(record) = read_msgpack_record(&mut reader)? {
+			if std::mem::take(&mut first) {
+				check_header(LogFormat::Msgpack, &record, path)?;
+			}
+			if let Some(entry) = decode_record::<P>(LogFormat::Msgpack, &record) {
+				f(entry);
+			}
+		}
+	} else {
+		let mut line = Vec::new();
+		while reader.read_until(b'\n', &mut line)? > 0 {
+			if std::mem::take(&mut first) {
+				check_header(LogFormat::Ndjson, line.trim_ascii(), path)?;
+			}
+			if let Some(entry) = decode_record::<P>(LogFormat::Ndjson, line.trim_ascii()) {
+				f(entry);
+			}
+			line.clear();
+		}
+	}
+	Ok(())
+}
+
+/// Layout version of the log at `path`, in whichever format its extension says. `None` if the log is empty or doesn't exist.
+fn read_version(path: &Path) -> Result<Option<u32>> {
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+		Err(e) => return Err(e.into()),
+	};
+	let mut reader = BufReader::new(file);
+	let (format, first) = if path.extension().is_some_and(|ext| ext == LogFormat::Msgpack.extension()) {
+		(LogFormat::Msgpack, read_msgpack_record(&mut reader)?)
+	} else {
+		let mut line = Vec::new();
+		let first = (reader.read_until(b'\n', &mut line)? > 0).then(|| line.trim_ascii().to_vec());
+		(LogFormat::Ndjson, first)
+	};
+	Ok(first.map(|record| decode_record::<LogHeader>(format, &record).map_or(0, |header| header.version)))
+}
+
+/// Fails if the first record of a log is a header of a layout newer than this build reads.
+fn check_header(format: LogFormat, record: &[u8], path: &Path) -> Result<()> {
+	match decode_record::<LogHeader>(format, record) {
+		Some(header) => check_version(header.version, path),
+		None => Ok(()),
+	}
+}
+
+fn check_version(version: u32, path: &Path) -> Result<()> {
+	if version > LOG_VERSION {
+		bail!(
+			"{} was written by a newer version of the archiver (log version {version}, this one reads up to {LOG_VERSION})",
+			path.display()
+		);
+	}
+	Ok(())
+}
+
+/// Rewrites the log at `path`, keeping only the records `keep` returns true for. Records that can't be parsed as `P` are kept.
+/// Every `LogStore` of the file has to be flushed and dropped first, or whatever they write next goes to the replaced file.
+/// Returns the number of dropped records.
+pub fn retain_records<P: DeserializeOwned>(path: &Path, mut keep: impl FnMut(P) -> bool) -> Result<usize> {
+	let file = match File::open(path) {
+		Ok(f) => f,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(0),
+		Err(e) => return Err(e.into()),
+	};
+	let mut reader = BufReader::new(file);
+
+	let temp_path = path.with_extension("retain");
+	let mut writer = BufWriter::new(File::create(&temp_path)?);
+	let mut dropped = 0;
+
+	if path.extension().is_some_and(|ext| ext == LogFormat::Msgpack.extension()) {
+		while let Some(record) = read_msgpack_record(&mut reader)? {
+			let kept = decode_record::<P>(LogFormat::Msgpack, &record).is_none_or(&mut keep);
+			if kept {
+				let len = u32::try_from(record.len())?.to_le_bytes();
+				writer.write_all(&len)?;
+				writer.write_all(&record)?;
+				writer.write_all(&len)?;
+			} else {
+				dropped += 1;
+			}
+		}
+	} else {
+		let mut line = Vec::new();
+		while reader.read_until(b'\n', &mut line)? > 0 {
+			let kept = decode_record::<P>(LogFormat::Ndjson, line.trim_ascii()).is_none_or(&mut keep);
+			if kept {
+				writer.write_all(&line)?;
+			} else {
+				dropped += 1;
+			}
+			line.clear();
+		}
+	}
+
+	let file = writer.into_inner().map_err(std::io::IntoInnerError::into_error)?;
+	if dropped == 0 {
+		drop(file);
+		fs::remove_file(&temp_path)?;
+		return Ok(0);
+	}
+	file.sync_all()?;
+	fs::rename(&temp_path, path).with_context(|| format!("Failed to replace log file: {}", path.display()))?;
+	object_store::log_rewritten(path);
+	Ok(dropped)
+}
+
+/// Whether `path` is a log in any of the formats, so logs written before switching formats are found too.
+pub fn is_log_file(path: &Path) -> bool {
+	path.extension()
+		.is_some_and(|ext| ext == LogFormat::Ndjson.extension() || ext == LogFormat::Msgpack.extension())
+}
+
+impl LogFormat {
+	pub const fn extension(self) -> &'static str {
+		match self {
+			Self::Ndjson => "ndjson",
+			Self::Msgpack => "msgpack",
+		}
+	}
+
+	/// The header record of a new log. Never encrypted, so the version of any log can be read.
+	fn header(self) -> Result<Vec<u8>, ArchiveError> {
+		let header = LogHeader { version: LOG_VERSION };
+		let mut buffer = Vec::new();
+		match self {
+			Self::Ndjson => {
+				buffer.extend_from_slice(&sonic_rs::to_vec(&header)?);
+				buffer.push(b'\n');
+			}
+			Self::Msgpack => {
+				let record = rmp_serde::to_vec_named(&header)?;
+				let len = u32::try_from(record.len())
+					.map_err(|_| ArchiveError::Serialize("Log header is too large".into()))?
+					.to_le_bytes();
+				buffer.extend_from_slice(&len);
+				buffer.extend_from_slice(&record);
+				buffer.extend_from_slice(&len);
+			}
+		}
+		Ok(buffer)
+	}
+
+	/// Encodes `value` as a single framed record at the end of `buffer`, encrypted if `storage.encrypt_logs` is on.
+	fn encode_into<T: Serialize>(self, buffer: &mut Vec<u8>, value: &T) -> Result<(), ArchiveError> {
+		match self {
+			Self::Ndjson => {
+				let json = sonic_rs::to_vec(value)?;
+				if let Some(cipher) = cipher() {
+					buffer.push(ENCRYPTED_NDJSON_MARKER);
+					buffer.extend_from_slice(BASE64.encode(seal(cipher, &json)?).as_bytes());
+				} else {
+					buffer.extend_from_slice(&json);
+				}
+				buffer.push(b'\n');
+			}
+			Self::Msgpack => {
+				// The length is written both before and after the record, so the log can be read in either direction
+				let start = buffer.len();
+				buffer.extend_from_slice(&[0; 4]);
+				if let Some(cipher) = cipher() {
+					buffer.push(ENCRYPTED_MSGPACK_MARKER);
+					buffer.extend_from_slice(&seal(cipher, &rmp_serde::to_vec_named(value)?)?);
+				} else {
+					rmp_serde::encode::write_named(buffer, value)?;
 				}
+				// Readers take longer records for corruption, see `read_msgpack_record`
+				let Some(len) = u32::try_from(buffer.len() - start - 4)
+					.ok()
+					.filter(|&len| len <= MAX_MSGPACK_RECORD_LEN)
+				else {
+					buffer.truncate(start);
+					return Err(ArchiveError::Serialize("Log record is too large".into()));
+				};
+				let len = len.to_le_bytes();
+				buffer[start..start + 4].copy_from_slice(&len);
+				buffer.extend_from_slice(&len);
 			}
+		}
+		Ok(())
+	}
+}
+
+/// Parses a record's bytes, without framing, as `P`. Encrypted records are decrypted first.
+fn decode_record<P: DeserializeOwned>(format: LogFormat, record: &[u8]) -> Option<P> {
+	let record = open_record(format, record)?;
+	match format {
+		LogFormat::Ndjson => sonic_rs::from_slice(&record).ok(),
+		LogFormat::Msgpack => rmp_serde::from_slice(&record).ok(),
+	}
+}
+
+/// First byte of an encrypted MessagePack record. 0xc1 is never used by MessagePack itself.
+const ENCRYPTED_MSGPACK_MARKER: u8 = 0xc1;
+/// First byte of an encrypted ndjson line, which can't start a JSON object.
+const ENCRYPTED_NDJSON_MARKER: u8 = b'!';
+const NONCE_LEN: usize = 12;
+const ENCRYPTION_KEY_ENV: &str = "BIGBROTHER_ENCRYPTION_KEY";
+
+static CIPHER: OnceLock<Option<Aes256Gcm>> = OnceLock::new();
+static DECRYPT_FAILED: AtomicBool = AtomicBool::new(false);
+
+/// Loads the log encryption key if `storage.encrypt_logs` is on, so a missing or malformed key fails at startup.
+pub fn init_encryption() -> Result<()> {
+	if CIPHER.get().is_none() {
+		let _ = CIPHER.set(load_cipher()?);
+	}
+	Ok(())
+}
+
+fn cipher() -> Option<&'static Aes256Gcm> {
+	CIPHER
+		.get_or_init(|| load_cipher().expect("Failed to load the log encryption key"))
+		.as_ref()
+}
+
+/// Reads the key from the environment variable, or else from `storage.encryption_key_file`. Either holds 64 hex characters.
+fn load_cipher() -> Result<Option<Aes256Gcm>> {
+	if !SETTINGS.storage.encrypt_logs {
+		return Ok(None);
+	}
+
+	let key = match std::env::var(ENCRYPTION_KEY_ENV) {
+		Ok(key) => key,
+		Err(_) if !SETTINGS.storage.encryption_key_file.is_empty() => fs::read_to_string(&SETTINGS.storage.encryption_key_file)
+			.with_context(|| format!("Failed to read {}", SETTINGS.storage.encryption_key_file))?,
+		Err(_) => bail!("`storage.encrypt_logs` is on, but there is no key. Set {ENCRYPTION_KEY_ENV} or `storage.encryption_key_file`"),
+	};
+	let key = key.trim();
+	if key.len() != 64 {
+		bail!("The log encryption key must be 64 hex characters (32 bytes), got {}", key.len());
+	}
+
+	let mut bytes = [0u8; 32];
+	for (byte, pair) in bytes.iter_mut().zip(key.as_bytes().chunks_exact(2)) {
+		*byte = std::str::from_utf8(pair)
+			.ok()
+			.and_then(|pair| u8::from_str_radix(pair, 16).ok())
+			.context("The log encryption key isn't valid hex")?;
+	}
+	Ok(Some(Aes256Gcm::new(&bytes.into())))
+}
+
+/// Encrypts a record with a random nonce, which is stored in front of the ciphertext.
+fn seal(cipher: &Aes256Gcm, plaintext: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+	let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+	let ciphertext = cipher
+		.encrypt(&nonce, plaintext)
+		.map_err(|_| ArchiveError::Serialize("Failed to encrypt log record".into()))?;
+	let mut sealed = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+	sealed.extend_from_slice(&nonce);
+	sealed.extend_from_slice(&ciphertext);
+	Ok(sealed)
+}
+
+/// Decrypts a record if it is encrypted. Plaintext records, like ones written before encryption was turned on, are returned as they are.
+/// Returns `None` for an encrypted record that can't be decrypted, which is then skipped like a corrupt one.
+fn open_record(format: LogFormat, record: &[u8]) -> Option<Cow<'_, [u8]>> {
+	let sealed = match (format, record.split_first()) {
+		(LogFormat::Ndjson, Some((&ENCRYPTED_NDJSON_MARKER, encoded))) => Cow::Owned(BASE64.decode(encoded).ok()?),
+		(LogFormat::Msgpack, Some((&ENCRYPTED_MSGPACK_MARKER, sealed))) => Cow::Borrowed(sealed),
+		_ => return Some(Cow::Borrowed(record)),
+	};
+
+	let opened = cipher().filter(|_| sealed.len() >= NONCE_LEN).and_then(|cipher| {
+		let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+		cipher.decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+	});
+	if opened.is_none() && !DECRYPT_FAILED.swap(true, Ordering::Relaxed) {
+		error!("Found encrypted log records that can't be decrypted. Check that `storage.encrypt_logs` is on with the right key.");
+	}
+	opened.map(Cow::Owned)
+}
+
+/// Records longer than this are treated as corruption rather than allocated, and refused when written.
+const MAX_MSGPACK_RECORD_LEN: u32 = 64 * 1024 * 1024;
+
+/// Reads the next length-framed record, without its framing.
+/// Returns `None` at the end of the log, or at a record that was cut short (e.g. by a crash).
+fn read_msgpack_record(reader: &mut impl Read) -> std::io::Result<Option<Vec<u8>>> {
+	let mut len = [0u8; 4];
+	match reader.read_exact(&mut len) {
+		Ok(()) => {}
+		Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+		Err(e) => return Err(e),
+	}
+
+	let len = u32::from_le_bytes(len);
+	if len > MAX_MSGPACK_RECORD_LEN {
+		return Ok(None);
+	}
+
+	let mut record = vec![0u8; len as usize + 4];
+	match reader.read_exact(&mut record) {
+		Ok(()) => {}
+		Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+		Err(e) => return Err(e),
+	}
+	if record[len as usize..] != len.to_le_bytes() {
+		return Ok(None);
+	}
+	record.truncate(len as usize);
+	Ok(Some(record))
+}
+
+/// Scans the records between `floor`, which must be the start of one, and the end of the log backwards.
+fn scan_last_ndjson<P, R>(file: &mut StdFile, floor: u64, file_len: u64, scanner: &impl Fn(P) -> Option<R>) -> Result<Option<R>>
+where
+	P: DeserializeOwned,
+{
+	const CAP: usize = 64 * 1024;
+
+	let mut buffer = vec![0u8; CAP];
 
-			if !line_suffix.is_empty()
-				&& let Ok(entry) = sonic_rs::from_slice::<P>(&line_suffix)
+	let mut line_suffix: Vec<u8> = Vec::new();
+	let mut file_pos = file_len;
+
+	while file_pos > floor {
+		#[allow(clippy::cast_possible_truncation)]
+		let read_len = std::cmp::min(file_pos - floor, CAP as u64) as usize;
+		file_pos -= read_len as u64;
+
+		file.seek(SeekFrom::Start(file_pos))?;
+		file.read_exact(&mut buffer[..read_len])?;
+
+		let window = &buffer[..read_len];
+		let mut cursor = read_len;
+
+		// Scan backwards for newlines within the chunk
+		while let Some(newline_idx) = window[..cursor].iter().rposition(|&b| b == b'\n') {
+			let line_slice = &window[newline_idx + 1..cursor];
+
+			let bytes_to_parse = if line_suffix.is_empty() {
+				line_slice
+			} else {
+				&[line_slice, &line_suffix].concat()
+			};
+
+			if !bytes_to_parse.is_empty()
+				&& let Some(entry) = decode_record::<P>(LogFormat::Ndjson, bytes_to_parse)
 				&& let Some(found) = scanner(entry)
 			{
 				return Ok(Some(found));
 			}
 
-			Ok(None)
-		})
-		.await?
+			line_suffix.clear();
+			cursor = newline_idx;
+		}
+
+		if cursor > 0 {
+			let prefix = &window[0..cursor];
+			let mut new_suffix = Vec::with_capacity(prefix.len() + line_suffix.len());
+			new_suffix.extend_from_slice(prefix);
+			new_suffix.append(&mut line_suffix);
+			line_suffix = new_suffix;
+		}
 	}
 
-	pub async fn flush(&self) -> Result<()> {
-		let (tx, rx) = oneshot::channel();
-		self.tx
-			.send(StoreCommand::Flush(tx))
-			.map_err(|_| anyhow::anyhow!("LogStore closed"))?;
-		rx.await.context("Flush responder dropped")?;
-		Ok(())
+	if !line_suffix.is_empty()
+		&& let Some(entry) = decode_record::<P>(LogFormat::Ndjson, &line_suffix)
+		&& let Some(found) = scanner(entry)
+	{
+		return Ok(Some(found));
 	}
 
-	pub async fn clear(&self) -> Result<()> {
-		self.flush().await?;
+	Ok(None)
+}
 
-		let path = self.path.clone();
-		tokio::task::spawn_blocking(move || OpenOptions::new().write(true).truncate(true).open(path)).await??;
+/// Like `scan_last_ndjson`, for MessagePack logs.
+fn scan_last_msgpack<P, R>(file: &mut StdFile, floor: u64, file_len: u64, scanner: &impl Fn(P) -> Option<R>) -> Result<Option<R>>
+where
+	P: DeserializeOwned,
+{
+	let mut end = file_len;
+	let mut trailer = [0u8; 4];
+	let mut record = Vec::new();
 
-		Ok(())
+	while end > floor {
+		if end - floor < 8 {
+			return scan_msgpack_forward(file, floor, scanner);
+		}
+		file.seek(SeekFrom::Start(end - 4))?;
+		file.read_exact(&mut trailer)?;
+
+		let len = u32::from_le_bytes(trailer);
+		let start = end.checked_sub(u64::from(len) + 8);
+		let Some(start) = start.filter(|&start| start >= floor && len <= MAX_MSGPACK_RECORD_LEN) else {
+			return scan_msgpack_forward(file, floor, scanner);
+		};
+
+		record.resize(len as usize + 4, 0);
+		file.seek(SeekFrom::Start(start))?;
+		file.read_exact(&mut record)?;
+		if record[..4] != trailer {
+			return scan_msgpack_forward(file, floor, scanner);
+		}
+
+		if let Some(entry) = decode_record::<P>(LogFormat::Msgpack, &record[4..])
+			&& let Some(found) = scanner(entry)
+		{
+			return Ok(Some(found));
+		}
+		end = start;
 	}
+	Ok(None)
+}
 
-	pub fn path(&self) -> &Path {
-		&self.path
+/// Cuts off a record left incomplete by a crash, so that records appended after it can still be read.
+fn repair_msgpack_tail(path: &Path) -> Result<()> {
+	let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+		Ok(f) => f,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+		Err(e) => return Err(e.into()),
+	};
+	let file_len = file.metadata()?.len();
+	if file_len == 0 {
+		return Ok(());
+	}
+
+	// Fast path: the last record is intact
+	if file_len >= 8 {
+		let mut trailer = [0u8; 4];
+		file.seek(SeekFrom::Start(file_len - 4))?;
+		file.read_exact(&mut trailer)?;
+		let len = u32::from_le_bytes(trailer);
+		if let Some(start) = file_len.checked_sub(u64::from(len) + 8) {
+			let mut header = [0u8; 4];
+			file.seek(SeekFrom::Start(start))?;
+			file.read_exact(&mut header)?;
+			if header == trailer {
+				return Ok(());
+			}
+		}
+	}
+
+	file.seek(SeekFrom::Start(0))?;
+	let mut reader = BufReader::new(&mut file);
+	let mut valid_len = 0u64;
+	while let Some(record) = read_msgpack_record(&mut reader)? {
+		valid_len += record.len() as u64 + 8;
+	}
+	drop(reader);
+
+	// A crash only tears the last write, leaving a frame that runs past the end of the log. A frame damaged anywhere else
+	// means the log is corrupt, and cutting it off would lose the intact records after it
+	let remaining = file_len - valid_len;
+	if remaining >= 4 {
+		let mut rest = Vec::new();
+		file.seek(SeekFrom::Start(valid_len))?;
+		(&mut file).take(u64::from(MAX_MSGPACK_RECORD_LEN) + 8).read_to_end(&mut rest)?;
+		let len = u32::from_le_bytes(rest[..4].try_into().expect("Slice is 4 bytes long"));
+		if len > MAX_MSGPACK_RECORD_LEN || u64::from(len) + 8 <= remaining || contains_msgpack_frame(&rest[1..]) {
+			bail!(
+				"{} is damaged at byte {valid_len}, before its end. It was left as is, move it aside to start a new log",
+				path.display()
+			);
+		}
+	}
+
+	warn!(
+		?path,
+		dropped_bytes = file_len - valid_len,
+		"Log ends with an incomplete record, truncating it."
+	);
+	file.set_len(valid_len)?;
+	object_store::log_rewritten(path);
+	Ok(())
+}
+
+/// Whether a complete frame starts anywhere in `bytes`.
+fn contains_msgpack_frame(bytes: &[u8]) -> bool {
+	(0..bytes.len()).any(|start| {
+		let Some(header) = bytes.get(start..start + 4) else {
+			return false;
+		};
+		let len = u32::from_le_bytes(header.try_into().expect("Slice is 4 bytes long")) as usize;
+		len > 0 && len <= MAX_MSGPACK_RECORD_LEN as usize && bytes.get(start + 4 + len..start + 8 + len) == Some(header)
+	})
+}
+
+/// Makes sure the log ends with a newline, so the next append starts a line of its own instead of being glued to the last one.
+/// A last line without one is complete if it parses, and is then just terminated. Otherwise it was cut short by a crash and is cut off.
+fn repair_ndjson_tail(path: &Path) -> Result<()> {
+	const CAP: u64 = 64 * 1024;
+
+	let mut file = match OpenOptions::new().read(true).write(true).open(path) {
+		Ok(f) => f,
+		Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+		Err(e) => return Err(e.into()),
+	};
+	let file_len = file.metadata()?.len();
+	if file_len == 0 {
+		return Ok(());
+	}
+
+	let mut last = [0u8; 1];
+	file.seek(SeekFrom::Start(file_len - 1))?;
+	file.read_exact(&mut last)?;
+	if last[0] == b'\n' {
+		return Ok(());
+	}
+
+	// Find where the unterminated line starts
+	let mut line_start = 0;
+	let mut pos = file_len;
+	let mut buffer = Vec::new();
+	while pos > 0 {
+		let read_len = pos.min(CAP);
+		pos -= read_len;
+		#[allow(clippy::cast_possible_truncation)]
+		buffer.resize(read_len as usize, 0);
+		file.seek(SeekFrom::Start(pos))?;
+		file.read_exact(&mut buffer)?;
+		if let Some(idx) = buffer.iter().rposition(|&b| b == b'\n') {
+			line_start = pos + idx as u64 + 1;
+			break;
+		}
+	}
+
+	#[allow(clippy::cast_possible_truncation)]
+	let mut line = vec![0u8; (file_len - line_start) as usize];
+	file.seek(SeekFrom::Start(line_start))?;
+	file.read_exact(&mut line)?;
+
+	let trimmed = line.trim_ascii();
+	if !trimmed.is_empty() && decode_record::<serde::de::IgnoredAny>(LogFormat::Ndjson, trimmed).is_some() {
+		file.seek(SeekFrom::End(0))?;
+		file.write_all(b"\n")?;
+	} else {
+		warn!(
+			?path,
+			dropped_bytes = line.len(),
+			"Log ends with an incomplete line, truncating it."
+		);
+		file.set_len(line_start)?;
+		object_store::log_rewritten(path);
+	}
+	Ok(())
+}
+
+/// Slow path for a log with a damaged tail, where records can't be found from the end.
+/// Reads the whole log from `floor`, keeping the last match.
+fn scan_msgpack_forward<P, R>(file: &mut StdFile, floor: u64, scanner: &impl Fn(P) -> Option<R>) -> Result<Option<R>>
+where
+	P: DeserializeOwned,
+{
+	file.seek(SeekFrom::Start(floor))?;
+	let mut reader = BufReader::new(file);
+	let mut last = None;
+	while let Some(record) = read_msgpack_record(&mut reader)? {
+		if let Some(entry) = decode_record::<P>(LogFormat::Msgpack, &record)
+			&& let Some(found) = scanner(entry)
+		{
+			last = Some(found);
+		}
+	}
+	Ok(last)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[derive(serde::Deserialize, serde::Serialize, Debug)]
+	struct Entry {
+		id: u64,
+	}
+
+	const IDS: [u64; 4] = [1, 2, 3, 4];
+
+	/// A log of `format` with a header and an event for each of `ids`, as `LogStore` writes it.
+	fn encode_log(format: LogFormat, ids: &[u64]) -> Vec<u8> {
+		let mut log = format.header().unwrap();
+		for &id in ids {
+			let event = LogEvent {
+				timestamp: id * 1000,
+				payload: Entry { id },
+			};
+			format.encode_into(&mut log, &event).unwrap();
+		}
+		log
+	}
+
+	/// A store reading the log at `path` as it is, without the repair `LogStore::new` does first.
+	fn reader(path: &Path) -> LogStore {
+		LogStore {
+			path: path.to_path_buf(),
+			writer: Writer::DryRun(path.into()),
+		}
+	}
+
+	fn ids(events: &[LogEvent<Entry>]) -> Vec<u64> {
+		events.iter().map(|e| e.payload.id).collect()
+	}
+
+	#[tokio::test]
+	async fn ndjson_torn_tail_is_skipped_and_cut_off() {
+		let complete = encode_log(LogFormat::Ndjson, &IDS[..3]);
+		let full = encode_log(LogFormat::Ndjson, &IDS);
+		// Every cut of the last line down to its first byte. With only its newline missing it's complete, see below.
+		for len in complete.len() + 1..full.len() - 1 {
+			let dir = tempfile::tempdir().unwrap();
+			let path = dir.path().join("log.ndjson");
+			fs::write(&path, &full[..len]).unwrap();
+
+			let store = reader(&path);
+			assert_eq!(ids(&store.read_all().await.unwrap()), [1, 2, 3], "cut at {len}");
+			let scan = store.scan_last_since(None, |e: LogEvent<Entry>| Some(e.payload.id)).await.unwrap();
+			assert_eq!(scan.found, Some(3), "cut at {len}");
+			assert!(scan.end.is_none(), "cut at {len}");
+			let below_three = store
+				.scan_last(|e: LogEvent<Entry>| (e.payload.id < 3).then_some(e.payload.id))
+				.await
+				.unwrap();
+			assert_eq!(below_three, Some(2), "cut at {len}");
+
+			let shutdown = Arc::new(AtomicBool::new(false));
+			let store = LogStore::new(path.clone(), &shutdown).unwrap();
+			assert_eq!(fs::read(&path).unwrap(), complete, "cut at {len}");
+			store.append(&Entry { id: 5 }).unwrap();
+			store.flush().await.unwrap();
+			assert_eq!(ids(&store.read_all().await.unwrap()), [1, 2, 3, 5], "cut at {len}");
+		}
+	}
+
+	#[tokio::test]
+	async fn ndjson_unterminated_last_line_is_kept() {
+		let full = encode_log(LogFormat::Ndjson, &IDS);
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("log.ndjson");
+		fs::write(&path, &full[..full.len() - 1]).unwrap();
+
+		let store = reader(&path);
+		assert_eq!(ids(&store.read_all().await.unwrap()), IDS);
+		assert_eq!(store.scan_last(|e: LogEvent<Entry>| Some(e.payload.id)).await.unwrap(), Some(4));
+
+		let shutdown = Arc::new(AtomicBool::new(false));
+		let store = LogStore::new(path.clone(), &shutdown).unwrap();
+		assert_eq!(fs::read(&path).unwrap(), full);
+		store.append(&Entry { id: 5 }).unwrap();
+		store.flush().await.unwrap();
+		assert_eq!(ids(&store.read_all().await.unwrap()), [1, 2, 3, 4, 5]);
+	}
+
+	#[tokio::test]
+	async fn exact_read_refuses_unreadable_records() {
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("log.ndjson");
+		fs::write(&path, encode_log(LogFormat::Ndjson, &IDS)).unwrap();
+		// The header isn't an event, but doesn't count as unreadable
+		assert_eq!(ids(&reader(&path).read_all_exact().await.unwrap()), IDS);
+
+		let mut log = encode_log(LogFormat::Ndjson, &IDS[..2]);
+		log.extend_from_slice(b"{\"ts\":3000,\"id\":\"three\"}\n");
+		log.extend_from_slice(&encode_log(LogFormat::Ndjson, &IDS[3..])[LogFormat::Ndjson.header().unwrap().len()..]);
+		fs::write(&path, log).unwrap();
+		let store = reader(&path);
+		assert_eq!(ids(&store.read_all().await.unwrap()), [1, 2, 4]);
+		assert!(store.read_all_exact::<Entry>().await.is_err());
+	}
+
+	// `LogStore` writes whichever format the settings pick, so MessagePack goes through the functions it uses for it
+	#[test]
+	fn msgpack_torn_tail_is_skipped_and_cut_off() {
+		let complete = encode_log(LogFormat::Msgpack, &IDS[..3]);
+		let full = encode_log(LogFormat::Msgpack, &IDS);
+		let next = encode_log(LogFormat::Msgpack, &[5]);
+		let header_len = LogFormat::Msgpack.header().unwrap().len();
+		// Every cut of the last record, including into either of its length fields
+		for len in complete.len() + 1..full.len() {
+			let dir = tempfile::tempdir().unwrap();
+			let path = dir.path().join("log.msgpack");
+			fs::write(&path, &full[..len]).unwrap();
+
+			let mut read = Vec::new();
+			for_each_record(&path, |e: LogEvent<Entry>| read.push(e.payload.id)).unwrap();
+			assert_eq!(read, [1, 2, 3], "cut at {len}");
+
+			let mut file = StdFile::open(&path).unwrap();
+			let file_len = file.metadata().unwrap().len();
+			let last = scan_last_msgpack(&mut file, 0, file_len, &|e: LogEvent<Entry>| Some(e.payload.id)).unwrap();
+			assert_eq!(last, Some(3), "cut at {len}");
+			let below_three = scan_last_msgpack(&mut file, 0, file_len, &|e: LogEvent<Entry>| {
+				(e.payload.id < 3).then_some(e.payload.id)
+			})
+			.unwrap();
+			assert_eq!(below_three, Some(2), "cut at {len}");
+			drop(file);
+
+			repair_msgpack_tail(&path).unwrap();
+			assert_eq!(fs::read(&path).unwrap(), complete, "cut at {len}");
+
+			// A record appended after the repair is framed right after the last complete one
+			let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+			file.write_all(&next[header_len..]).unwrap();
+			drop(file);
+			let mut read = Vec::new();
+			for_each_record(&path, |e: LogEvent<Entry>| read.push(e.payload.id)).unwrap();
+			assert_eq!(read, [1, 2, 3, 5], "cut at {len}");
+		}
+	}
+
+	#[test]
+	fn msgpack_damage_before_the_end_is_left_alone() {
+		let mut log = encode_log(LogFormat::Msgpack, &IDS);
+		// The trailer of the second record no longer matches, and the last record is torn
+		let second_end = encode_log(LogFormat::Msgpack, &IDS[..2]).len();
+		log[second_end - 1] ^= 0xff;
+		log.pop();
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("log.msgpack");
+		fs::write(&path, &log).unwrap();
+
+		assert!(repair_msgpack_tail(&path).is_err());
+		assert_eq!(fs::read(&path).unwrap(), log);
+	}
+
+	#[test]
+	fn msgpack_records_over_the_cap_are_refused() {
+		let mut buffer = vec![1, 2, 3];
+		let payload = "x".repeat(MAX_MSGPACK_RECORD_LEN as usize);
+		assert!(LogFormat::Msgpack.encode_into(&mut buffer, &payload).is_err());
+		assert_eq!(buffer, [1, 2, 3]);
+	}
+
+	#[test]
+	fn msgpack_intact_log_is_left_alone() {
+		let full = encode_log(LogFormat::Msgpack, &IDS);
+		let dir = tempfile::tempdir().unwrap();
+		let path = dir.path().join("log.msgpack");
+		fs::write(&path, &full).unwrap();
+
+		repair_msgpack_tail(&path).unwrap();
+		assert_eq!(fs::read(&path).unwrap(), full);
+
+		let mut file = StdFile::open(&path).unwrap();
+		let file_len = file.metadata().unwrap().len();
+		let last = scan_last_msgpack(&mut file, 0, file_len, &|e: LogEvent<Entry>| Some(e.payload.id)).unwrap();
+		assert_eq!(last, Some(4));
 	}
 }
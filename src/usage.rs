@@ -0,0 +1,208 @@
+use crate::messages::{self, CustomReaction, MessageEvent, ReactionData};
+use crate::metadata::{EmojiEvent, StickerEvent};
+use crate::settings::SETTINGS;
+use crate::storage::{self, LogEvent};
+use crate::utils::{create_path, int_to_str};
+use anyhow::{Context, Result, bail};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use twilight_model::id::Id;
+use twilight_model::id::marker::GuildMarker;
+
+#[derive(Default)]
+struct Usage {
+	/// Messages using it in their content (emojis), or attached to them (stickers).
+	messages: u64,
+	reactions: u64,
+}
+
+/// Usage of a single message, as of its latest record.
+#[derive(Default)]
+struct MessageUsage {
+	emojis: Vec<u64>,
+	stickers: Vec<u64>,
+	reactions: HashMap<u64, ReactionCount>,
+}
+
+#[derive(Default)]
+struct ReactionCount {
+	/// Count reported by Discord when the message was fetched.
+	snapshot: u64,
+	/// Users seen reacting since.
+	users: HashSet<u64>,
+}
+
+impl ReactionCount {
+	fn total(&self) -> u64 {
+		self.snapshot.max(self.users.len() as u64)
+	}
+}
+
+#[derive(Default)]
+struct Tally {
+	emojis: HashMap<u64, Usage>,
+	stickers: HashMap<u64, Usage>,
+}
+
+/// Tallies how often each custom emoji and sticker is used in a guild's archived messages and reactions, and prints it to stdout as TSV.
+/// Run with `BigBrother usage <guild_id>`. The bot doesn't have to be running, only the data folder is read.
+///
+/// Emojis are counted once per message using them in its content, and once per reacting user.
+/// Reaction counts are approximate, since gateway events received while offline are missed.
+/// Rows are sorted by least used first, which are the candidates to remove when running out of slots.
+pub fn run(guild_id: &str) -> Result<()> {
+	let guild_id: Id<GuildMarker> = guild_id.parse().with_context(|| format!("Invalid guild ID: {guild_id}"))?;
+	let guild = int_to_str!(guild_id.get(), u64);
+
+	let mut tally = Tally::default();
+	let dirs = messages::message_log_dirs(&guild);
+	if !dirs[0].is_dir() {
+		bail!("No messages archived at {}", dirs[0].display());
+	}
+	for dir in dirs {
+		for entry in fs::read_dir(&dir)? {
+			let path = entry?.path();
+			if path.is_file() && storage::is_log_file(&path) {
+				tally.add_channel(&path)?;
+			}
+		}
+	}
+
+	let ext = SETTINGS.storage.log_format.extension();
+	let emojis_path = create_path(&[&guild, "metadata", &format!("emojis.{ext}")]);
+	let stickers_path = create_path(&[&guild, "metadata", &format!("stickers.{ext}")]);
+	let emojis = current_names(&emojis_path, |e: EmojiEvent| (e.id, e.name, e.deleted))?;
+	let stickers = current_names(&stickers_path, |s: StickerEvent| (s.id, s.name, s.deleted))?;
+
+	let mut out = BufWriter::new(std::io::stdout().lock());
+	writeln!(out, "kind\tid\tname\tin_guild\tmessages\treactions")?;
+	write_rows(&mut out, "emoji", tally.emojis, &emojis)?;
+	write_rows(&mut out, "sticker", tally.stickers, &stickers)?;
+	out.flush()?;
+	Ok(())
+}
+
+impl Tally {
+	fn add_channel(&mut self, path: &Path) -> Result<()> {
+		let mut messages: HashMap<u64, MessageUsage> = HashMap::new();
+		storage::for_each_record(path, |event: LogEvent<MessageEvent>| match event.payload {
+			MessageEvent::Create { message } | MessageEvent::Update { message } => {
+				let usage = messages.entry(message.id).or_default();
+				usage.emojis = custom_emojis(&message.content).collect();
+				usage.emojis.sort_unstable();
+				usage.emojis.dedup();
+				usage.stickers = message.stickers.into_iter().map(|s| s.id).collect();
+				for (emoji, count) in message.reactions {
+					if let ReactionData::Custom(CustomReaction { id, .. }) = emoji {
+						usage.reactions.entry(id).or_default().snapshot = count;
+					}
+				}
+			}
+			MessageEvent::ReactionAdd {
+				message_id,
+				user_id,
+				emoji: ReactionData::Custom(CustomReaction { id, .. }),
+			} => {
+				messages
+					.entry(message_id)
+					.or_default()
+					.reactions
+					.entry(id)
+					.or_default()
+					.users
+					.insert(user_id);
+			}
+			MessageEvent::ReactionRemove {
+				message_id,
+				user_id,
+				emoji: ReactionData::Custom(CustomReaction { id, .. }),
+			} => {
+				if let Some(count) = messages.get_mut(&message_id).and_then(|m| m.reactions.get_mut(&id)) {
+					count.users.remove(&user_id);
+				}
+			}
+			MessageEvent::ReactionRemoveAll { message_id } => {
+				if let Some(usage) = messages.get_mut(&message_id) {
+					usage.reactions.clear();
+				}
+			}
+			MessageEvent::ReactionRemoveEmoji {
+				message_id,
+				emoji: ReactionData::Custom(CustomReaction { id, .. }),
+			} => {
+				if let Some(usage) = messages.get_mut(&message_id) {
+					usage.reactions.remove(&id);
+				}
+			}
+			MessageEvent::ReactionUsers {
+				message_id,
+				emoji: ReactionData::Custom(CustomReaction { id, .. }),
+				user_ids,
+			} => {
+				messages.entry(message_id).or_default().reactions.entry(id).or_default().users = user_ids.into_iter().collect();
+			}
+			// Unicode reactions have no slot to free up. Deleted messages still count, their emojis were used all the same
+			_ => {}
+		})?;
+
+		for usage in messages.into_values() {
+			for id in usage.emojis {
+				self.emojis.entry(id).or_default().messages += 1;
+			}
+			for id in usage.stickers {
+				self.stickers.entry(id).or_default().messages += 1;
+			}
+			for (id, count) in usage.reactions {
+				self.emojis.entry(id).or_default().reactions += count.total();
+			}
+		}
+		Ok(())
+	}
+}
+
+/// IDs of custom emojis in message content, written as `<:name:id>` or `<a:name:id>`.
+fn custom_emojis(content: &str) -> impl Iterator<Item = u64> + '_ {
+	content.split('<').skip(1).filter_map(|part| {
+		let (tag, _) = part.split_once('>')?;
+		let rest = tag.strip_prefix("a:").or_else(|| tag.strip_prefix(':'))?;
+		let (_, id) = rest.split_once(':')?;
+		id.parse().ok()
+	})
+}
+
+/// Names of the emojis or stickers the guild currently has, replayed from their metadata log.
+fn current_names<P: serde::de::DeserializeOwned>(path: &Path, fields: impl Fn(P) -> (u64, String, bool)) -> Result<HashMap<u64, String>> {
+	let mut names = HashMap::new();
+	storage::for_each_record(path, |event: LogEvent<P>| {
+		let (id, name, deleted) = fields(event.payload);
+		if deleted {
+			names.remove(&id);
+		} else {
+			names.insert(id, name);
+		}
+	})?;
+	Ok(names)
+}
+
+/// Writes one row per used or current item. Current items that were never used are included with zero counts.
+fn write_rows(out: &mut impl Write, kind: &str, mut usage: HashMap<u64, Usage>, current: &HashMap<u64, String>) -> Result<()> {
+	for id in current.keys() {
+		usage.entry(*id).or_default();
+	}
+
+	let mut rows: Vec<_> = usage.into_iter().collect();
+	rows.sort_unstable_by_key(|(id, u)| (u.messages + u.reactions, *id));
+	for (id, u) in rows {
+		let name = current.get(&id).map_or("", String::as_str);
+		writeln!(
+			out,
+			"{kind}\t{id}\t{name}\t{}\t{}\t{}",
+			current.contains_key(&id),
+			u.messages,
+			u.reactions
+		)?;
+	}
+	Ok(())
+}
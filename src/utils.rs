@@ -1,5 +1,6 @@
 use crate::settings::SETTINGS;
 use std::fmt;
+use std::fmt::Write as _;
 use std::path::{Path, PathBuf};
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
 use tokio::fs;
@@ -20,6 +21,49 @@ pub fn create_path(file_names: &[&str]) -> PathBuf {
 	path
 }
 
+/// Makes a user-provided file name safe to save as-is.
+/// Path separators, characters Windows doesn't allow and control characters (including NUL) become `_`.
+/// Names that are empty, `.` or `..`, or that Windows reserves for devices like `CON`, are prefixed with `_`.
+/// Names longer than `max_bytes` are shortened, keeping the extension.
+pub fn sanitize_filename(name: &str, max_bytes: usize) -> String {
+	let mut clean: String = name
+		.chars()
+		.map(|c| {
+			if matches!(c, '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|') || c.is_control() {
+				'_'
+			} else {
+				c
+			}
+		})
+		.collect();
+	if matches!(clean.as_str(), "" | "." | "..") || is_reserved_on_windows(&clean) {
+		clean.insert(0, '_');
+	}
+	if clean.len() <= max_bytes {
+		return clean;
+	}
+
+	// A long "extension" is more likely part of the name, so it is cut like the rest
+	let (stem, ext) = match clean.rfind('.') {
+		Some(dot) if dot > 0 && clean.len() - dot <= 16 => clean.split_at(dot),
+		_ => (clean.as_str(), ""),
+	};
+	let mut cut = max_bytes.saturating_sub(ext.len());
+	while !stem.is_char_boundary(cut) {
+		cut -= 1;
+	}
+	format!("{}{ext}", &stem[..cut])
+}
+
+/// Whether Windows treats `name` as a device rather than a file, with any extension.
+fn is_reserved_on_windows(name: &str) -> bool {
+	let stem = name.split('.').next().unwrap_or_default().trim_end();
+	matches!(
+		stem.to_ascii_uppercase().as_bytes(),
+		b"CON" | b"PRN" | b"AUX" | b"NUL" | [b'C', b'O', b'M', b'1'..=b'9'] | [b'L', b'P', b'T', b'1'..=b'9']
+	)
+}
+
 pub fn remove_extension(path: &Path) -> PathBuf {
 	match (path.parent(), path.file_stem()) {
 		(Some(parent), Some(stem)) => parent.join(stem),
@@ -41,6 +85,8 @@ pub const fn is_archivable_channel(kind: ChannelType) -> bool {
 	)
 }
 
+/// Guild an event belongs to, which decides the guild processor it is routed to.
+/// Lists every guild-scoped event, including ones `dispatch::handle_event` ignores for now, so handling one only needs a match arm there.
 pub fn get_event_guild_id(event: &Event) -> Option<Id<GuildMarker>> {
 	match event {
 		Event::GuildUpdate(e) => Some(e.id),
@@ -66,10 +112,55 @@ pub fn get_event_guild_id(event: &Event) -> Option<Id<GuildMarker>> {
 		Event::ThreadCreate(e) => e.guild_id,
 		Event::ThreadUpdate(e) => e.guild_id,
 		Event::ThreadDelete(e) => Some(e.guild_id),
+		Event::InviteCreate(e) => Some(e.guild_id),
+		Event::InviteDelete(e) => Some(e.guild_id),
+		Event::IntegrationCreate(e) => e.0.guild_id,
+		Event::IntegrationUpdate(e) => e.0.guild_id,
+		Event::IntegrationDelete(e) => Some(e.guild_id),
+		Event::GuildIntegrationsUpdate(e) => Some(e.guild_id),
+		Event::ChannelPinsUpdate(e) => e.guild_id,
+		Event::ThreadListSync(e) => Some(e.guild_id),
+		Event::ThreadMemberUpdate(e) => Some(e.guild_id),
+		Event::ThreadMembersUpdate(e) => Some(e.guild_id),
+		Event::MemberChunk(e) => Some(e.guild_id),
+		Event::BanAdd(e) => Some(e.guild_id),
+		Event::BanRemove(e) => Some(e.guild_id),
+		Event::GuildAuditLogEntryCreate(e) => e.0.guild_id,
+		Event::GuildScheduledEventCreate(e) => Some(e.0.guild_id),
+		Event::GuildScheduledEventUpdate(e) => Some(e.0.guild_id),
+		Event::GuildScheduledEventDelete(e) => Some(e.0.guild_id),
+		Event::GuildScheduledEventUserAdd(e) => Some(e.guild_id),
+		Event::GuildScheduledEventUserRemove(e) => Some(e.guild_id),
+		Event::StageInstanceCreate(e) => Some(e.0.guild_id),
+		Event::StageInstanceUpdate(e) => Some(e.0.guild_id),
+		Event::StageInstanceDelete(e) => Some(e.0.guild_id),
+		Event::AutoModerationRuleCreate(e) => Some(e.0.guild_id),
+		Event::AutoModerationRuleUpdate(e) => Some(e.0.guild_id),
+		Event::AutoModerationRuleDelete(e) => Some(e.0.guild_id),
+		Event::AutoModerationActionExecution(e) => Some(e.guild_id),
+		Event::CommandPermissionsUpdate(e) => Some(e.0.guild_id),
+		Event::MessagePollVoteAdd(e) => e.guild_id,
+		Event::MessagePollVoteRemove(e) => e.guild_id,
+		Event::VoiceStateUpdate(e) => e.0.guild_id,
+		Event::VoiceServerUpdate(e) => Some(e.guild_id),
+		Event::WebhooksUpdate(e) => Some(e.guild_id),
+		// Presences and typing are very frequent, so they aren't routed at all unless archived
+		Event::PresenceUpdate(e) if SETTINGS.metadata.archive_presences => Some(e.guild_id),
+		Event::TypingStart(e) if SETTINGS.metadata.archive_typing => e.guild_id,
+		// GuildCreate and GuildDelete start and stop the processors themselves, so they are handled before routing.
+		// Interactions and entitlements belong to the bot's application rather than the guild's history.
 		_ => None,
 	}
 }
 
+/// Lowercase hex of `bytes`, as used for hashes.
+pub fn to_hex(bytes: &[u8]) -> String {
+	bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut hex, b| {
+		let _ = write!(hex, "{b:02x}");
+		hex
+	})
+}
+
 pub fn get_current_time_millis() -> Result<u64, std::time::SystemTimeError> {
 	#[allow(clippy::cast_possible_truncation)]
 	SystemTime::now()
@@ -77,6 +168,19 @@ pub fn get_current_time_millis() -> Result<u64, std::time::SystemTimeError> {
 		.map(|duration| duration.as_millis() as u64)
 }
 
+/// Unix timestamp of the first second of 2015, in milliseconds.
+const DISCORD_EPOCH_MS: u64 = 1_420_070_400_000;
+
+/// Returns the smallest snowflake that could have been generated at the given Unix timestamp.
+pub const fn millis_to_snowflake(millis: u64) -> u64 {
+	millis.saturating_sub(DISCORD_EPOCH_MS) << 22
+}
+
+/// Extracts the creation time of a snowflake as a Unix timestamp in milliseconds.
+pub const fn snowflake_to_millis(snowflake: u64) -> u64 {
+	(snowflake >> 22) + DISCORD_EPOCH_MS
+}
+
 macro_rules! int_to_str {
 	($num:expr, $type:ty) => {{ itoa::Buffer::new().format($num).to_string() }};
 }
@@ -148,3 +252,121 @@ impl FormatTime for HumanUptime {
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::mock_discord;
+	use serde::de::DeserializeSeed;
+	use serde_json::{Value, json};
+	use twilight_model::gateway::event::DispatchEventWithTypeDeserializer;
+
+	#[test]
+	fn sanitize_filename_replaces_separators() {
+		assert_eq!(sanitize_filename("../../etc/passwd", 200), ".._.._etc_passwd");
+		assert_eq!(sanitize_filename("..\\windows\\win.ini", 200), ".._windows_win.ini");
+		assert_eq!(sanitize_filename("a:b*c?d\"e<f>g|h.txt", 200), "a_b_c_d_e_f_g_h.txt");
+	}
+
+	#[test]
+	fn sanitize_filename_replaces_control_characters() {
+		assert_eq!(sanitize_filename("a\0b\nc\x7f.png", 200), "a_b_c_.png");
+	}
+
+	#[test]
+	fn sanitize_filename_escapes_reserved_names() {
+		for (name, expected) in [
+			("", "_"),
+			(".", "_."),
+			("..", "_.."),
+			("con", "_con"),
+			("NUL.txt", "_NUL.txt"),
+			("com1.tar.gz", "_com1.tar.gz"),
+			("LPT9", "_LPT9"),
+		] {
+			assert_eq!(sanitize_filename(name, 200), expected, "{name:?}");
+		}
+		// Only whole names are reserved
+		for name in ["...", ".hidden", "console.log", "com10", "COM0", "nul_byte"] {
+			assert_eq!(sanitize_filename(name, 200), name);
+		}
+	}
+
+	#[test]
+	fn sanitize_filename_caps_length_keeping_the_extension() {
+		let long = format!("{}.png", "a".repeat(300));
+		let clean = sanitize_filename(&long, 200);
+		assert_eq!(clean.len(), 200);
+		assert!(clean.ends_with("a.png"));
+		// A long extension is cut like the rest of the name
+		let clean = sanitize_filename(&format!("name.{}", "b".repeat(300)), 200);
+		assert_eq!(clean, format!("name.{}", "b".repeat(195)));
+		// Multi-byte characters are never split
+		let clean = sanitize_filename(&format!("{}.txt", "é".repeat(150)), 200);
+		assert_eq!(clean, format!("{}.txt", "é".repeat(98)));
+	}
+
+	/// `data` of a dispatch named `name`, as the gateway sends it.
+	fn dispatch(name: &str, data: Value) -> Event {
+		Event::from(DispatchEventWithTypeDeserializer::new(name).deserialize(data).unwrap())
+	}
+
+	#[test]
+	fn guild_scoped_events_are_routed_to_their_guild() {
+		let events = [
+			("GUILD_BAN_ADD", json!({ "guild_id": "1", "user": mock_discord::user(2) })),
+			("GUILD_BAN_REMOVE", json!({ "guild_id": "1", "user": mock_discord::user(2) })),
+			("GUILD_INTEGRATIONS_UPDATE", json!({ "guild_id": "1" })),
+			(
+				"CHANNEL_PINS_UPDATE",
+				json!({ "channel_id": "2", "guild_id": "1", "last_pin_timestamp": null }),
+			),
+			(
+				"THREAD_LIST_SYNC",
+				json!({ "channel_ids": [], "guild_id": "1", "members": [], "threads": [] }),
+			),
+			(
+				"MESSAGE_POLL_VOTE_ADD",
+				json!({ "answer_id": 1, "channel_id": "2", "guild_id": "1", "message_id": "3", "user_id": "4" }),
+			),
+			(
+				"MESSAGE_POLL_VOTE_REMOVE",
+				json!({ "answer_id": 1, "channel_id": "2", "guild_id": "1", "message_id": "3", "user_id": "4" }),
+			),
+			(
+				"VOICE_SERVER_UPDATE",
+				json!({ "endpoint": null, "guild_id": "1", "token": "token" }),
+			),
+			(
+				"GUILD_SCHEDULED_EVENT_USER_ADD",
+				json!({ "guild_id": "1", "guild_scheduled_event_id": "2", "user_id": "3" }),
+			),
+			(
+				"GUILD_SCHEDULED_EVENT_USER_REMOVE",
+				json!({ "guild_id": "1", "guild_scheduled_event_id": "2", "user_id": "3" }),
+			),
+			("WEBHOOKS_UPDATE", json!({ "channel_id": "2", "guild_id": "1" })),
+		];
+		for (name, data) in events {
+			assert_eq!(get_event_guild_id(&dispatch(name, data)), Some(Id::new(1)), "{name}");
+		}
+	}
+
+	#[test]
+	fn events_handled_outside_a_guild_processor_are_not_routed() {
+		let events = [
+			// Stops the processor itself
+			dispatch("GUILD_DELETE", json!({ "id": "1", "unavailable": false })),
+			// Not archived by default
+			dispatch(
+				"TYPING_START",
+				json!({ "channel_id": "2", "guild_id": "1", "timestamp": 1_700_000_000, "user_id": "3" }),
+			),
+			Event::GatewayHeartbeatAck,
+			Event::Resumed,
+		];
+		for event in events {
+			assert_eq!(get_event_guild_id(&event), None, "{:?}", event.kind());
+		}
+	}
+}